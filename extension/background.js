@@ -0,0 +1,33 @@
+// Reports the active tab of every browser window to the native messaging
+// host, which passes it on to the daemon.
+const api = globalThis.browser ?? globalThis.chrome;
+let port = null;
+
+function send(message) {
+  if (port === null) {
+    port = api.runtime.connectNative("sway_xkb_switcher");
+    port.onDisconnect.addListener(() => {
+      port = null;
+    });
+  }
+  port.postMessage(message);
+}
+
+function report(tab) {
+  if (tab.active && tab.url) {
+    send({ window: tab.windowId, url: tab.url, title: tab.title ?? "" });
+  }
+}
+
+api.tabs.onActivated.addListener(({ tabId }) => {
+  api.tabs.get(tabId).then(report, () => {});
+});
+api.tabs.onUpdated.addListener((tabId, change, tab) => {
+  if (change.url || change.title) {
+    report(tab);
+  }
+});
+api.windows.onRemoved.addListener((windowId) => {
+  send({ window: windowId, closed: true });
+});
+api.tabs.query({ active: true }).then((tabs) => tabs.forEach(report));
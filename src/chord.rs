@@ -0,0 +1,54 @@
+use std::{io, process::Command};
+
+/// Modifier names understood by `wtype -M`.
+const MODIFIERS: &[&str] = &["shift", "capslock", "ctrl", "logo", "win", "alt", "altgr"];
+
+/// Builds the wtype arguments for a chord like `alt+Shift_L`: every part but
+/// the last is held as a modifier while the last one is tapped.
+fn args(chord: &str) -> io::Result<Vec<String>> {
+    let parts: Vec<String> = chord
+        .split('+')
+        .map(|part| match part.to_lowercase().as_str() {
+            "super" | "mod4" => "logo".to_string(),
+            "control" => "ctrl".to_string(),
+            "mod1" => "alt".to_string(),
+            _ => part.to_string(),
+        })
+        .collect();
+    let Some((key, mods)) = parts.split_last() else {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "empty chord"));
+    };
+    if let Some(modifier) = mods.iter().find(|m| !MODIFIERS.contains(&m.as_str())) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{modifier} is not a modifier"),
+        ));
+    }
+    let mut args = vec![];
+    for modifier in mods {
+        args.extend(["-M".to_string(), modifier.clone()]);
+    }
+    args.extend(["-k".to_string(), key.clone()]);
+    for modifier in mods.iter().rev() {
+        args.extend(["-m".to_string(), modifier.clone()]);
+    }
+    Ok(args)
+}
+
+/// Emits the chord `times` times through wtype, which types through the
+/// Wayland virtual-keyboard protocol.
+pub fn press(chord: &str, times: usize) -> io::Result<()> {
+    let args = args(chord)?;
+    for _ in 0..times {
+        let status = Command::new("wtype").args(&args).status()?;
+        if !status.success() {
+            return Err(io::Error::other(format!("wtype exited with {status}")));
+        }
+    }
+    Ok(())
+}
+
+/// Checks the chord up front so a typo fails at startup, not on first focus.
+pub fn validate(chord: &str) -> io::Result<()> {
+    args(chord).map(|_| ())
+}
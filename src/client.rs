@@ -0,0 +1,123 @@
+use std::{
+    io::{BufRead, BufReader, Write},
+    os::unix::net::UnixStream,
+    time::Duration,
+};
+
+use serde_json::{json, Value};
+
+use crate::paths;
+
+/// Subcommands answered by the running daemon through its control socket.
+pub const COMMANDS: &[&str] = &[
+    "status",
+    "set",
+    "set-current",
+    "forget",
+    "pause",
+    "resume",
+    "freeze",
+    "unfreeze",
+    "watch",
+];
+
+pub fn request(command: &str) -> Result<Value, Box<dyn std::error::Error>> {
+    let path = paths::control_socket();
+    let stream = UnixStream::connect(&path)
+        .map_err(|err| format!("cannot reach the daemon at {}: {err}", path.display()))?;
+    stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+    writeln!(&stream, "{command}")?;
+    let mut line = String::new();
+    BufReader::new(&stream).read_line(&mut line)?;
+    let response: Value = serde_json::from_str(&line)?;
+    match response["error"].as_str() {
+        Some(err) => Err(err.into()),
+        None => Ok(response),
+    }
+}
+
+/// Prints what the daemon decides, e.g. which layout a focused window got
+/// and why, until it goes away.
+fn watch() -> Result<(), Box<dyn std::error::Error>> {
+    let path = paths::control_socket();
+    let stream = UnixStream::connect(&path)
+        .map_err(|err| format!("cannot reach the daemon at {}: {err}", path.display()))?;
+    writeln!(&stream, "watch")?;
+    for line in BufReader::new(&stream).lines() {
+        println!("{}", line?);
+    }
+    Ok(())
+}
+
+fn print_status(status: &Value) {
+    let flag = |name: &str| status[name].as_bool().unwrap_or_default();
+    let state = match (flag("locked"), flag("frozen"), flag("paused")) {
+        (true, _, _) => "locked",
+        (_, true, _) => "frozen",
+        (_, _, true) => "paused",
+        _ => "running",
+    };
+    println!("state: {state}");
+    println!("mode: {}", status["mode"].as_str().unwrap_or("?"));
+    let pinned = if status["pinned"].as_bool().unwrap_or_default() {
+        " (pinned)"
+    } else {
+        ""
+    };
+    println!(
+        "focused: {}{pinned}",
+        status["focused"].as_str().unwrap_or("-")
+    );
+    println!("layout: {}", status["layout"].as_str().unwrap_or("-"));
+    let stats = &status["stats"];
+    if stats.is_object() {
+        println!(
+            "stats: {} switches in {} events, {} IPC errors, up {}s",
+            stats["switches"], stats["events"], stats["ipc_errors"], stats["uptime_seconds"]
+        );
+    }
+    if let Some(windows) = status["windows"].as_object() {
+        println!("remembered:");
+        for (key, layouts) in windows {
+            println!("  {key}: {layouts}");
+        }
+    }
+}
+
+/// Runs `status [--json]`, `set <window> <layout>`, `set-current <layout>`,
+/// `forget [<window>]`,
+/// `pause`, `resume`, `freeze`, `unfreeze` or `watch` against the daemon of
+/// this session.
+pub fn run(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    match args {
+        [status] if status == "status" => print_status(&request("status")?),
+        [status, json] if status == "status" && json == "--json" => {
+            println!("{}", request("status")?)
+        }
+        [set, window, lang @ ..] if set == "set" && !lang.is_empty() => {
+            let layout = lang.join(" ");
+            request(&json!({ "command": "set", "window": window, "layout": layout }).to_string())?;
+        }
+        [set, lang @ ..] if set == "set-current" && !lang.is_empty() => {
+            request(&format!("set-current {}", lang.join(" ")))?;
+        }
+        [forget] if forget == "forget" => {
+            request("forget")?;
+        }
+        [forget, window] if forget == "forget" => {
+            request(&format!("forget {window}"))?;
+        }
+        [command] if command == "watch" => watch()?,
+        [command] if ["pause", "resume", "freeze", "unfreeze"].contains(&command.as_str()) => {
+            request(command)?;
+        }
+        _ => {
+            return Err(format!(
+                "invalid arguments {args:?}, expected status [--json], set <window> <layout>, \
+                 set-current <layout>, forget [<window>], pause, resume, freeze, unfreeze or watch"
+            )
+            .into())
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,169 @@
+use crate::{Arity, OptSpec, OPTIONS};
+
+/// Subcommands completed in the first free position.
+const SUBCOMMANDS: &[&str] = &[
+    "run",
+    "setup",
+    "migrate",
+    "simulate",
+    "status",
+    "set",
+    "set-current",
+    "forget",
+    "pause",
+    "resume",
+    "freeze",
+    "unfreeze",
+    "watch",
+    "waybar",
+    "native-host",
+    "completions",
+    "gen-schema",
+];
+
+pub const SHELLS: &[&str] = &["bash", "zsh", "fish"];
+
+impl OptSpec {
+    fn takes_value(&self) -> bool {
+        self.arity != Arity::Flag && self.arity != Arity::FlagMulti
+    }
+
+    /// Values of a hint like `window|workspace`.
+    pub(crate) fn choices(&self) -> Vec<&str> {
+        let hint = self.hint.trim_start_matches('[').trim_end_matches(']');
+        let plain = |word: &str| {
+            !word.is_empty() && word.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        };
+        let words: Vec<&str> = hint.split('|').collect();
+        if words.len() > 1 && words.iter().all(|word| plain(word)) {
+            words
+        } else {
+            vec![]
+        }
+    }
+
+    /// Whether the value is a file or directory.
+    fn takes_path(&self) -> bool {
+        ["path", "file", "dir"]
+            .iter()
+            .any(|word| self.hint.contains(word))
+    }
+}
+
+fn bash(program: &str, opts: &[OptSpec]) -> String {
+    let function = format!("_{}", program.replace(['-', '.'], "_"));
+    let flags: Vec<String> = opts
+        .iter()
+        .flat_map(|opt| {
+            let long = format!("--{}", opt.long);
+            let short = (!opt.short.is_empty()).then(|| format!("-{}", opt.short));
+            short.into_iter().chain([long])
+        })
+        .collect();
+    let mut cases = String::new();
+    for opt in opts.iter().filter(|opt| opt.takes_value()) {
+        let mut names = format!("--{}", opt.long);
+        if !opt.short.is_empty() {
+            names.push_str(&format!("|-{}", opt.short));
+        }
+        let reply = match opt.choices() {
+            _ if opt.takes_path() => "($(compgen -f -- \"$cur\"))".to_string(),
+            choices if choices.is_empty() => "()".to_string(),
+            choices => format!("($(compgen -W \"{}\" -- \"$cur\"))", choices.join(" ")),
+        };
+        cases.push_str(&format!("        {names}) COMPREPLY={reply}; return ;;\n"));
+    }
+    format!(
+        "{function}() {{\n    \
+             local cur=${{COMP_WORDS[COMP_CWORD]}} prev=${{COMP_WORDS[COMP_CWORD-1]}}\n    \
+             case $prev in\n{cases}    esac\n    \
+             if [[ $cur == -* ]]; then\n        \
+                 COMPREPLY=($(compgen -W \"{flags}\" -- \"$cur\"))\n    \
+             else\n        \
+                 COMPREPLY=($(compgen -W \"{subcommands}\" -- \"$cur\"))\n    \
+             fi\n\
+         }}\n\
+         complete -F {function} {program}\n",
+        flags = flags.join(" "),
+        subcommands = SUBCOMMANDS.join(" "),
+    )
+}
+
+fn zsh_escape(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace('\'', "'\\''")
+        .replace('[', "\\[")
+        .replace(']', "\\]")
+        .replace(':', "\\:")
+}
+
+fn zsh(program: &str, opts: &[OptSpec]) -> String {
+    let mut specs = String::new();
+    for opt in opts {
+        let desc = zsh_escape(opt.summary);
+        let hint = zsh_escape(opt.hint);
+        let value = match opt.choices() {
+            _ if !opt.takes_value() => String::new(),
+            _ if opt.takes_path() => format!(":{hint}:_files"),
+            choices if choices.is_empty() => format!(":{hint}: "),
+            choices => format!(":{hint}:({})", choices.join(" ")),
+        };
+        let equals = if opt.takes_value() { "=" } else { "" };
+        let spec = match opt.short {
+            "" => format!("'--{}{equals}[{desc}]{value}'", opt.long),
+            short => format!(
+                "'(-{short} --{long})'{{-{short},--{long}{equals}}}'[{desc}]{value}'",
+                long = opt.long
+            ),
+        };
+        specs.push_str(&format!("    {spec} \\\n"));
+    }
+    format!(
+        "#compdef {program}\n\n\
+         _arguments -s \\\n{specs}    '1:subcommand:({})' \\\n    '*::argument:_files'\n",
+        SUBCOMMANDS.join(" ")
+    )
+}
+
+fn fish(program: &str, opts: &[OptSpec]) -> String {
+    let mut script = format!(
+        "complete -c {program} -n __fish_use_subcommand -f -a '{}'\n",
+        SUBCOMMANDS.join(" ")
+    );
+    for opt in opts {
+        let mut line = format!("complete -c {program} -l {}", opt.long);
+        if !opt.short.is_empty() {
+            line.push_str(&format!(" -s {}", opt.short));
+        }
+        if opt.takes_value() {
+            line.push_str(" -r");
+            if !opt.takes_path() {
+                line.push_str(" -f");
+            }
+        }
+        let choices = opt.choices();
+        if !choices.is_empty() {
+            line.push_str(&format!(" -a '{}'", choices.join(" ")));
+        }
+        line.push_str(&format!(
+            " -d '{}'",
+            opt.summary.replace('\\', "\\\\").replace('\'', "\\'")
+        ));
+        script.push_str(&line);
+        script.push('\n');
+    }
+    script
+}
+
+/// The completion script for `shell`, completing `program`.
+pub fn generate(shell: &str, program: &str) -> Result<String, String> {
+    match shell {
+        "bash" => Ok(bash(program, OPTIONS)),
+        "zsh" => Ok(zsh(program, OPTIONS)),
+        "fish" => Ok(fish(program, OPTIONS)),
+        other => Err(format!(
+            "no completions for {other:?}, expected {}",
+            SHELLS.join(", ")
+        )),
+    }
+}
@@ -0,0 +1,271 @@
+use std::{env, fmt, os::unix::net::UnixStream, path::PathBuf, process::Command, sync::Mutex};
+
+use swayipc::{Connection, Event, EventType, Fallible, Input, Seat, Version, Workspace};
+
+/// Where sway listens since it restarted, if it did. Kept here rather than
+/// by changing SWAYSOCK, which would race with every thread reading the
+/// environment.
+static MOVED_SOCKET: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+pub fn socket_moved(path: PathBuf) {
+    *MOVED_SOCKET.lock().unwrap_or_else(|err| err.into_inner()) = Some(path);
+}
+
+/// The socket sway listens on: where it moved, else SWAYSOCK.
+pub fn socket() -> Option<PathBuf> {
+    let moved = MOVED_SOCKET.lock().unwrap_or_else(|err| err.into_inner());
+    moved
+        .clone()
+        .or_else(|| env::var_os("SWAYSOCK").map(PathBuf::from))
+}
+
+/// Connects to sway where it moved, or as swayipc finds it.
+pub fn connect() -> Fallible<Connection> {
+    let moved = MOVED_SOCKET
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .clone();
+    match moved {
+        Some(path) => Ok(Connection::from(UnixStream::connect(path)?)),
+        None => Connection::new(),
+    }
+}
+
+/// Gives a helper the socket sway moved to, for e.g. swaymsg in a hook.
+pub fn pass_socket(command: &mut Command) {
+    if let Some(path) = MOVED_SOCKET
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .as_ref()
+    {
+        command.env("SWAYSOCK", path);
+    }
+}
+
+/// Events as they arrive, until the compositor goes away.
+pub type Events = Box<dyn Iterator<Item = Fallible<Event>>>;
+
+/// What the switching logic needs from the compositor. Sway's IPC connection
+/// is the real one; anything else (a mock, another compositor speaking the
+/// same protocol) only has to answer these.
+pub trait Compositor: fmt::Debug {
+    fn get_inputs(&mut self) -> Fallible<Vec<Input>>;
+
+    fn get_seats(&mut self) -> Fallible<Vec<Seat>>;
+
+    fn get_workspaces(&mut self) -> Fallible<Vec<Workspace>>;
+
+    fn get_version(&mut self) -> Fallible<Version>;
+
+    /// Runs a sway command, returning the outcome of each of its parts.
+    fn run_command(&mut self, command: String) -> Fallible<Vec<Fallible<()>>>;
+
+    /// Starts a stream of the given events, independent of this connection.
+    fn subscribe(&mut self, events: &[EventType]) -> Fallible<Events>;
+
+    /// Switches one input (or `type:keyboard`) to a layout index; `false`
+    /// when the compositor rejected it.
+    fn set_layout(&mut self, input: &str, index: i32) -> Fallible<bool> {
+        let outcomes = self.run_command(format!("input {input} xkb_switch_layout {index}"))?;
+        Ok(outcomes.iter().all(Result::is_ok))
+    }
+
+    /// Switches several inputs in one round trip, as commands joined by
+    /// `;`. Tells for each target whether the compositor accepted it.
+    fn set_layouts(&mut self, targets: &[(String, i32)]) -> Fallible<Vec<bool>> {
+        let command = targets
+            .iter()
+            .map(|(input, index)| format!("input {input} xkb_switch_layout {index}"))
+            .collect::<Vec<_>>()
+            .join("; ");
+        let outcomes = self.run_command(command)?;
+        Ok((0..targets.len())
+            .map(|i| outcomes.get(i).is_some_and(Result::is_ok))
+            .collect())
+    }
+}
+
+impl Compositor for Connection {
+    fn get_inputs(&mut self) -> Fallible<Vec<Input>> {
+        Connection::get_inputs(self)
+    }
+
+    fn get_seats(&mut self) -> Fallible<Vec<Seat>> {
+        Connection::get_seats(self)
+    }
+
+    fn get_workspaces(&mut self) -> Fallible<Vec<Workspace>> {
+        Connection::get_workspaces(self)
+    }
+
+    fn get_version(&mut self) -> Fallible<Version> {
+        Connection::get_version(self)
+    }
+
+    fn run_command(&mut self, command: String) -> Fallible<Vec<Fallible<()>>> {
+        Connection::run_command(self, command)
+    }
+
+    /// Sway delivers events on a connection of their own.
+    fn subscribe(&mut self, events: &[EventType]) -> Fallible<Events> {
+        let conn = connect()?;
+        info!("Started event connection to sway-ipc: {:?}", conn);
+        Ok(Box::new(conn.subscribe(events)?))
+    }
+}
+
+/// Keyboards that exist only in memory, for tests. Switch commands change
+/// their active layout like sway would and are recorded; the handles are
+/// shared with the clone kept by the test.
+#[cfg(test)]
+#[derive(Debug, Default, Clone)]
+pub struct Mock {
+    pub inputs: std::rc::Rc<std::cell::RefCell<Vec<Input>>>,
+    pub commands: std::rc::Rc<std::cell::RefCell<Vec<String>>>,
+    pub seats: std::rc::Rc<std::cell::RefCell<Vec<Seat>>>,
+}
+
+#[cfg(test)]
+impl Mock {
+    pub fn with_keyboards(keyboards: &[(&str, &[&str])]) -> Mock {
+        let mock = Mock::default();
+        for (identifier, layouts) in keyboards {
+            let input = serde_json::json!({
+                "identifier": identifier,
+                "name": identifier,
+                "type": "keyboard",
+                "xkb_active_layout_name": layouts[0],
+                "xkb_layout_names": layouts,
+                "xkb_active_layout_index": 0,
+                "scroll_factor": 1.0,
+                "libinput": null,
+                "vendor": null,
+                "product": null,
+            });
+            mock.inputs
+                .borrow_mut()
+                .push(serde_json::from_value(input).unwrap());
+        }
+        mock
+    }
+
+    /// Puts the keyboards named in `seats` on seats of their own, none of
+    /// them focusing anything yet.
+    pub fn with_seats(self, seats: &[(&str, &[&str])]) -> Mock {
+        for (name, keyboards) in seats {
+            let devices: Vec<Input> = self
+                .inputs
+                .borrow()
+                .iter()
+                .filter(|input| keyboards.contains(&input.identifier.as_str()))
+                .cloned()
+                .collect();
+            let seat = serde_json::json!({
+                "name": name,
+                "capabilities": 3,
+                "focus": 0,
+                "devices": devices,
+            });
+            self.seats
+                .borrow_mut()
+                .push(serde_json::from_value(seat).unwrap());
+        }
+        self
+    }
+
+    /// `seat` moving its focus to the window `con_id`.
+    pub fn focus_seat(&self, seat: &str, con_id: i64) {
+        let mut seats = self.seats.borrow_mut();
+        seats.iter_mut().find(|s| s.name == seat).unwrap().focus = con_id;
+    }
+
+    /// Every command run so far.
+    pub fn commands(&self) -> Vec<String> {
+        self.commands.borrow().clone()
+    }
+
+    /// The `input <id> xkb_switch_layout <index>` commands run so far.
+    pub fn switches(&self) -> Vec<(String, i32)> {
+        self.commands
+            .borrow()
+            .iter()
+            .flat_map(|command| parse_switches(command))
+            .collect()
+    }
+
+    /// The user switching `input` by hand.
+    pub fn type_layout(&self, input: &str, index: i32) -> Input {
+        let mut inputs = self.inputs.borrow_mut();
+        let keyboard = inputs
+            .iter_mut()
+            .find(|keyboard| keyboard.identifier == input)
+            .unwrap();
+        keyboard.xkb_active_layout_index = Some(index);
+        keyboard.xkb_active_layout_name = keyboard.xkb_layout_names.get(index as usize).cloned();
+        keyboard.clone()
+    }
+}
+
+#[cfg(test)]
+fn parse_switches(command: &str) -> Vec<(String, i32)> {
+    command
+        .split("; ")
+        .filter_map(|part| {
+            let mut words = part.split(' ');
+            match (words.next(), words.next(), words.next(), words.next()) {
+                (Some("input"), Some(input), Some("xkb_switch_layout"), Some(index)) => {
+                    Some((input.to_string(), index.parse().ok()?))
+                }
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+impl Compositor for Mock {
+    fn get_inputs(&mut self) -> Fallible<Vec<Input>> {
+        Ok(self.inputs.borrow().clone())
+    }
+
+    fn get_seats(&mut self) -> Fallible<Vec<Seat>> {
+        Ok(self.seats.borrow().clone())
+    }
+
+    fn get_workspaces(&mut self) -> Fallible<Vec<Workspace>> {
+        Ok(vec![])
+    }
+
+    fn get_version(&mut self) -> Fallible<Version> {
+        let version = serde_json::json!({
+            "major": 1,
+            "minor": 10,
+            "patch": 0,
+            "human_readable": "1.10",
+            "loaded_config_file_name": "",
+        });
+        Ok(serde_json::from_value(version).unwrap())
+    }
+
+    fn run_command(&mut self, command: String) -> Fallible<Vec<Fallible<()>>> {
+        let parts = command.split("; ").count();
+        for (input, index) in parse_switches(&command) {
+            let ids: Vec<String> = self
+                .inputs
+                .borrow()
+                .iter()
+                .map(|keyboard| keyboard.identifier.clone())
+                .filter(|id| input == "type:keyboard" || *id == input)
+                .collect();
+            for id in ids {
+                self.type_layout(&id, index);
+            }
+        }
+        self.commands.borrow_mut().push(command);
+        Ok((0..parts).map(|_| Ok(())).collect())
+    }
+
+    fn subscribe(&mut self, _events: &[EventType]) -> Fallible<Events> {
+        Ok(Box::new(std::iter::empty()))
+    }
+}
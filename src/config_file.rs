@@ -0,0 +1,466 @@
+use std::{
+    env, fs, io,
+    path::{Path, PathBuf},
+};
+
+use getopts::{Fail, Matches, Options};
+use serde_json::json;
+
+use crate::{
+    glob,
+    toml::{self, Table, Value},
+    Arity, OPTIONS,
+};
+
+/// Prefix of the environment variables naming long options, e.g.
+/// `SWAY_XKB_DEFAULT_LANG` for --default-lang.
+pub const ENV_PREFIX: &str = "SWAY_XKB_";
+
+/// Settings that only make sense on the command line.
+pub const CLI_ONLY: &[&str] = &["help", "config", "replace"];
+
+/// Options given once per entry, since their values may contain commas:
+/// regexes and layout names.
+const REPEATABLE: &[&str] = &[
+    "tabbed-key",
+    "default-lang",
+    "device-default",
+    "force-layout",
+    "xkb-file",
+    "allowed-layouts",
+    "app-default",
+    "ssh-layout",
+];
+
+/// Turns one setting into the `--key=value` form getopts understands, so the
+/// file accepts exactly the long options, validated by the same code. Lists
+/// become comma separated and tables become `key=value` pairs, except for
+/// REPEATABLE options, given once per entry.
+fn to_arg(key: &str, value: &Value) -> Result<Option<String>, String> {
+    let scalar = |path: String, value: &Value| match value {
+        Value::String(s) => Ok(s.clone()),
+        Value::Integer(n) => Ok(n.to_string()),
+        other => Err(format!(
+            "{path}: expected a string or integer, found {} {other}",
+            other.type_name()
+        )),
+    };
+    let value = match value {
+        Value::Boolean(true) => return Ok(Some(format!("--{key}"))),
+        Value::Boolean(false) => return Ok(None),
+        Value::Array(items) => items
+            .iter()
+            .enumerate()
+            .map(|(i, item)| scalar(format!("{key}[{i}]"), item))
+            .collect::<Result<Vec<_>, _>>()?
+            .join(","),
+        Value::Table(table) => table
+            .iter()
+            .map(|(k, v)| scalar(format!("{key}.{k}"), v).map(|v| format!("{k}={v}")))
+            .collect::<Result<Vec<_>, _>>()?
+            .join(","),
+        other => scalar(key.to_string(), other)?,
+    };
+    Ok(Some(format!("--{key}={value}")))
+}
+
+/// The machine's hostname, which `[[host]]` sections are matched against.
+fn hostname() -> Option<String> {
+    let mut name = [0u8; 256];
+    if unsafe { libc::gethostname(name.as_mut_ptr().cast(), name.len()) } != 0 {
+        return None;
+    }
+    let len = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+    Some(String::from_utf8_lossy(&name[..len]).into_owned())
+}
+
+/// Replaces the settings of the top level with those of each `[[host]]`
+/// section whose `name`, a pattern, matches `host`, in file order.
+fn for_host(table: Table, host: Option<&str>) -> Result<Table, String> {
+    let (hosts, mut table): (Table, Table) = table.into_iter().partition(|(key, _)| key == "host");
+    let sections = match hosts.into_iter().next() {
+        None => return Ok(table),
+        Some((_, Value::Array(sections))) => sections,
+        Some((_, other)) => {
+            return Err(format!(
+                "host: expected [[host]] sections, found {}",
+                other.type_name()
+            ))
+        }
+    };
+    for (i, section) in sections.into_iter().enumerate() {
+        let Value::Table(section) = section else {
+            return Err(format!("host[{i}]: expected a [[host]] section"));
+        };
+        let (name, settings): (Table, Table) =
+            section.into_iter().partition(|(key, _)| key == "name");
+        let pattern = match name.first() {
+            Some((_, Value::String(pattern))) => pattern.clone(),
+            _ => return Err(format!("host[{i}]: needs a name = \"<hostname>\"")),
+        };
+        if !host.is_some_and(|host| glob::matches(&pattern, host)) {
+            continue;
+        }
+        for (key, value) in settings {
+            table.retain(|(k, _)| *k != key);
+            table.push((key, value));
+        }
+    }
+    Ok(table)
+}
+
+/// The one of `candidates` closest to a misspelled `word`, if any is close
+/// enough to be what was meant.
+pub fn nearest<'a>(word: &str, candidates: impl IntoIterator<Item = &'a str>) -> Option<&'a str> {
+    let word: Vec<char> = word.to_lowercase().chars().collect();
+    candidates
+        .into_iter()
+        .map(|candidate| {
+            let other: Vec<char> = candidate.to_lowercase().chars().collect();
+            (edit_distance(&word, &other), candidate)
+        })
+        .filter(|(distance, _)| *distance <= (word.len() / 3).max(1))
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, candidate)| candidate)
+}
+
+/// Levenshtein distance: insertions, deletions and substitutions.
+fn edit_distance(a: &[char], b: &[char]) -> usize {
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.iter().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let above = row[j + 1];
+            row[j + 1] = (above + 1)
+                .min(row[j] + 1)
+                .min(diagonal + usize::from(ca != cb));
+            diagonal = above;
+        }
+    }
+    row[b.len()]
+}
+
+/// The error for a setting that is no long option, suggesting the one that
+/// was maybe meant.
+fn unknown_setting(name: &str) -> String {
+    let name = name.trim_start_matches('-');
+    let longs = OPTIONS
+        .iter()
+        .map(|spec| spec.long)
+        .filter(|long| !CLI_ONLY.contains(long));
+    match nearest(name, longs) {
+        Some(long) => format!("unknown setting {name}, did you mean {long}?"),
+        None => format!("unknown setting {name}"),
+    }
+}
+
+/// Where one of `sources`, the files read with their text, sets `key`, as
+/// `<file>: line <n>, column <n>`.
+fn locate(sources: &[(PathBuf, String)], key: &str) -> Option<String> {
+    sources.iter().find_map(|(path, text)| {
+        let (line, column) = toml::locate(text, key)?;
+        Some(format!("{}: line {line}, column {column}", path.display()))
+    })
+}
+
+/// Where the config file at `path`, or a file it includes, sets `key`, as
+/// `<file>: line <n>, column <n>`.
+pub fn position_of(path: &Path, key: &str) -> Option<String> {
+    let mut sources = vec![];
+    read(path, 0, &mut sources).ok()?;
+    locate(&sources, key)
+}
+
+/// How deep files may include each other, so a cycle is an error.
+const MAX_INCLUDE_DEPTH: usize = 8;
+
+/// Adds `from` to `into`: lists and tables are joined, other settings
+/// replaced.
+fn merge(into: &mut Table, from: Table) {
+    for (key, value) in from {
+        match (into.iter_mut().find(|(k, _)| *k == key), value) {
+            (Some((_, Value::Array(items))), Value::Array(more)) => items.extend(more),
+            (Some((_, Value::Table(table))), Value::Table(more)) => merge(table, more),
+            (Some((_, old)), value) => *old = value,
+            (None, value) => into.push((key, value)),
+        }
+    }
+}
+
+/// The files an `include` pattern names, sorted. Relative patterns are
+/// relative to the including file, and wildcards are allowed in the file
+/// name.
+fn expand_include(dir: &Path, pattern: &str) -> Result<Vec<PathBuf>, String> {
+    let pattern = dir.join(pattern);
+    let name = pattern
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    if !name.contains(['*', '?']) {
+        return Ok(vec![pattern]);
+    }
+    let parent = pattern.parent().unwrap_or(dir);
+    let entries = match fs::read_dir(parent) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(err) => return Err(format!("cannot read {}: {err}", parent.display())),
+    };
+    let mut paths: Vec<PathBuf> = entries
+        .flatten()
+        .filter(|entry| glob::matches(&name, &entry.file_name().to_string_lossy()))
+        .map(|entry| entry.path())
+        .collect();
+    paths.sort();
+    Ok(paths)
+}
+
+/// Parses a config file and the files it includes with e.g.
+/// `include = ["rules.d/*.toml"]`, which come first so the including file
+/// can override them. The files read go to `sources` with their text, the
+/// including file first, so errors can point at lines.
+fn read(
+    path: &Path,
+    depth: usize,
+    sources: &mut Vec<(PathBuf, String)>,
+) -> Result<Option<Table>, String> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(format!("cannot read {}: {err}", path.display())),
+    };
+    let table = toml::parse(&text).map_err(|err| format!("{}: {err}", path.display()))?;
+    sources.push((path.to_path_buf(), text));
+    let (includes, table): (Table, Table) =
+        table.into_iter().partition(|(key, _)| key == "include");
+    let patterns = match includes.into_iter().next() {
+        None => vec![],
+        Some((_, Value::String(pattern))) => vec![pattern],
+        Some((_, Value::Array(items))) => items
+            .into_iter()
+            .map(|item| match item {
+                Value::String(pattern) => Ok(pattern),
+                other => Err(format!(
+                    "{}: include: expected a string, found {}",
+                    path.display(),
+                    other.type_name()
+                )),
+            })
+            .collect::<Result<_, _>>()?,
+        Some((_, other)) => {
+            return Err(format!(
+                "{}: include: expected a list of files, found {}",
+                path.display(),
+                other.type_name()
+            ))
+        }
+    };
+    if !patterns.is_empty() && depth == MAX_INCLUDE_DEPTH {
+        return Err(format!(
+            "{}: includes nested too deep, do files include each other?",
+            path.display()
+        ));
+    }
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let mut merged = Table::new();
+    for pattern in &patterns {
+        for include in expand_include(dir, pattern)? {
+            match read(&include, depth + 1, sources)? {
+                Some(included) => merge(&mut merged, included),
+                None => {
+                    return Err(format!(
+                        "{}: included file {} does not exist",
+                        path.display(),
+                        include.display()
+                    ))
+                }
+            }
+        }
+    }
+    merge(&mut merged, table);
+    Ok(Some(merged))
+}
+
+/// Reads a config file with the long options as keys, e.g.
+///
+/// ```toml
+/// default-lang = "English (US)"
+/// tabbed-apps = ["firefox", "chromium"]
+///
+/// [app-alias]
+/// "org.mozilla.firefox" = "firefox"
+///
+/// [[host]]
+/// name = "laptop*"
+/// device-default = ["*AT_Translated*=English (US)"]
+/// ```
+///
+/// where `[[host]]` sections apply only on machines whose hostname matches
+/// their name, overriding the settings above them. `include` adds rules
+/// from other files. Returns `None` when the file does not exist.
+pub fn load(path: &Path, opts: &Options) -> Result<Option<Matches>, String> {
+    let mut sources = vec![];
+    let Some(table) = read(path, 0, &mut sources)? else {
+        return Ok(None);
+    };
+    // Errors name the line setting the key where one does.
+    let at = |key: &str| locate(&sources, key).unwrap_or_else(|| path.display().to_string());
+    let table =
+        for_host(table, hostname().as_deref()).map_err(|err| format!("{}: {err}", at("host")))?;
+
+    let mut args = vec![];
+    for (key, value) in &table {
+        let key = key.replace('_', "-");
+        if CLI_ONLY.contains(&key.as_str()) {
+            return Err(format!(
+                "{}: {key} can only be given on the command line",
+                at(&key)
+            ));
+        }
+        let entries = match value {
+            Value::Array(items) if REPEATABLE.contains(&key.as_str()) => items.clone(),
+            Value::Table(table) if REPEATABLE.contains(&key.as_str()) => table
+                .iter()
+                .map(|entry| Value::Table(vec![entry.clone()]))
+                .collect(),
+            value => vec![value.clone()],
+        };
+        for entry in &entries {
+            if let Some(arg) = to_arg(&key, entry).map_err(|err| format!("{}: {err}", at(&key)))? {
+                args.push(arg);
+            }
+        }
+    }
+    let matches = opts.parse(&args).map_err(|fail| {
+        let (key, message) = match fail {
+            Fail::UnrecognizedOption(key) => (key.clone(), unknown_setting(&key)),
+            Fail::UnexpectedArgument(key) => {
+                let message = format!("{key} is a switch, set it to true or false");
+                (key, message)
+            }
+            Fail::ArgumentMissing(key) => {
+                let message = format!("{key} needs a value");
+                (key, message)
+            }
+            other => (String::new(), other.to_string()),
+        };
+        format!("{}: {message}", at(key.trim_start_matches('-')))
+    })?;
+    Ok(Some(matches))
+}
+
+/// A JSON Schema of the config file for editors, e.g. taplo's
+/// `#:schema ./sway-xkb-switcher.schema.json` or Even Better TOML. It
+/// allows what `load` does: values of options as strings or integers,
+/// lists and tables of them, switches as booleans, `include` and
+/// `[[host]]` sections.
+pub fn schema() -> serde_json::Value {
+    let scalar = json!({ "type": ["string", "integer"] });
+    let mut settings = serde_json::Map::new();
+    for spec in OPTIONS {
+        if CLI_ONLY.contains(&spec.long) {
+            continue;
+        }
+        let mut property = match spec.arity {
+            Arity::Flag | Arity::FlagMulti => json!({ "type": "boolean" }),
+            Arity::FlagValue => json!({ "type": ["boolean", "string", "integer"] }),
+            Arity::Value if !spec.choices().is_empty() => json!({ "enum": spec.choices() }),
+            Arity::Value | Arity::Multi => json!({
+                "anyOf": [
+                    scalar,
+                    { "type": "array", "items": scalar },
+                    { "type": "object", "additionalProperties": scalar },
+                ]
+            }),
+        };
+        property["description"] = json!(spec.help);
+        settings.insert(spec.long.to_string(), property);
+    }
+    let mut host = settings.clone();
+    host.insert(
+        "name".into(),
+        json!({ "type": "string", "description": "Hostname pattern, globs allowed." }),
+    );
+    let mut top = settings;
+    top.insert(
+        "include".into(),
+        json!({
+            "description": "Files read first, relative to this one, globs allowed.",
+            "anyOf": [
+                { "type": "string" },
+                { "type": "array", "items": { "type": "string" } },
+            ]
+        }),
+    );
+    top.insert(
+        "host".into(),
+        json!({
+            "description": "Settings for machines whose hostname matches name.",
+            "type": "array",
+            "items": {
+                "type": "object",
+                "properties": host,
+                "required": ["name"],
+                "additionalProperties": false,
+            }
+        }),
+    );
+    json!({
+        "$schema": "http://json-schema.org/draft-07/schema#",
+        "title": format!("{} config", env!("CARGO_PKG_NAME")),
+        "type": "object",
+        "properties": top,
+        "additionalProperties": false,
+    })
+}
+
+/// Reads long options from `SWAY_XKB_*` variables, e.g. set in a systemd
+/// drop-in. Switches are on for `1`, `true` or `yes` and off for `0`,
+/// `false`, `no` or nothing. Returns `None` when no variable is set, and
+/// the variables that name no setting, to be warned about: a stray one in
+/// the environment should not keep the daemon from starting.
+pub fn from_env(opts: &Options) -> Result<(Option<Matches>, Vec<String>), String> {
+    from_vars(opts, env::vars())
+}
+
+/// `from_env` with the variables given.
+pub fn from_vars(
+    opts: &Options,
+    vars: impl IntoIterator<Item = (String, String)>,
+) -> Result<(Option<Matches>, Vec<String>), String> {
+    let mut args = vec![];
+    let mut skipped = vec![];
+    for (name, value) in vars {
+        let Some(key) = name.strip_prefix(ENV_PREFIX) else {
+            continue;
+        };
+        let key = key.to_lowercase().replace('_', "-");
+        if CLI_ONLY.contains(&key.as_str()) {
+            skipped.push(format!(
+                "{name}: {key} can only be given on the command line"
+            ));
+            continue;
+        }
+        let arg = format!("--{key}={value}");
+        let arg = match opts.parse([&arg]) {
+            // A switch: only its value tells whether it is given.
+            Err(Fail::UnexpectedArgument(_)) => match value.to_lowercase().as_str() {
+                "1" | "true" | "yes" => format!("--{key}"),
+                "" | "0" | "false" | "no" => continue,
+                _ => return Err(format!("{name}: {key} is a switch, set it to 1 or 0")),
+            },
+            Err(Fail::UnrecognizedOption(_)) => {
+                skipped.push(format!("{name}: {}", unknown_setting(&key)));
+                continue;
+            }
+            _ => arg,
+        };
+        args.push(arg);
+    }
+    if args.is_empty() {
+        return Ok((None, skipped));
+    }
+    opts.parse(&args)
+        .map(|matches| (Some(matches), skipped))
+        .map_err(|fail| format!("{ENV_PREFIX}*: {fail}"))
+}
@@ -0,0 +1,119 @@
+use std::{
+    fs,
+    io::{self, BufRead, BufReader, Write},
+    os::unix::net::{UnixListener, UnixStream},
+    path::Path,
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use serde_json::Value;
+use swayipc::Connection;
+
+use crate::compositor;
+
+/// A command line read from the control socket, answered by the event loop.
+#[derive(Debug)]
+pub struct Request {
+    pub command: String,
+    reply: Sender<Value>,
+}
+
+impl Request {
+    pub fn reply(self, response: Value) {
+        let _ = self.reply.send(response);
+    }
+}
+
+/// Connections that sent `watch`, which get a line for each decision the
+/// daemon makes until they hang up.
+#[derive(Debug, Clone, Default)]
+pub struct Watchers(Arc<Mutex<Vec<UnixStream>>>);
+
+impl Watchers {
+    pub fn add(&self, stream: UnixStream) {
+        // A watcher that stops reading must not stall the event loop.
+        let _ = stream.set_write_timeout(Some(Duration::from_millis(100)));
+        let mut streams = self.0.lock().unwrap_or_else(|err| err.into_inner());
+        streams.push(stream);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.lock().map_or(true, |streams| streams.is_empty())
+    }
+
+    pub fn send(&self, line: &str) {
+        let mut streams = self.0.lock().unwrap_or_else(|err| err.into_inner());
+        streams.retain(|mut stream| writeln!(stream, "{line}").is_ok());
+    }
+}
+
+/// Listens on `path` for one command per connection, e.g. `status`,
+/// `forget 42` or the same as a JSON object, and answers with one line of
+/// JSON. Requests are queued for
+/// the event loop, which is woken with the `wake` tick since it blocks on
+/// sway events. `watch` connections are handed to `watchers` instead.
+pub fn listen(path: &Path, wake: String, watchers: Watchers) -> io::Result<Receiver<Request>> {
+    // A leftover from a crashed instance would make bind fail.
+    if UnixStream::connect(path).is_err() {
+        let _ = fs::remove_file(path);
+    }
+    let listener = UnixListener::bind(path)?;
+    info!("Listening for control commands on {}", path.display());
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut sway: Option<Connection> = None;
+        for stream in listener.incoming().flatten() {
+            if let Err(err) = serve(stream, &sender, &mut sway, &wake, &watchers) {
+                warn!("Control connection failed: {err}");
+            }
+        }
+    });
+    Ok(receiver)
+}
+
+fn serve(
+    stream: UnixStream,
+    requests: &Sender<Request>,
+    sway: &mut Option<Connection>,
+    wake: &str,
+    watchers: &Watchers,
+) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut command = String::new();
+    BufReader::new(&stream).read_line(&mut command)?;
+    let command = command.trim().to_string();
+    debug!("Control command: {command}");
+    if command == "watch" {
+        watchers.add(stream);
+        return Ok(());
+    }
+
+    let (reply, response) = mpsc::channel();
+    requests
+        .send(Request { command, reply })
+        .map_err(|_| io::Error::other("event loop is gone"))?;
+    let woken = match sway {
+        Some(conn) => conn.send_tick(wake),
+        None => compositor::connect().and_then(|mut conn| {
+            let woken = conn.send_tick(wake);
+            *sway = Some(conn);
+            woken
+        }),
+    };
+    if let Err(err) = woken {
+        *sway = None;
+        return Err(io::Error::other(format!(
+            "cannot wake the event loop: {err}"
+        )));
+    }
+
+    let response = response
+        .recv_timeout(Duration::from_secs(5))
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "no reply from the event loop"))?;
+    writeln!(&stream, "{response}")
+}
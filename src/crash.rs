@@ -0,0 +1,85 @@
+use std::{
+    backtrace::Backtrace,
+    collections::VecDeque,
+    fmt::Write as _,
+    fs, panic,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::paths;
+
+const RECENT_EVENTS: usize = 64;
+
+struct Context {
+    summary: String,
+    sway_version: String,
+    events: VecDeque<String>,
+}
+
+static CONTEXT: Mutex<Context> = Mutex::new(Context {
+    summary: String::new(),
+    sway_version: String::new(),
+    events: VecDeque::new(),
+});
+
+pub fn set_summary(summary: String) {
+    if let Ok(mut ctx) = CONTEXT.lock() {
+        ctx.summary = summary;
+    }
+}
+
+pub fn set_sway_version(version: String) {
+    if let Ok(mut ctx) = CONTEXT.lock() {
+        ctx.sway_version = version;
+    }
+}
+
+pub fn record_event(event: String) {
+    if let Ok(mut ctx) = CONTEXT.lock() {
+        if ctx.events.len() == RECENT_EVENTS {
+            ctx.events.pop_front();
+        }
+        ctx.events.push_back(event);
+    }
+}
+
+fn report(info: &panic::PanicHookInfo) -> String {
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "{} {}",
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION")
+    );
+    let _ = writeln!(out, "panic: {info}\n");
+    if let Ok(ctx) = CONTEXT.try_lock() {
+        let _ = writeln!(out, "sway: {}", ctx.sway_version);
+        let _ = writeln!(out, "config: {}\n", ctx.summary);
+        let _ = writeln!(out, "recent events (oldest first):");
+        for event in &ctx.events {
+            let _ = writeln!(out, "  {event}");
+        }
+    }
+    let _ = writeln!(out, "\nbacktrace:\n{}", Backtrace::force_capture());
+    out
+}
+
+/// Chains a panic hook that writes a crash report to the state dir before
+/// running the default hook.
+pub fn install_hook() {
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        let dir = paths::session_state_dir();
+        let path = dir.join(format!("crash-{timestamp}.txt"));
+        match fs::create_dir_all(&dir).and_then(|_| fs::write(&path, report(info))) {
+            Ok(()) => eprintln!("Crash report written to {}", path.display()),
+            Err(err) => eprintln!("Failed to write crash report: {err}"),
+        }
+        default_hook(info);
+    }));
+}
@@ -0,0 +1,50 @@
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    os::fd::AsRawFd,
+    path::Path,
+};
+
+fn fork() -> io::Result<bool> {
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => Ok(true),
+        _ => Ok(false),
+    }
+}
+
+/// Detaches the process from its controlling terminal using the classic
+/// double fork, sends stdout/stderr to `log_file` and writes the pid of the
+/// surviving grandchild to `pid_file`. Only the grandchild returns.
+pub fn daemonize(log_file: &Path, pid_file: &Path) -> io::Result<()> {
+    if !fork()? {
+        std::process::exit(0);
+    }
+    if unsafe { libc::setsid() } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    if !fork()? {
+        std::process::exit(0);
+    }
+    std::env::set_current_dir("/")?;
+
+    if let Some(dir) = log_file.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let null = File::open("/dev/null")?;
+    let log = File::options().create(true).append(true).open(log_file)?;
+    unsafe {
+        libc::dup2(null.as_raw_fd(), libc::STDIN_FILENO);
+        libc::dup2(log.as_raw_fd(), libc::STDOUT_FILENO);
+        libc::dup2(log.as_raw_fd(), libc::STDERR_FILENO);
+    }
+
+    let mut pid = File::create(pid_file)?;
+    writeln!(pid, "{}", std::process::id())?;
+    info!(
+        "Daemonized with pid {}, pidfile {}",
+        std::process::id(),
+        pid_file.display()
+    );
+    Ok(())
+}
@@ -0,0 +1,688 @@
+use std::{
+    env,
+    ffi::OsStr,
+    io::{self, Read, Write},
+    net::Shutdown,
+    os::{
+        linux::net::SocketAddrExt,
+        unix::{
+            ffi::OsStrExt,
+            net::{SocketAddr, UnixStream},
+        },
+    },
+    sync::{
+        mpsc::{self, Sender},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use crate::paths;
+
+/// Bus name of the default instance; named instances append their name.
+pub const NAME: &str = "org.sway.XkbSwitcher";
+const PATH: &str = "/org/sway/XkbSwitcher";
+const INTERFACE: &str = "org.sway.XkbSwitcher";
+const PROPERTIES: &str = "org.freedesktop.DBus.Properties";
+const INTROSPECTABLE: &str = "org.freedesktop.DBus.Introspectable";
+const PEER: &str = "org.freedesktop.DBus.Peer";
+
+/// Methods of the interface, with the daemon command each runs.
+const METHODS: &[(&str, &str)] = &[
+    ("Pause", "pause"),
+    ("Resume", "resume"),
+    ("Forget", "forget"),
+];
+
+/// How long authenticating and each call to the bus itself may take.
+const TIMEOUT: Duration = Duration::from_secs(2);
+
+const METHOD_CALL: u8 = 1;
+const METHOD_RETURN: u8 = 2;
+const ERROR: u8 = 3;
+const SIGNAL: u8 = 4;
+const NO_REPLY_EXPECTED: u8 = 0x1;
+/// RequestName flag: fail rather than wait in line for the name.
+const DO_NOT_QUEUE: u32 = 0x4;
+
+const INTROSPECTION: &str = r#"<!DOCTYPE node PUBLIC "-//freedesktop//DTD D-BUS Object Introspection 1.0//EN"
+ "http://www.freedesktop.org/standards/dbus/1.0/introspect.dtd">
+<node>
+  <interface name="org.sway.XkbSwitcher">
+    <method name="Pause"/>
+    <method name="Resume"/>
+    <method name="Forget"/>
+    <property name="Layout" type="s" access="read"/>
+    <property name="AppId" type="s" access="read"/>
+  </interface>
+  <interface name="org.freedesktop.DBus.Properties">
+    <method name="Get">
+      <arg name="interface" type="s" direction="in"/>
+      <arg name="property" type="s" direction="in"/>
+      <arg name="value" type="v" direction="out"/>
+    </method>
+    <method name="GetAll">
+      <arg name="interface" type="s" direction="in"/>
+      <arg name="properties" type="a{sv}" direction="out"/>
+    </method>
+    <signal name="PropertiesChanged">
+      <arg name="interface" type="s"/>
+      <arg name="changed" type="a{sv}"/>
+      <arg name="invalidated" type="as"/>
+    </signal>
+  </interface>
+  <interface name="org.freedesktop.DBus.Introspectable">
+    <method name="Introspect">
+      <arg name="xml" type="s" direction="out"/>
+    </method>
+  </interface>
+  <interface name="org.freedesktop.DBus.Peer">
+    <method name="Ping"/>
+  </interface>
+</node>
+"#;
+
+/// Minimal D-Bus service: owns a name on the session bus, answers
+/// `Get`/`GetAll` for the layout and app of the focused window, signals
+/// `PropertiesChanged` when they change and runs Pause, Resume and Forget.
+/// Like the MQTT publisher, the bus is talked to on threads of its own.
+#[derive(Debug)]
+pub struct Service {
+    updates: Sender<Properties>,
+    last: Properties,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+struct Properties {
+    layout: String,
+    app_id: String,
+}
+
+impl Properties {
+    fn all(&self) -> [(&str, &str); 2] {
+        [("Layout", &self.layout), ("AppId", &self.app_id)]
+    }
+
+    fn get(&self, name: &str) -> Option<&str> {
+        self.all()
+            .into_iter()
+            .find(|(known, _)| *known == name)
+            .map(|(_, value)| value)
+    }
+}
+
+/// The session bus, from DBUS_SESSION_BUS_ADDRESS or the `bus` socket in
+/// XDG_RUNTIME_DIR where systemd puts it.
+pub fn session_address() -> String {
+    match env::var("DBUS_SESSION_BUS_ADDRESS") {
+        Ok(address) if !address.is_empty() => address,
+        _ => format!(
+            "unix:path={}",
+            paths::xdg_runtime_dir().join("bus").display()
+        ),
+    }
+}
+
+/// Bus name of `instance`, e.g. `org.sway.XkbSwitcher.work`.
+pub fn bus_name(instance: &str) -> String {
+    if instance == "default" {
+        return NAME.to_string();
+    }
+    let element: String = instance
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    match element.starts_with(|c: char| c.is_ascii_digit()) {
+        true => format!("{NAME}._{element}"),
+        false => format!("{NAME}.{element}"),
+    }
+}
+
+impl Service {
+    /// Serves `name` on the bus at `address`; `run` gets the daemon command
+    /// of each method called.
+    pub fn start(address: String, name: String, run: impl Fn(&str) + Send + 'static) -> Service {
+        let (updates, receiver) = mpsc::channel::<Properties>();
+        thread::spawn(move || {
+            let (bus, incoming) = match register(&address, &name) {
+                Ok(bus) => bus,
+                Err(err) => {
+                    warn!("Cannot serve {name} on D-Bus at {address}: {err}");
+                    return;
+                }
+            };
+            info!("Serving {name} on D-Bus");
+            let bus = Arc::new(Mutex::new(bus));
+            let properties = Arc::new(Mutex::new(Properties::default()));
+            {
+                let (bus, properties) = (bus.clone(), properties.clone());
+                thread::spawn(move || serve(incoming, &bus, &properties, run));
+            }
+            while let Ok(update) = receiver.recv() {
+                // Only the latest state matters after a slow send.
+                let update = receiver.try_iter().last().unwrap_or(update);
+                let old = std::mem::replace(&mut *properties.lock().unwrap(), update.clone());
+                if let Err(err) = bus.lock().unwrap().send(changed(&old, &update)) {
+                    warn!("Lost the D-Bus connection: {err}");
+                    break;
+                }
+            }
+            // Ends `serve` too, and with it the name.
+            let _ = bus.lock().unwrap().stream.shutdown(Shutdown::Both);
+        });
+        Service {
+            updates,
+            last: Properties::default(),
+        }
+    }
+
+    /// Publishes the layout and focused app, skipping repeats.
+    pub fn publish(&mut self, layout: &str, app_id: Option<&str>) {
+        let update = Properties {
+            layout: layout.to_string(),
+            app_id: app_id.unwrap_or_default().to_string(),
+        };
+        if update == self.last {
+            return;
+        }
+        let _ = self.updates.send(update.clone());
+        self.last = update;
+    }
+}
+
+/// The connection, shared by the threads that write to it.
+#[derive(Debug)]
+struct Bus {
+    stream: UnixStream,
+    serial: u32,
+}
+
+impl Bus {
+    fn send(&mut self, mut message: Message) -> io::Result<u32> {
+        self.serial += 1;
+        message.serial = self.serial;
+        self.stream.write_all(&message.encode())?;
+        Ok(self.serial)
+    }
+
+    /// Calls a method of the bus itself and waits for its reply.
+    fn call(&mut self, member: &str, signature: &str, body: Vec<u8>) -> io::Result<Message> {
+        let serial = self.send(Message {
+            kind: METHOD_CALL,
+            path: Some("/org/freedesktop/DBus".to_string()),
+            interface: Some("org.freedesktop.DBus".to_string()),
+            member: Some(member.to_string()),
+            destination: Some("org.freedesktop.DBus".to_string()),
+            signature: signature.to_string(),
+            body,
+            ..Message::default()
+        })?;
+        loop {
+            let reply = read(&mut self.stream)?;
+            if reply.reply_serial != Some(serial) {
+                continue;
+            }
+            if reply.kind == ERROR {
+                let error = reply.error.unwrap_or_default();
+                return Err(io::Error::other(format!("{member} failed: {error}")));
+            }
+            return Ok(reply);
+        }
+    }
+}
+
+/// Connects to the bus, authenticates as our uid and takes `name`.
+/// Returns the connection and a handle to read calls from.
+fn register(address: &str, name: &str) -> io::Result<(Bus, UnixStream)> {
+    let mut stream = open(address)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    let uid = unsafe { libc::getuid() }.to_string();
+    let uid: String = uid.bytes().map(|byte| format!("{byte:02x}")).collect();
+    stream.write_all(format!("\0AUTH EXTERNAL {uid}\r\n").as_bytes())?;
+    let line = read_line(&mut stream)?;
+    if !line.starts_with("OK ") {
+        return Err(io::Error::other(format!(
+            "bus refused to authenticate us: {}",
+            line.trim_end()
+        )));
+    }
+    stream.write_all(b"BEGIN\r\n")?;
+
+    let mut bus = Bus { stream, serial: 0 };
+    bus.call("Hello", "", vec![])?;
+    let mut body = Writer::default();
+    body.str(name);
+    body.u32(DO_NOT_QUEUE);
+    let reply = bus.call("RequestName", "su", body.buf)?;
+    // 1: now the owner, 4: was already.
+    match reply.args().u32()? {
+        1 | 4 => {}
+        _ => {
+            return Err(io::Error::other(format!(
+                "{name} is taken, is another daemon of this instance running?"
+            )))
+        }
+    }
+    bus.stream.set_read_timeout(None)?;
+    let incoming = bus.stream.try_clone()?;
+    Ok((bus, incoming))
+}
+
+/// Connects to the first unix socket of a D-Bus address such as
+/// `unix:path=/run/user/1000/bus` or `unix:abstract=/tmp/dbus-x,guid=...`.
+fn open(address: &str) -> io::Result<UnixStream> {
+    let mut failed = None;
+    for entry in address.split(';') {
+        let Some(params) = entry.strip_prefix("unix:") else {
+            continue;
+        };
+        for param in params.split(',') {
+            let addr = match param.split_once('=') {
+                Some(("path", path)) => {
+                    SocketAddr::from_pathname(OsStr::from_bytes(&unescape(path)))?
+                }
+                Some(("abstract", name)) => SocketAddr::from_abstract_name(unescape(name))?,
+                _ => continue,
+            };
+            match UnixStream::connect_addr(&addr) {
+                Ok(stream) => return Ok(stream),
+                Err(err) => failed = Some(err),
+            }
+        }
+    }
+    Err(failed.unwrap_or_else(|| io::Error::other("no unix socket in the address")))
+}
+
+/// Undoes the %-escaping of address values.
+fn unescape(value: &str) -> Vec<u8> {
+    let mut bytes = vec![];
+    let mut rest = value.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        let hex = tail.get(..2).and_then(|hex| std::str::from_utf8(hex).ok());
+        match hex.and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+            Some(escaped) if byte == b'%' => {
+                bytes.push(escaped);
+                rest = &tail[2..];
+            }
+            _ => {
+                bytes.push(byte);
+                rest = tail;
+            }
+        }
+    }
+    bytes
+}
+
+fn read_line(stream: &mut UnixStream) -> io::Result<String> {
+    let mut line = vec![];
+    let mut byte = [0u8];
+    while !line.ends_with(b"\r\n") {
+        stream.read_exact(&mut byte)?;
+        line.push(byte[0]);
+        if line.len() > 4096 {
+            return Err(io::Error::other("bus sent an overlong line"));
+        }
+    }
+    Ok(String::from_utf8_lossy(&line).into_owned())
+}
+
+/// Answers calls until the connection closes.
+fn serve(
+    mut incoming: UnixStream,
+    bus: &Mutex<Bus>,
+    properties: &Mutex<Properties>,
+    run: impl Fn(&str),
+) {
+    loop {
+        let call = match read(&mut incoming) {
+            Ok(call) => call,
+            Err(err) => {
+                debug!("D-Bus connection closed: {err}");
+                return;
+            }
+        };
+        if call.kind != METHOD_CALL {
+            continue;
+        }
+        let properties = properties.lock().unwrap().clone();
+        let reply = answer(&call, &properties, &run);
+        if call.flags & NO_REPLY_EXPECTED != 0 {
+            continue;
+        }
+        let reply = Message {
+            reply_serial: Some(call.serial),
+            destination: call.sender,
+            ..reply
+        };
+        if let Err(err) = bus.lock().unwrap().send(reply) {
+            warn!("Cannot answer on D-Bus: {err}");
+            return;
+        }
+    }
+}
+
+fn answer(call: &Message, properties: &Properties, run: &impl Fn(&str)) -> Message {
+    let member = call.member.as_deref().unwrap_or_default();
+    if call.path.as_deref() != Some(PATH) {
+        return error("UnknownObject", format!("no object {:?}", call.path));
+    }
+    // The interface may be left out of calls.
+    let interface = call.interface.as_deref();
+    let of = |name: &str| interface.is_none_or(|interface| interface == name);
+    match member {
+        "Introspect" if of(INTROSPECTABLE) => reply("s", |w| w.str(INTROSPECTION)),
+        "Ping" if of(PEER) => reply("", |_| {}),
+        "Get" if of(PROPERTIES) => {
+            let mut args = call.args();
+            let (Ok(_), Ok(name)) = (args.str(), args.str()) else {
+                return error("InvalidArgs", "expected an interface and a property".into());
+            };
+            match properties.get(&name) {
+                Some(value) => reply("v", |w| {
+                    w.sig("s");
+                    w.str(value);
+                }),
+                None => error("UnknownProperty", format!("no property {name}")),
+            }
+        }
+        "GetAll" if of(PROPERTIES) => match call.args().str() {
+            Ok(name) if name == INTERFACE || name.is_empty() => {
+                reply("a{sv}", |w| w.dict(&properties.all()))
+            }
+            _ => reply("a{sv}", |w| w.dict(&[])),
+        },
+        "Set" if of(PROPERTIES) => error("PropertyReadOnly", "all properties are read-only".into()),
+        _ => match METHODS.iter().find(|(method, _)| *method == member) {
+            Some((_, command)) if of(INTERFACE) => {
+                run(command);
+                reply("", |_| {})
+            }
+            _ => error("UnknownMethod", format!("no method {member}")),
+        },
+    }
+}
+
+fn reply(signature: &str, body: impl FnOnce(&mut Writer)) -> Message {
+    let mut writer = Writer::default();
+    body(&mut writer);
+    Message {
+        kind: METHOD_RETURN,
+        signature: signature.to_string(),
+        body: writer.buf,
+        ..Message::default()
+    }
+}
+
+fn error(name: &str, text: String) -> Message {
+    Message {
+        error: Some(format!("org.freedesktop.DBus.Error.{name}")),
+        kind: ERROR,
+        ..reply("s", |w| w.str(&text))
+    }
+}
+
+/// The PropertiesChanged signal from `old` to `new`.
+fn changed(old: &Properties, new: &Properties) -> Message {
+    let changed: Vec<(&str, &str)> = new
+        .all()
+        .into_iter()
+        .filter(|(name, value)| old.get(name) != Some(value))
+        .collect();
+    let mut body = Writer::default();
+    body.str(INTERFACE);
+    body.dict(&changed);
+    body.array(4, |_| {});
+    Message {
+        kind: SIGNAL,
+        path: Some(PATH.to_string()),
+        interface: Some(PROPERTIES.to_string()),
+        member: Some("PropertiesChanged".to_string()),
+        signature: "sa{sv}as".to_string(),
+        body: body.buf,
+        ..Message::default()
+    }
+}
+
+/// A message, with the header fields used here.
+#[derive(Debug, Default)]
+struct Message {
+    kind: u8,
+    flags: u8,
+    serial: u32,
+    path: Option<String>,
+    interface: Option<String>,
+    member: Option<String>,
+    error: Option<String>,
+    reply_serial: Option<u32>,
+    destination: Option<String>,
+    sender: Option<String>,
+    signature: String,
+    body: Vec<u8>,
+    big_endian: bool,
+}
+
+impl Message {
+    /// Always little-endian.
+    fn encode(&self) -> Vec<u8> {
+        let mut w = Writer::default();
+        w.buf.extend_from_slice(&[b'l', self.kind, self.flags, 1]);
+        w.u32(self.body.len() as u32);
+        w.u32(self.serial);
+        let strings = [
+            (1, "o", &self.path),
+            (2, "s", &self.interface),
+            (3, "s", &self.member),
+            (4, "s", &self.error),
+            (6, "s", &self.destination),
+        ];
+        w.array(8, |w| {
+            for (code, signature, value) in strings {
+                if let Some(value) = value {
+                    w.pad(8);
+                    w.buf.push(code);
+                    w.sig(signature);
+                    w.str(value);
+                }
+            }
+            if let Some(serial) = self.reply_serial {
+                w.pad(8);
+                w.buf.push(5);
+                w.sig("u");
+                w.u32(serial);
+            }
+            if !self.signature.is_empty() {
+                w.pad(8);
+                w.buf.push(8);
+                w.sig("g");
+                w.sig(&self.signature);
+            }
+        });
+        w.pad(8);
+        w.buf.extend_from_slice(&self.body);
+        w.buf
+    }
+
+    fn args(&self) -> Reader<'_> {
+        Reader {
+            buf: &self.body,
+            pos: 0,
+            big_endian: self.big_endian,
+        }
+    }
+}
+
+/// Reads one message.
+fn read(stream: &mut impl Read) -> io::Result<Message> {
+    let mut fixed = [0u8; 16];
+    stream.read_exact(&mut fixed)?;
+    let big_endian = match fixed[0] {
+        b'l' => false,
+        b'B' => true,
+        other => return Err(invalid(format!("unknown byte order {other}"))),
+    };
+    let mut r = Reader {
+        buf: &fixed,
+        pos: 4,
+        big_endian,
+    };
+    let (body_len, serial, fields_len) = (r.u32()? as usize, r.u32()?, r.u32()? as usize);
+    // The limit the spec puts on messages.
+    if body_len + fields_len > 1 << 27 {
+        return Err(invalid("message too long".into()));
+    }
+    let header_len = (16 + fields_len).next_multiple_of(8);
+    let mut buf = fixed.to_vec();
+    buf.resize(header_len + body_len, 0);
+    stream.read_exact(&mut buf[16..])?;
+
+    let mut message = Message {
+        kind: fixed[1],
+        flags: fixed[2],
+        serial,
+        big_endian,
+        ..Message::default()
+    };
+    let mut r = Reader {
+        buf: &buf[..16 + fields_len],
+        pos: 16,
+        big_endian,
+    };
+    while r.pos < r.buf.len() {
+        r.align(8);
+        let code = r.byte()?;
+        match r.sig()?.as_str() {
+            "s" | "o" => {
+                let value = Some(r.str()?);
+                match code {
+                    1 => message.path = value,
+                    2 => message.interface = value,
+                    3 => message.member = value,
+                    4 => message.error = value,
+                    6 => message.destination = value,
+                    7 => message.sender = value,
+                    _ => {}
+                }
+            }
+            "g" => {
+                let value = r.sig()?;
+                if code == 8 {
+                    message.signature = value;
+                }
+            }
+            "u" => {
+                let value = r.u32()?;
+                if code == 5 {
+                    message.reply_serial = Some(value);
+                }
+            }
+            other => return Err(invalid(format!("unknown header field type {other}"))),
+        }
+    }
+    message.body = buf.split_off(header_len);
+    Ok(message)
+}
+
+fn invalid(text: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, text)
+}
+
+#[derive(Debug, Default)]
+struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    fn pad(&mut self, align: usize) {
+        self.buf.resize(self.buf.len().next_multiple_of(align), 0);
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.pad(4);
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn str(&mut self, value: &str) {
+        self.u32(value.len() as u32);
+        self.buf.extend_from_slice(value.as_bytes());
+        self.buf.push(0);
+    }
+
+    fn sig(&mut self, value: &str) {
+        self.buf.push(value.len() as u8);
+        self.buf.extend_from_slice(value.as_bytes());
+        self.buf.push(0);
+    }
+
+    /// An array of elements aligned to `align`, written by `elements`.
+    fn array(&mut self, align: usize, elements: impl FnOnce(&mut Writer)) {
+        self.u32(0);
+        let len_at = self.buf.len() - 4;
+        self.pad(align);
+        let start = self.buf.len();
+        elements(self);
+        let len = (self.buf.len() - start) as u32;
+        self.buf[len_at..len_at + 4].copy_from_slice(&len.to_le_bytes());
+    }
+
+    /// An `a{sv}` of string values.
+    fn dict(&mut self, entries: &[(&str, &str)]) {
+        self.array(8, |w| {
+            for (key, value) in entries {
+                w.pad(8);
+                w.str(key);
+                w.sig("s");
+                w.str(value);
+            }
+        });
+    }
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+    big_endian: bool,
+}
+
+impl<'a> Reader<'a> {
+    fn align(&mut self, align: usize) {
+        self.pos = self.pos.next_multiple_of(align);
+    }
+
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        let bytes = self
+            .buf
+            .get(self.pos..self.pos + len)
+            .ok_or_else(|| invalid("message ends early".into()))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        self.align(4);
+        let bytes: [u8; 4] = self.take(4)?.try_into().unwrap();
+        Ok(match self.big_endian {
+            true => u32::from_be_bytes(bytes),
+            false => u32::from_le_bytes(bytes),
+        })
+    }
+
+    fn text(&mut self, len: usize) -> io::Result<String> {
+        let bytes = self.take(len + 1)?;
+        String::from_utf8(bytes[..len].to_vec()).map_err(|err| invalid(err.to_string()))
+    }
+
+    fn str(&mut self) -> io::Result<String> {
+        let len = self.u32()? as usize;
+        self.text(len)
+    }
+
+    fn sig(&mut self) -> io::Result<String> {
+        let len = self.byte()? as usize;
+        self.text(len)
+    }
+}
@@ -0,0 +1,60 @@
+use std::{
+    sync::mpsc::{self, RecvTimeoutError, Sender},
+    thread,
+    time::Instant,
+};
+
+use swayipc::Connection;
+
+use crate::compositor;
+
+/// Wakes the event loop with a tick once no new deadline was set before the
+/// last one passed, so a burst of focus changes ends in a single wake-up.
+#[derive(Debug)]
+pub struct Timer {
+    deadlines: Sender<Instant>,
+}
+
+impl Timer {
+    pub fn start(payload: String) -> Timer {
+        let (deadlines, receiver) = mpsc::channel::<Instant>();
+        thread::spawn(move || {
+            let mut sway: Option<Connection> = None;
+            while let Ok(mut deadline) = receiver.recv() {
+                loop {
+                    let wait = deadline.saturating_duration_since(Instant::now());
+                    match receiver.recv_timeout(wait) {
+                        Ok(later) => deadline = later,
+                        Err(RecvTimeoutError::Timeout) => break,
+                        Err(RecvTimeoutError::Disconnected) => return,
+                    }
+                }
+                let sent = match &mut sway {
+                    Some(conn) => conn.send_tick(&payload),
+                    None => compositor::connect().and_then(|mut conn| {
+                        let sent = conn.send_tick(&payload);
+                        sway = Some(conn);
+                        sent
+                    }),
+                };
+                if let Err(err) = sent {
+                    warn!("Cannot wake the event loop after the debounce delay: {err}");
+                    sway = None;
+                }
+            }
+        });
+        Timer { deadlines }
+    }
+
+    /// A timer that never fires, for tests that deliver its tick themselves.
+    #[cfg(test)]
+    pub fn idle() -> Timer {
+        Timer {
+            deadlines: mpsc::channel().0,
+        }
+    }
+
+    pub fn schedule(&self, deadline: Instant) {
+        let _ = self.deadlines.send(deadline);
+    }
+}
@@ -0,0 +1,20 @@
+use thiserror::Error;
+
+/// Conditions the daemon cannot recover from. Failed IPC requests and
+/// events are logged and skipped instead.
+#[derive(Debug, Error)]
+pub enum Fatal {
+    #[error("cannot connect to sway: {0}")]
+    Connect(#[source] swayipc::Error),
+    #[error("lost connection to sway: {0}")]
+    Disconnected(#[source] swayipc::Error),
+}
+
+/// An event this swayipc cannot decode, e.g. one added by a newer sway. The
+/// stream stays in sync, so only this event is lost.
+pub fn is_unreadable_event(err: &swayipc::Error) -> bool {
+    matches!(
+        err,
+        swayipc::Error::SerdeJson(_) | swayipc::Error::UnimplementedEvent(..)
+    )
+}
@@ -0,0 +1,27 @@
+use std::{process::Command, thread};
+
+use crate::compositor;
+
+/// Runs `command` through `sh -c` with `vars` in its environment. It is
+/// waited for on a thread of its own, so a slow hook never holds up
+/// switching.
+pub fn run(command: &str, vars: &[(&str, String)]) {
+    debug!("Running hook {command:?} with {vars:?}");
+    let mut child = Command::new("sh");
+    child
+        .arg("-c")
+        .arg(command)
+        .envs(vars.iter().map(|(key, value)| (key, value)));
+    compositor::pass_socket(&mut child);
+    let spawned = child.spawn();
+    match spawned {
+        Ok(mut child) => {
+            thread::spawn(move || match child.wait() {
+                Ok(status) if !status.success() => warn!("Hook exited with {status}"),
+                Err(err) => warn!("Hook failed: {err}"),
+                Ok(_) => {}
+            });
+        }
+        Err(err) => warn!("Cannot run hook {command:?}: {err}"),
+    }
+}
@@ -0,0 +1,207 @@
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    thread,
+    time::Duration,
+};
+
+use serde_json::{json, Value};
+
+use crate::client;
+
+/// Largest request body read, plenty for `POST /set`.
+const MAX_BODY: usize = 64 * 1024;
+
+const ROUTES: &[&str] = &[
+    "/", "/state", "/focused", "/set", "/pause", "/resume", "/pin", "/unpin", "/forget",
+];
+
+/// The page at `/`, which shows `/state` and has buttons for the calls.
+const DASHBOARD: &str = include_str!("dashboard.html");
+
+/// Serves a small JSON API on `addr` for scripts that speak HTTP more
+/// easily than a Unix socket: `GET /state`, `GET /focused`, `POST /set`
+/// with `{"window": ..., "layout": ...}`, `POST /pause`, `POST /resume`,
+/// and `POST /pin`, `/unpin` and `/forget` with `{"window": ...}`. `GET /`
+/// is a dashboard of the same. Requests become commands of the control
+/// socket. Anything local may use it, so only loopback addresses are
+/// served, and only to requests naming it as their host and origin, see
+/// `check`. Returns the address served.
+pub fn serve(addr: &str) -> io::Result<SocketAddr> {
+    let listener = TcpListener::bind(addr)?;
+    let local = listener.local_addr()?;
+    if !local.ip().is_loopback() {
+        return Err(io::Error::other(
+            "only loopback addresses are served, the API has no authentication",
+        ));
+    }
+    info!("Serving the HTTP API on http://{local}/");
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            // Requests are answered one at a time, so a client that sends
+            // nothing must not hold up the next one.
+            let _ = stream.set_read_timeout(Some(Duration::from_secs(2)));
+            let _ = stream.set_write_timeout(Some(Duration::from_secs(2)));
+            if let Err(err) = answer(&stream, local.port()) {
+                debug!("Cannot answer an HTTP request: {err}");
+            }
+        }
+    });
+    Ok(local)
+}
+
+struct Request {
+    method: String,
+    path: String,
+    host: Option<String>,
+    origin: Option<String>,
+    content_type: Option<String>,
+    body: Vec<u8>,
+}
+
+fn read_request(stream: &TcpStream) -> io::Result<Request> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let mut words = line.split_whitespace();
+    let method = words.next().unwrap_or_default().to_string();
+    let target = words.next().unwrap_or_default();
+    let path = target.split('?').next().unwrap_or_default().to_string();
+    let (mut len, mut host, mut origin, mut content_type) = (0, None, None, None);
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+            break;
+        }
+        let Some((name, value)) = header.split_once(':') else {
+            continue;
+        };
+        let value = value.trim().to_string();
+        match name.to_ascii_lowercase().as_str() {
+            "content-length" => len = value.parse().unwrap_or_default(),
+            "host" => host = Some(value),
+            "origin" => origin = Some(value),
+            "content-type" => content_type = Some(value),
+            _ => {}
+        }
+    }
+    let mut body = vec![0; len.min(MAX_BODY)];
+    reader.read_exact(&mut body)?;
+    Ok(Request {
+        method,
+        path,
+        host,
+        origin,
+        content_type,
+        body,
+    })
+}
+
+/// Turns down what a web page could send: a page cannot name this server
+/// as its host but through DNS rebinding, nor as its origin, and cannot
+/// POST JSON without a preflight, which is never answered.
+fn check(request: &Request, port: u16) -> Result<(), (u16, String)> {
+    let hosts = [
+        format!("127.0.0.1:{port}"),
+        format!("[::1]:{port}"),
+        format!("localhost:{port}"),
+    ];
+    match &request.host {
+        Some(host) if hosts.contains(&host.to_lowercase()) => {}
+        Some(host) => return Err((403, format!("host {host} is not served"))),
+        None => return Err((403, "the Host header is missing".into())),
+    }
+    if let Some(origin) = &request.origin {
+        let own = hosts.iter().any(|host| *origin == format!("http://{host}"));
+        if !own {
+            return Err((403, format!("origin {origin} is not allowed")));
+        }
+    }
+    let json = request.content_type.as_deref().is_some_and(|content_type| {
+        let mime = content_type.split(';').next().unwrap_or_default();
+        mime.trim().eq_ignore_ascii_case("application/json")
+    });
+    if request.method == "POST" && !json {
+        return Err((415, "POST needs Content-Type: application/json".into()));
+    }
+    Ok(())
+}
+
+/// The control command a request stands for, or the status and message to
+/// turn it down with. Windows and layouts are passed as JSON fields, as
+/// keys and layout names may contain spaces.
+fn route(request: &Request) -> Result<String, (u16, String)> {
+    // No body at all names the focused window.
+    let body = || -> Result<Value, (u16, String)> {
+        if request.body.is_empty() {
+            return Ok(Value::Null);
+        }
+        serde_json::from_slice(&request.body).map_err(|err| (400, format!("invalid JSON: {err}")))
+    };
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/state" | "/focused") => Ok("status".into()),
+        ("POST", "/pause") => Ok("pause".into()),
+        ("POST", "/resume") => Ok("resume".into()),
+        ("POST", "/set") => {
+            let body = body()?;
+            let layout = body["layout"]
+                .as_str()
+                .ok_or((400, "layout is missing".to_string()))?;
+            let command = json!({ "command": "set", "window": body["window"], "layout": layout });
+            Ok(command.to_string())
+        }
+        ("POST", path @ ("/pin" | "/unpin" | "/forget")) => {
+            let body = body()?;
+            let command = json!({ "command": &path[1..], "window": body["window"] });
+            Ok(command.to_string())
+        }
+        (method, path) if ROUTES.contains(&path) => {
+            Err((405, format!("{method} is not allowed here")))
+        }
+        (_, path) => Err((404, format!("no {path}"))),
+    }
+}
+
+fn answer(mut stream: &TcpStream, port: u16) -> io::Result<()> {
+    let request = read_request(stream)?;
+    debug!("HTTP request: {} {}", request.method, request.path);
+    let checked = check(&request, port);
+    if checked.is_ok() && (request.method.as_str(), request.path.as_str()) == ("GET", "/") {
+        return write!(
+            stream,
+            "HTTP/1.1 200 OK\r\nContent-Type: text/html; charset=utf-8\r\n\
+             Content-Length: {}\r\nConnection: close\r\n\r\n{DASHBOARD}",
+            DASHBOARD.len()
+        );
+    }
+    let (status, body) = match checked.and_then(|_| route(&request)) {
+        Err((status, message)) => (status, json!({ "error": message })),
+        Ok(command) => match client::request(&command) {
+            Ok(status) if request.path == "/focused" => (
+                200,
+                json!({
+                    "focused": status["focused"],
+                    "layout": status["layout"],
+                    "pinned": status["pinned"],
+                }),
+            ),
+            Ok(response) => (200, response),
+            Err(err) => (400, json!({ "error": err.to_string() })),
+        },
+    };
+    let reason = match status {
+        200 => "OK",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        415 => "Unsupported Media Type",
+        _ => "Method Not Allowed",
+    };
+    let body = body.to_string();
+    write!(
+        stream,
+        "HTTP/1.1 {status} {reason}\r\nContent-Type: application/json\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )
+}
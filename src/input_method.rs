@@ -0,0 +1,63 @@
+use std::process::Command;
+
+/// An input-method framework whose active engine is remembered per window
+/// with --input-method. Both are driven through their own command-line
+/// clients, which talk to the framework over D-Bus.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Framework {
+    Fcitx5,
+    Ibus,
+}
+
+impl Framework {
+    pub fn parse(name: &str) -> Option<Framework> {
+        match name {
+            "fcitx5" => Some(Framework::Fcitx5),
+            "ibus" => Some(Framework::Ibus),
+            _ => None,
+        }
+    }
+
+    fn command(self) -> Command {
+        match self {
+            Framework::Fcitx5 => Command::new("fcitx5-remote"),
+            Framework::Ibus => Command::new("ibus"),
+        }
+    }
+
+    /// The active engine, e.g. `mozc` or `keyboard-us`; `None` when the
+    /// framework is not running.
+    pub fn current(self) -> Option<String> {
+        let mut command = self.command();
+        match self {
+            Framework::Fcitx5 => command.arg("-n"),
+            Framework::Ibus => command.arg("engine"),
+        };
+        let output = match command.output() {
+            Ok(output) => output,
+            Err(err) => {
+                warn!("Cannot query the {self:?} engine: {err}");
+                return None;
+            }
+        };
+        if !output.status.success() {
+            debug!("{self:?} has no active engine: {}", output.status);
+            return None;
+        }
+        let engine = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        Some(engine).filter(|engine| !engine.is_empty())
+    }
+
+    pub fn activate(self, engine: &str) {
+        let mut command = self.command();
+        match self {
+            Framework::Fcitx5 => command.arg("-s"),
+            Framework::Ibus => command.arg("engine"),
+        };
+        match command.arg(engine).status() {
+            Ok(status) if status.success() => {}
+            Ok(status) => warn!("Cannot switch {self:?} to {engine}: {status}"),
+            Err(err) => warn!("Cannot switch {self:?} to {engine}: {err}"),
+        }
+    }
+}
@@ -0,0 +1,90 @@
+use std::{
+    fs::{self, File, TryLockError},
+    io::{self, Read, Seek, Write},
+    path::Path,
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Held while the daemon runs, so a second copy of it for the same session
+/// (one from the sway config, one from a systemd unit) cannot fight over
+/// layouts. The lock file holds the pid of the holder.
+#[derive(Debug)]
+pub struct Lock {
+    file: File,
+}
+
+impl Lock {
+    /// Writes the current pid, e.g. again after daemonizing.
+    pub fn write_pid(&mut self) -> io::Result<()> {
+        self.file.set_len(0)?;
+        self.file.rewind()?;
+        writeln!(self.file, "{}", std::process::id())
+    }
+}
+
+fn holder(file: &mut File) -> Option<libc::pid_t> {
+    let mut pid = String::new();
+    file.rewind().ok()?;
+    file.read_to_string(&mut pid).ok()?;
+    pid.trim().parse().ok()
+}
+
+/// Takes the lock at `path`. An instance holding it is an error, unless
+/// `replace` is given: then it is sent SIGTERM and given a few seconds to
+/// exit.
+pub fn acquire(path: &Path, replace: bool) -> io::Result<Lock> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut file = File::options()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)?;
+    match file.try_lock() {
+        Ok(()) => {}
+        Err(TryLockError::WouldBlock) => {
+            let pid = holder(&mut file);
+            let running = match pid {
+                Some(pid) => format!("another instance (pid {pid}) is running"),
+                None => "another instance is running".to_string(),
+            };
+            if !replace {
+                return Err(io::Error::new(
+                    io::ErrorKind::WouldBlock,
+                    format!("{running}, use --replace to take over"),
+                ));
+            }
+            let Some(pid) = pid else {
+                return Err(io::Error::other(format!(
+                    "{running}, but its pid is unknown"
+                )));
+            };
+            info!("Replacing the instance with pid {pid}");
+            if unsafe { libc::kill(pid, libc::SIGTERM) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let deadline = Instant::now() + Duration::from_secs(5);
+            loop {
+                thread::sleep(Duration::from_millis(50));
+                match file.try_lock() {
+                    Ok(()) => break,
+                    Err(TryLockError::WouldBlock) if Instant::now() < deadline => {}
+                    Err(TryLockError::WouldBlock) => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::TimedOut,
+                            format!("instance with pid {pid} did not exit"),
+                        ))
+                    }
+                    Err(TryLockError::Error(err)) => return Err(err),
+                }
+            }
+        }
+        Err(TryLockError::Error(err)) => return Err(err),
+    }
+    let mut lock = Lock { file };
+    lock.write_pid()?;
+    Ok(lock)
+}
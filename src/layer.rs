@@ -0,0 +1,64 @@
+use std::{thread, time::Duration};
+
+use swayipc::Connection;
+
+use crate::compositor;
+
+/// How often the seats are asked what has their keyboard.
+const INTERVAL: Duration = Duration::from_millis(200);
+
+/// Sway sends no event when a layer-shell surface such as a launcher or a
+/// panel's menu takes the keyboard: the seat then just reports focusing no
+/// node, as it does for an unmanaged Xwayland popup. Asks for the seats
+/// every INTERVAL and sends `payload(seat, true)` as a tick once a seat
+/// focuses nothing, `payload(seat, false)` once it focuses a node again.
+pub fn watch(payload: impl Fn(&str, bool) -> String + Send + 'static) {
+    thread::spawn(move || {
+        let mut sway: Option<Connection> = None;
+        // None until the first answer, which only tells where things stand.
+        let mut unfocused: Option<Vec<String>> = None;
+        loop {
+            thread::sleep(INTERVAL);
+            let conn = match sway.take() {
+                Some(conn) => conn,
+                None => match compositor::connect() {
+                    Ok(conn) => conn,
+                    Err(err) => {
+                        debug!("Cannot watch the seats' focus: {err}");
+                        continue;
+                    }
+                },
+            };
+            let conn = sway.insert(conn);
+            let seats = match conn.get_seats() {
+                Ok(seats) => seats,
+                Err(err) => {
+                    debug!("Cannot watch the seats' focus: {err}");
+                    sway = None;
+                    continue;
+                }
+            };
+            let now: Vec<String> = seats
+                .into_iter()
+                .filter(|seat| seat.focus == 0)
+                .map(|seat| seat.name)
+                .collect();
+            let Some(before) = unfocused.replace(now.clone()) else {
+                continue;
+            };
+            let left = before.iter().filter(|seat| !now.contains(seat));
+            let entered = now.iter().filter(|seat| !before.contains(seat));
+            let ticks: Vec<String> = entered
+                .map(|seat| payload(seat, true))
+                .chain(left.map(|seat| payload(seat, false)))
+                .collect();
+            for tick in ticks {
+                if let Err(err) = conn.send_tick(&tick) {
+                    debug!("Cannot send the layer focus tick: {err}");
+                    sway = None;
+                    break;
+                }
+            }
+        }
+    });
+}
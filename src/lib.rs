@@ -0,0 +1,5424 @@
+//! Remembers the keyboard layout of each window (or workspace) and restores
+//! it on focus. The binary is a thin wrapper around [`run`].
+
+use std::{
+    env,
+    fs::{self, File},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+extern crate pretty_env_logger;
+#[macro_use]
+extern crate log;
+
+#[macro_use]
+mod span;
+mod chord;
+mod client;
+mod completions;
+pub mod compositor;
+mod config_file;
+mod control;
+mod crash;
+mod daemon;
+mod dbus;
+mod debounce;
+mod error;
+mod fxhash;
+mod glob;
+mod hook;
+#[cfg(feature = "http")]
+mod http;
+mod input_method;
+mod instance;
+mod layer;
+mod migrate;
+mod mqtt;
+mod nag;
+mod native_host;
+mod notify;
+mod paths;
+mod persist;
+mod sandbox;
+mod setup;
+mod signals;
+mod simulate;
+mod ssh;
+mod stats;
+mod systemd;
+mod toml;
+mod waybar;
+mod xkb;
+
+pub use compositor::Compositor;
+use fxhash::FxHashMap;
+use getopts::{Matches, Options, ParsingStyle};
+use serde_json::{json, Value};
+
+use swayipc::{
+    Error, Event, EventType, Input, InputChange, Node, NodeType, ScratchpadState, WindowChange,
+    WindowEvent, Workspace, WorkspaceChange,
+};
+
+#[derive(Debug, Default)]
+pub struct Config {
+    default_lang: Option<String>,
+    /// `input_glob=layout` entries of --default-lang, tried before the
+    /// plain default for each keyboard.
+    input_defaults: Vec<(String, String)>,
+    tabbed: Vec<String>,
+    /// `app=regex` entries of --tabbed-key; the first capture group of a
+    /// matching title is used instead of the whole title.
+    tabbed_keys: Vec<(String, regex::Regex)>,
+    app_aliases: FxHashMap<String, String>,
+    compat: Option<Compat>,
+    mode: Mode,
+    /// How window keys are formed, by default and per app.
+    key_by: KeyBy,
+    app_key_by: Vec<(String, KeyBy)>,
+    continuity: bool,
+    /// New windows start with the layout last used on their workspace.
+    workspace_fallback: bool,
+    mqtt: Option<String>,
+    mqtt_topic: String,
+    /// Serve org.sway.XkbSwitcher on the session bus.
+    dbus: bool,
+    /// Address to serve Prometheus metrics on.
+    metrics: Option<String>,
+    secure_apps: Vec<String>,
+    /// Launchers, popups and on-screen keyboards whose focus is not tracked.
+    ignored_apps: Vec<String>,
+    /// Floating dialogs are not tracked either.
+    ignore_floating: bool,
+    /// Globs of sway marks that group windows under one remembered layout.
+    group_marks: Vec<String>,
+    sandbox: bool,
+    title_tag: bool,
+    device_defaults: Vec<(String, String)>,
+    ssh_layouts: Vec<(String, String)>,
+    forced_layouts: Vec<(String, String)>,
+    /// Apps whose windows get a keymap of their own, by .xkb file path.
+    xkb_files: Vec<(String, String)>,
+    /// Apps restricted to some layouts, the first being the fallback.
+    allowed_layouts: Vec<(String, Vec<String>)>,
+    app_defaults: Vec<(String, String)>,
+    group_inputs: bool,
+    /// One layout per window for all keyboards, remembered by name.
+    sync_inputs: bool,
+    revert_after: Option<Duration>,
+    /// How long focus has to stay on a window before it counts.
+    debounce: Option<Duration>,
+    /// Most windows remembered at once, 0 for no limit.
+    max_entries: usize,
+    workspaces: Vec<String>,
+    outputs: Vec<String>,
+    inputs: Vec<String>,
+    ignore_inputs: Vec<String>,
+    verify: bool,
+    /// Log the switch commands instead of sending them.
+    dry_run: bool,
+    /// Where remembered layouts are kept across restarts; none with
+    /// --no-persist.
+    state_file: Option<PathBuf>,
+    persist_user_only: bool,
+    switch_chord: Option<String>,
+    reset_on_exit: bool,
+    notify: Option<notify::Notifier>,
+    quiet_hours: Option<notify::QuietHours>,
+    on_switch: Option<String>,
+    input_method: Option<input_method::Framework>,
+    /// Apps whose input-method engine is remembered, all when empty.
+    im_apps: Vec<String>,
+    layer_focus: Option<LayerFocus>,
+}
+
+impl Config {
+    /// Builds a config from long options, e.g. `--default-lang=us`, ignoring
+    /// the config file.
+    pub fn from_args(args: &[String]) -> Result<Config, String> {
+        let cli = options().parse(args).map_err(|err| err.to_string())?;
+        build_config(&Settings {
+            cli,
+            env: None,
+            file: None,
+            skipped: vec![],
+        })
+    }
+}
+
+/// Default of --max-entries: plenty for real windows, but tabbed browsers
+/// add an entry per title they ever had.
+const MAX_ENTRIES: usize = 1000;
+
+const SECURE_APPS: &[&str] = &[
+    "pinentry*",
+    "gcr-prompter",
+    "polkit-gnome-authentication-agent-1",
+    "polkit-mate-authentication-agent-1",
+    "lxqt-policykit-agent",
+    "org.kde.polkit-kde-authentication-agent-1",
+    "ssh-askpass",
+    "org.kde.ksshaskpass",
+    "lxqt-openssh-askpass",
+];
+
+/// A remembered layout. The name is what gets restored, so reordering
+/// xkb_layout in the sway config does not put windows on the wrong layout;
+/// the index is only used when no name is known.
+#[derive(Debug, Clone, PartialEq)]
+struct StoredLayout {
+    name: String,
+    index: i32,
+    /// Chosen by the user at some point rather than a default the window
+    /// merely kept, which --persist-user-only tells apart.
+    user: bool,
+}
+
+impl StoredLayout {
+    fn of(input: &Input, index: i32) -> StoredLayout {
+        StoredLayout {
+            name: input
+                .xkb_layout_names
+                .get(index as usize)
+                .cloned()
+                .unwrap_or_default(),
+            index,
+            user: false,
+        }
+    }
+
+    /// Index of this layout among a keyboard's current layouts, or `None`
+    /// when it is no longer configured there.
+    fn resolve(&self, names: &[String]) -> Option<i32> {
+        if self.name.is_empty() {
+            return Some(self.index).filter(|index| (*index as usize) < names.len());
+        }
+        names
+            .iter()
+            .position(|name| *name == self.name)
+            .map(|index| index as i32)
+    }
+}
+
+/// The parts of a sway view the switching logic looks at.
+#[derive(Debug, Clone, Default)]
+struct Window {
+    id: i64,
+    app_id: Option<String>,
+    class: Option<String>,
+    title: Option<String>,
+    pid: Option<i32>,
+    marks: Vec<String>,
+    /// Floating but not a scratchpad window, i.e. most likely a dialog.
+    floating: bool,
+}
+
+impl From<&Node> for Window {
+    fn from(node: &Node) -> Window {
+        Window {
+            id: node.id,
+            app_id: node.app_id.clone(),
+            class: node
+                .window_properties
+                .as_ref()
+                .and_then(|props| props.class.clone()),
+            title: node.name.clone(),
+            pid: node.pid,
+            marks: node.marks.clone(),
+            floating: node.node_type == NodeType::FloatingCon && !in_scratchpad(node),
+        }
+    }
+}
+
+/// Active tab of a browser window, as the extension reports it through the
+/// native messaging host.
+#[derive(Debug, Clone)]
+struct Tab {
+    /// The browser's own ID for the window, not sway's.
+    window: i64,
+    title: String,
+    site: String,
+}
+
+#[derive(Debug)]
+pub struct LayoutState {
+    comm_conn: Box<dyn Compositor>,
+    config: Config,
+    prev_id: Option<String>,
+    state: FxHashMap<String, persist::Layouts>,
+    containers: FxHashMap<i64, (Option<String>, String)>,
+    mqtt: Option<mqtt::Publisher>,
+    dbus: Option<dbus::Service>,
+    tagged: Option<i64>,
+    device_default: Option<String>,
+    /// When each remembered window was last unfocused, for --revert-after
+    /// and evicting the least recently used beyond --max-entries.
+    unfocused_at: FxHashMap<String, Instant>,
+    /// The key gaining focus while the one losing it is saved, so evicting
+    /// does not drop the layout about to be restored.
+    focusing: Option<String>,
+    /// Keyboards as last reported by sway, in sway's order. Input events keep
+    /// it current so focus changes need no get_inputs round trip.
+    inputs: Vec<Input>,
+    /// With several seats: the seat of the last focus change, the
+    /// identifiers of its keyboards and the focused key of the other seats.
+    seat: Option<String>,
+    seat_devices: Vec<String>,
+    seat_prev: FxHashMap<String, Option<String>>,
+    multi_seat: bool,
+    suspended_for: Duration,
+    paused: bool,
+    /// Between the `lock` and `unlock` ticks, e.g. sent by swayidle around
+    /// swaylock. Kept apart from `paused` so unlocking does not resume a
+    /// daemon paused by hand.
+    locked: bool,
+    /// The --xkb-file keymap loaded for the focused window.
+    xkb_file: Option<String>,
+    /// Between `freeze` and `unfreeze`: the layout stays as it is in every
+    /// window, nothing is restored or saved.
+    freeze: bool,
+    /// Last STATUS= sent to systemd.
+    notified_status: String,
+    /// App (or workspace) focused last, named in --notify popups.
+    trigger: String,
+    /// app_id (or class) of the window focused last, for --on-switch.
+    focused_app: Option<String>,
+    /// Layouts frozen with the `pin` tick, by container (`con:<id>`, so a
+    /// tabbed app keeps it across titles) or by workspace key.
+    pinned: FxHashMap<String, persist::Layouts>,
+    /// Pin key of the window focused last.
+    focused_pin: Option<String>,
+    /// Input-method engine of each window, with --input-method.
+    engines: FxHashMap<String, String>,
+    /// Key of the focused window when its engine is remembered.
+    im_key: Option<String>,
+    /// With --workspace-fallback: the layout last used on each workspace and
+    /// the workspace of the window focused last.
+    workspace_layouts: FxHashMap<String, persist::Layouts>,
+    focused_workspace: Option<String>,
+    /// Workspace names by node id, as rename events only carry the new one.
+    workspace_names: FxHashMap<i64, String>,
+    persist_path: Option<PathBuf>,
+    /// Layouts read from the state file that no window has claimed yet.
+    saved: persist::Saved,
+    /// app and title of each remembered window, to write the state file.
+    identities: FxHashMap<String, (String, String)>,
+    dirty: bool,
+    persisted_at: Instant,
+    control: Option<std::sync::mpsc::Receiver<control::Request>>,
+    /// `watch` clients, told about each decision.
+    watchers: control::Watchers,
+    /// Layout index last asked of each keyboard, to tell the user's
+    /// switches from ours.
+    requested: FxHashMap<String, i32>,
+    /// Whether the user switched the layout since the focus changed.
+    user_switched: bool,
+    stats: stats::Stats,
+    /// With --debounce: the focus change waiting for focus to settle, and
+    /// when it may be handled.
+    pending_focus: Option<(Event, Instant)>,
+    /// New window handled before its focus event.
+    focused_new: Option<i64>,
+    /// Keys of windows hidden in the scratchpad, kept however long hidden.
+    hidden: Vec<String>,
+    /// Key whose layout was saved when an ignored dialog took focus; what
+    /// is typed in the dialog must not overwrite it.
+    held: Option<String>,
+    debounce_timer: Option<debounce::Timer>,
+    /// Active tab of each window of a browser process, most recently
+    /// reported last; sites key its windows instead of titles.
+    tabs: FxHashMap<i32, Vec<Tab>>,
+    /// The window focused last, for a tab switch in it to re-key it.
+    focused_window: Option<Window>,
+    /// Whether the seats are watched for layer-shell focus.
+    watching_layers: bool,
+}
+
+/// What a remembered layout belongs to.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+enum Mode {
+    /// Every window (or tab of a tabbed app) has its own layout.
+    #[default]
+    Window,
+    /// All windows on a workspace share one layout.
+    Workspace,
+}
+
+/// What happens while a layer-shell surface has the keyboard.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum LayerFocus {
+    /// The window gets its layout back after, whatever was typed there.
+    Ignore,
+    /// The surface gets the default layout, the window its own after.
+    Default,
+}
+
+/// What a window's layout is remembered under in --mode window.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+enum KeyBy {
+    /// The container (plus the title for tabbed apps).
+    #[default]
+    Window,
+    /// The app_id or class, so new windows inherit the app's last layout.
+    App,
+    /// The app and the title, wherever the window is.
+    AppTitle,
+}
+
+impl KeyBy {
+    fn parse(name: &str) -> Option<KeyBy> {
+        match name {
+            "window" => Some(KeyBy::Window),
+            "app" => Some(KeyBy::App),
+            "app+title" => Some(KeyBy::AppTitle),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Compat {
+    /// swaykbdd keeps a single layout index per window and switches all
+    /// keyboards at once; its default layout is given as an index.
+    Swaykbdd,
+}
+
+const ALL_KEYBOARDS: &str = "type:keyboard";
+const TICK_PREFIX: &str = "xkb-switcher:";
+
+/// A tick for this instance only, as sway sends ticks to every subscriber.
+/// Used for what the daemon sends itself, e.g. signals it forwards.
+fn own_tick(command: &str) -> String {
+    format!("{TICK_PREFIX}{command}@{}", paths::instance())
+}
+
+/// The command of a tick for this instance: `<command>` is for all,
+/// `<command>@<instance>` only for the one so named.
+fn tick_command(payload: &str) -> Option<&str> {
+    let command = payload.strip_prefix(TICK_PREFIX)?.trim();
+    match command.rsplit_once('@') {
+        Some((command, instance)) => (instance == paths::instance()).then_some(command),
+        None => Some(command),
+    }
+}
+
+/// Serves the D-Bus interface, whose methods reach the event loop as ticks.
+fn dbus_service() -> dbus::Service {
+    let name = dbus::bus_name(paths::instance());
+    dbus::Service::start(dbus::session_address(), name, |command| {
+        let sent = compositor::connect().and_then(|mut conn| conn.send_tick(own_tick(command)));
+        if let Err(err) = sent {
+            warn!("Cannot pass {command} from D-Bus to the event loop: {err}");
+        }
+    })
+}
+
+/// What can follow TICK_PREFIX in a tick. `reload` and `quit` are handled by
+/// the event loop, the rest by `on_tick`.
+const TICK_COMMANDS: &[&str] = &[
+    "pause",
+    "resume",
+    "toggle",
+    "forget",
+    "forget-all",
+    "pin",
+    "unpin",
+    "toggle-pin",
+    "lock",
+    "unlock",
+    "freeze",
+    "unfreeze",
+    "dump",
+    "set-current <layout>",
+    "reload",
+    "quit",
+];
+
+/// Commands of the control socket, with their arguments.
+const CONTROL_COMMANDS: &[&str] = &[
+    "status",
+    "pause",
+    "resume",
+    "freeze",
+    "unfreeze",
+    "forget [<window>]",
+    "pin [<window>]",
+    "unpin [<window>]",
+    "set <window> <layout>",
+    "set-current <layout>",
+    "tab <browser_pid> <report>",
+    "watch",
+];
+
+impl LayoutState {
+    pub fn new(comm_conn: Box<dyn Compositor>, config: Config) -> LayoutState {
+        let mqtt = config
+            .mqtt
+            .clone()
+            .map(|addr| mqtt::Publisher::new(addr, config.mqtt_topic.clone()));
+        let dbus = config.dbus.then(dbus_service);
+        let stats = stats::Stats::default();
+        let mut state = LayoutState {
+            comm_conn: Box::new(stats::Counted::new(comm_conn, stats.clone())),
+            config,
+            state: FxHashMap::default(),
+            prev_id: None,
+            containers: FxHashMap::default(),
+            mqtt,
+            dbus,
+            tagged: None,
+            device_default: None,
+            unfocused_at: FxHashMap::default(),
+            focusing: None,
+            inputs: vec![],
+            seat: None,
+            seat_devices: vec![],
+            seat_prev: FxHashMap::default(),
+            multi_seat: false,
+            suspended_for: suspended_for(),
+            paused: false,
+            locked: false,
+            xkb_file: None,
+            freeze: false,
+            notified_status: String::new(),
+            trigger: String::new(),
+            focused_app: None,
+            pinned: FxHashMap::default(),
+            focused_pin: None,
+            engines: FxHashMap::default(),
+            im_key: None,
+            workspace_layouts: FxHashMap::default(),
+            focused_workspace: None,
+            workspace_names: FxHashMap::default(),
+            persist_path: None,
+            saved: persist::Saved::default(),
+            identities: FxHashMap::default(),
+            dirty: false,
+            persisted_at: Instant::now(),
+            control: None,
+            watchers: control::Watchers::default(),
+            requested: FxHashMap::default(),
+            user_switched: false,
+            stats,
+            pending_focus: None,
+            focused_new: None,
+            hidden: vec![],
+            held: None,
+            debounce_timer: None,
+            tabs: FxHashMap::default(),
+            focused_window: None,
+            watching_layers: false,
+        };
+        if let Some(path) = state.config.state_file.clone() {
+            let loaded = match persist::load(&path) {
+                Ok(None) if path == paths::state_file() => {
+                    persist::load(&paths::shared_state_file())
+                }
+                loaded => loaded,
+            };
+            match loaded {
+                Ok(saved) => {
+                    state.saved = saved.unwrap_or_default();
+                    state.persist_path = Some(path);
+                }
+                Err(err) => warn!(
+                    "Not persisting layouts, cannot read {}: {err}",
+                    path.display()
+                ),
+            }
+        }
+        state.refresh_inputs();
+        state.refresh_device_default();
+        state
+    }
+
+    /// Reacts to one compositor event. Reload ticks are left to the caller,
+    /// which knows where the config comes from.
+    pub fn handle_event(&mut self, event: Event) {
+        let Some(delay) = self.config.debounce else {
+            return self.process_event(event);
+        };
+        match &event {
+            Event::Tick(tick) if tick_command(&tick.payload) == Some("debounce") => {
+                // The timer may fire for an earlier deadline.
+                if let Some((pending, _)) = self
+                    .pending_focus
+                    .take_if(|(_, deadline)| *deadline <= Instant::now())
+                {
+                    self.process_event(pending);
+                }
+                return;
+            }
+            Event::Window(w) if self.is_focus_change(w) => {
+                let deadline = Instant::now() + delay;
+                let replaced = self.pending_focus.replace((event, deadline));
+                if let Some((Event::Window(replaced), _)) = replaced {
+                    debug!("Focus left {} before settling", replaced.container.id);
+                }
+                self.debounce_timer
+                    .get_or_insert_with(|| debounce::Timer::start(own_tick("debounce")))
+                    .schedule(deadline);
+                return;
+            }
+            Event::Window(w) if matches!(w.change, WindowChange::Close) => {
+                self.pending_focus.take_if(|(pending, _)| {
+                    matches!(pending, Event::Window(p) if p.container.id == w.container.id)
+                });
+            }
+            // Layout switches and commands apply to the window focused by
+            // then.
+            Event::Input(_) | Event::Tick(_) => {
+                if let Some((pending, _)) = self.pending_focus.take() {
+                    self.process_event(pending);
+                }
+            }
+            _ => {}
+        }
+        self.process_event(event)
+    }
+
+    fn has_seat_focus(&mut self, con_id: i64) -> bool {
+        self.comm_conn
+            .get_seats()
+            .is_ok_and(|seats| seats.iter().any(|seat| seat.focus == con_id))
+    }
+
+    fn is_focus_change(&self, w: &WindowEvent) -> bool {
+        match w.change {
+            WindowChange::Focus => true,
+            WindowChange::Title => self.tracks_title(&w.container),
+            _ => false,
+        }
+    }
+
+    fn process_event(&mut self, event: Event) {
+        self.check_resume();
+        self.persist(false);
+        if let Event::Input(input) = &event {
+            if matches!(input.change, InputChange::Removed) {
+                self.on_input_removed(&input.input);
+            } else {
+                self.on_input(&input.input);
+            }
+            match input.change {
+                InputChange::XkbLayout => {
+                    self.on_layout_change(&input.input);
+                    self.update_title_tag(None);
+                    let app_id = self.focused_window.as_ref().and_then(|w| w.app_id.clone());
+                    self.publish_layout(app_id.as_deref());
+                }
+                InputChange::Added | InputChange::Removed => {
+                    self.refresh_seats();
+                    self.refresh_device_default();
+                    if matches!(input.change, InputChange::Added) {
+                        self.on_input_added(&input.input);
+                    }
+                }
+                _ => {}
+            }
+            return;
+        }
+        if let Event::Tick(tick) = &event {
+            if tick_command(&tick.payload) == Some("control") {
+                self.serve_control();
+            } else {
+                self.on_tick(&tick.payload);
+            }
+            return;
+        }
+        if let Event::Workspace(ws) = &event {
+            if let Some((id, name)) = ws
+                .current
+                .as_ref()
+                .and_then(|node| Some((node.id, node.name.clone()?)))
+            {
+                match self.workspace_names.insert(id, name.clone()) {
+                    Some(old) if ws.change == WorkspaceChange::Rename && old != name => {
+                        self.rename_workspace(&old, &name)
+                    }
+                    _ => {}
+                }
+            }
+            // Switching to an empty workspace focuses no window.
+            if let (WorkspaceChange::Focus, Some(name), Mode::Workspace) = (
+                ws.change,
+                ws.current.as_ref().and_then(|node| node.name.as_ref()),
+                self.config.mode,
+            ) {
+                let _span = span!("workspace", name = name);
+                if self.frozen() {
+                    return;
+                }
+                self.trigger = format!("workspace {name}");
+                self.focused_app = None;
+                if !self.is_managed() {
+                    self.on_unmanaged_focus();
+                    return;
+                }
+                self.on_focus(&Window::default(), &workspace_key(name));
+                self.publish_layout(None);
+            }
+            return;
+        }
+        if let Event::Window(w) = event {
+            let span = span!(
+                "window",
+                change = format!("{:?}", w.change),
+                id = w.container.id,
+                app_id = w.container.app_id.as_deref().unwrap_or_default()
+            );
+            info!("Got an event: {:?}", w);
+            crash::record_event(format!(
+                "{:?} id={} app_id={:?} name={:?}",
+                w.change, w.container.id, w.container.app_id, w.container.name
+            ));
+            match w.change {
+                // Only tabbed apps are keyed by title. Terminals retitle on
+                // every command, which must not cost a round of queries.
+                WindowChange::Title if !self.tracks_title(&w.container) => {}
+                WindowChange::Focus | WindowChange::Title if self.frozen() => {
+                    debug!("Paused, ignoring focus change");
+                }
+                WindowChange::Focus | WindowChange::Title => {
+                    if matches!(w.change, WindowChange::Focus)
+                        && self.focused_new.take() == Some(w.container.id)
+                    {
+                        debug!("Focus of a new window, handled already");
+                        return;
+                    }
+                    self.on_window_focus(w.container, &span);
+                }
+                // A new window gets its layout as soon as sway has focused
+                // it, before the focus event, so the first keystroke already
+                // goes out in the right layout.
+                WindowChange::New
+                    if is_leaf(&w.container)
+                        && !self.frozen()
+                        && self.has_seat_focus(w.container.id) =>
+                {
+                    self.focused_new = Some(w.container.id);
+                    self.on_window_focus(w.container, &span);
+                }
+                // Marking the focused window moves it into (or out of) a
+                // group, which then decides its layout.
+                WindowChange::Mark
+                    if w.container.focused
+                        && is_leaf(&w.container)
+                        && !self.frozen()
+                        && !self.config.group_marks.is_empty() =>
+                {
+                    self.refocus(&w.container, &span);
+                }
+                // Moving the focused window to another workspace or output
+                // can change its key (--mode workspace) and whether it is
+                // managed at all.
+                WindowChange::Move if is_hidden_scratchpad(&w.container) => {
+                    self.on_scratchpad_hide(&w.container);
+                }
+                WindowChange::Move
+                    if w.container.focused && is_leaf(&w.container) && !self.frozen() =>
+                {
+                    let window = Window::from(&w.container);
+                    if !self.is_ignored(&window) {
+                        self.refocus(&w.container, &span);
+                    }
+                }
+                WindowChange::Close if is_leaf(&w.container) => {
+                    let key = self.make_map_key(&Window::from(&w.container));
+                    span.record("key", &key);
+                    self.containers.remove(&w.container.id);
+                    if self.tagged == Some(w.container.id) {
+                        self.tagged = None;
+                    }
+                    self.pinned.remove(&format!("con:{}", w.container.id));
+                    // Other windows of a marked group or app keep its layout.
+                    if self.config.mode == Mode::Window && !is_shared_key(&key) {
+                        self.on_close(&key)
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Follows focus to a window: saves the layout of the one focused before
+    /// and gives this one its layout.
+    fn on_window_focus(&mut self, container: Node, span: &span::Span) {
+        let Some(container) = focused_leaf(container) else {
+            debug!("Ignoring non-leaf container without a focused view");
+            return;
+        };
+        let window = Window::from(&container);
+        // The window focused before keeps prev_id, so focus coming
+        // back to it is not a change.
+        if self.is_ignored(&window) {
+            debug!("Ignoring focus of {:?} (--ignore-apps)", window.app_id);
+            if window.floating {
+                self.hold_prev();
+            }
+            return;
+        }
+        self.focused_app = window.app_id.clone().or_else(|| window.class.clone());
+        self.trigger = match &self.focused_app {
+            Some(app) => app.clone(),
+            None => format!("window {}", window.id),
+        };
+        self.enter_seat(container.id);
+        if !self.is_managed() {
+            self.on_unmanaged_focus();
+            return;
+        }
+        if self.is_secure_prompt(&window) {
+            self.on_secure_focus();
+            return;
+        }
+        if let Some((host, lang)) = self.ssh_layout(&window) {
+            self.on_ssh_focus(&host, &lang);
+            return;
+        }
+        self.focused_window = Some(window.clone());
+        let key = self.focus_key(&window);
+        span.record("key", &key);
+        if self.config.mode == Mode::Window {
+            self.rekey_on_app_change(&window, &key);
+        }
+        self.on_focus(&window, &key);
+        self.publish_layout(container.app_id.as_deref());
+        self.update_title_tag(Some(container.id));
+    }
+
+    /// Re-evaluates the still focused window after it changed in a way that
+    /// may give it another key, as if it had just been focused.
+    fn refocus(&mut self, container: &Node, span: &span::Span) {
+        if !self.is_managed() {
+            self.on_unmanaged_focus();
+            return;
+        }
+        let window = Window::from(container);
+        self.focused_window = Some(window.clone());
+        let key = self.focus_key(&window);
+        if self.prev_id.as_deref() == Some(key.as_str()) {
+            if self.config.workspace_fallback && self.config.mode == Mode::Window {
+                self.focused_workspace = self.focused_workspace().map(|ws| ws.name);
+            }
+            return;
+        }
+        span.record("key", &key);
+        self.on_focus(&window, &key);
+        self.publish_layout(container.app_id.as_deref());
+    }
+
+    /// With --layer-focus, starts asking sway where the keyboard is, as no
+    /// event tells when a layer-shell surface takes it.
+    fn watch_layers(&mut self) {
+        if self.config.layer_focus.is_none() || self.watching_layers {
+            return;
+        }
+        self.watching_layers = true;
+        layer::watch(|seat, entered| match entered {
+            true => own_tick(&format!("layer-focus {seat}")),
+            false => own_tick(&format!("layer-unfocus {seat}")),
+        });
+    }
+
+    /// A layer-shell surface, e.g. a launcher, took the keyboard of `seat`
+    /// or gave it back. What is typed there is held from the window, as for
+    /// a dialog, and the window gets its own layout back after.
+    fn on_layer_focus(&mut self, seat: &str, entered: bool) {
+        let Some(layer_focus) = self.config.layer_focus else {
+            return;
+        };
+        if self.frozen() || (self.multi_seat && self.seat.as_deref() != Some(seat)) {
+            return;
+        }
+        let Some(key) = self.prev_id.clone() else {
+            return;
+        };
+        if entered {
+            debug!("A surface of no window has the keyboard of {seat}");
+            self.hold_prev();
+            if layer_focus == LayerFocus::Default {
+                self.trigger = "layer surface".to_string();
+                let targets = self.default_targets();
+                self.switch_layouts(&targets);
+            }
+        } else if self.held.take_if(|held| *held == key).is_some() {
+            debug!("{key} has the keyboard of {seat} again");
+            self.trigger = "layer surface closed".to_string();
+            let window = self.focused_window.clone().unwrap_or_default();
+            self._set_lang(&window, &key);
+        }
+    }
+
+    /// Saves the focused window's layout now, as what follows until focus
+    /// comes back belongs to a dialog.
+    fn hold_prev(&mut self) {
+        let Some(key) = self.prev_id.clone() else {
+            return;
+        };
+        if self.held.as_ref() != Some(&key) {
+            self.save_layout(key.clone());
+            self.held = Some(key);
+        }
+    }
+
+    /// Sending a scratchpad window away moves focus to a window that may
+    /// not report it, e.g. an empty workspace, so its layout is saved now
+    /// and prev_id let go.
+    fn on_scratchpad_hide(&mut self, container: &Node) {
+        let key = self.make_map_key(&Window::from(container));
+        if self.prev_id.as_deref() == Some(key.as_str()) {
+            self.save_layout(key.clone());
+            self.prev_id = None;
+        }
+        if !self.hidden.contains(&key) {
+            self.hidden.push(key);
+        }
+    }
+
+    fn save_layout(&mut self, key: String) {
+        let _span = span!("save", key = key);
+        if self.held.take_if(|held| *held == key).is_some() {
+            debug!("{key} was saved when a dialog took focus");
+            return;
+        }
+        // Switches made in a pinned window are not remembered.
+        if self
+            .focused_pin
+            .as_ref()
+            .is_some_and(|pin| self.pinned.contains_key(pin))
+        {
+            debug!("{key} is pinned, not saving");
+            return;
+        }
+        let mut layoutmap = self._get_lang();
+        // Once chosen by hand, a layout the window keeps stays the user's.
+        let user = self.user_switched
+            || self
+                .state
+                .get(&key)
+                .is_some_and(|old| old.values().any(|stored| stored.user));
+        for stored in layoutmap.values_mut() {
+            stored.user = user;
+        }
+        if let Some(workspace) = &self.focused_workspace {
+            self.workspace_layouts
+                .insert(workspace.clone(), layoutmap.clone());
+        }
+        self.watch(|| {
+            let mut names: Vec<&str> = layoutmap.values().map(|stored| &*stored.name).collect();
+            names.sort();
+            names.dedup();
+            format!("saved {} for {key}", names.join(", "))
+        });
+        self.unfocused_at.insert(key.clone(), Instant::now());
+        self.state.insert(key, layoutmap);
+        self.dirty = true;
+        self.evict();
+    }
+
+    /// A layout change none of our switches asked for is the user's.
+    fn on_layout_change(&mut self, input: &Input) {
+        if self.manages_input(input)
+            && input.xkb_active_layout_index != self.requested.get(&input.identifier).copied()
+        {
+            self.user_switched = true;
+        }
+    }
+
+    /// Tells `watch` clients about a decision, formatted only if any.
+    fn watch(&self, line: impl FnOnce() -> String) {
+        if !self.watchers.is_empty() {
+            self.watchers.send(&line());
+        }
+    }
+
+    /// Keeps week-long sessions from piling up entries: windows unfocused
+    /// for longer than --revert-after are dropped right away instead of on
+    /// their next focus, then the least recently used beyond --max-entries.
+    fn evict(&mut self) {
+        let focused = [self.prev_id.clone(), self.focusing.clone()];
+        let hidden = &self.hidden;
+        let evictable =
+            |key: &String| !focused.contains(&Some(key.clone())) && !hidden.contains(key);
+        let mut evicted: Vec<String> = vec![];
+        if let Some(revert_after) = self.config.revert_after {
+            evicted.extend(
+                self.unfocused_at
+                    .iter()
+                    .filter(|(key, at)| evictable(key) && at.elapsed() >= revert_after)
+                    .map(|(key, _)| key.clone()),
+            );
+        }
+        let max = self.config.max_entries;
+        let remaining = self.state.len().saturating_sub(evicted.len());
+        if max > 0 && remaining > max {
+            // Entries never unfocused, e.g. set over the control socket, go
+            // first.
+            let mut by_age: Vec<(Option<Instant>, &String)> = self
+                .state
+                .keys()
+                .filter(|key| evictable(key) && !evicted.contains(key))
+                .map(|key| (self.unfocused_at.get(key).copied(), key))
+                .collect();
+            by_age.sort();
+            let oldest: Vec<String> = by_age
+                .into_iter()
+                .take(remaining - max)
+                .map(|(_, key)| key.clone())
+                .collect();
+            evicted.extend(oldest);
+        }
+        if evicted.is_empty() {
+            return;
+        }
+        debug!("Forgetting {} stale entries: {evicted:?}", evicted.len());
+        for key in &evicted {
+            self.state.remove(key);
+            self.unfocused_at.remove(key);
+            self.identities.remove(key);
+            self.engines.remove(key);
+        }
+    }
+
+    /// With --revert-after, a window unfocused for longer than that forgets
+    /// its layout and gets the default again.
+    fn expire_memory(&mut self, key: &str) {
+        let Some(revert_after) = self.config.revert_after else {
+            return;
+        };
+        // Scratchpad windows are meant to be away for long.
+        if self.hidden.iter().any(|hidden| hidden == key) {
+            return;
+        }
+        if let Some(unfocused_at) = self.unfocused_at.remove(key) {
+            if unfocused_at.elapsed() >= revert_after && self.state.remove(key).is_some() {
+                info!(
+                    "{key} was unfocused for {:?}, reverting to default",
+                    unfocused_at.elapsed()
+                );
+            }
+        }
+    }
+
+    fn on_focus(&mut self, window: &Window, key: &str) {
+        if let Some(prev) = self.prev_id.clone().filter(|_| self.xkb_file.is_none()) {
+            self.focusing = Some(key.to_string());
+            self.save_layout(prev);
+            self.focusing = None;
+        }
+        self.user_switched = false;
+        let xkb_file = self.app_rule(&self.config.xkb_files, window);
+        if xkb_file != self.xkb_file {
+            self.load_keymap(xkb_file);
+        }
+        if let Some(path) = &self.xkb_file {
+            self.watch(|| format!("focus {}: keymap {path}", watch_name(window, key)));
+            // The keymap has layouts of its own, nothing to restore.
+            self.focused_pin = None;
+            self.prev_id = Some(key.to_string());
+            return;
+        }
+
+        self.expire_memory(key);
+        self.hidden.retain(|hidden| hidden != key);
+        self.restore_saved(window, key);
+        if self.config.workspace_fallback && self.config.mode == Mode::Window {
+            self.focused_workspace = self.focused_workspace().map(|ws| ws.name);
+        }
+        let pin = match window.id {
+            0 => key.to_string(),
+            id => format!("con:{id}"),
+        };
+        match self.pinned.get(&pin).cloned() {
+            Some(map) => {
+                debug!("{key} is pinned");
+                self.watch(|| format!("focus {}: pinned", watch_name(window, key)));
+                let targets = self.resolve_inputs(map);
+                self.switch_layouts(&targets);
+            }
+            None => self._set_lang(window, key),
+        }
+        self.focused_pin = Some(pin);
+        self.prev_id = Some(key.to_string());
+        self.switch_engine(window, key);
+    }
+
+    /// With --input-method, remembers the engine of the window losing focus
+    /// and restores the one the focused window had. Windows without a
+    /// remembered engine keep whatever is active.
+    fn switch_engine(&mut self, window: &Window, key: &str) {
+        let Some(framework) = self.config.input_method else {
+            return;
+        };
+        if let Some(prev) = self.im_key.take() {
+            if let Some(engine) = framework.current() {
+                debug!("{prev} used {framework:?} engine {engine}");
+                self.engines.insert(prev, engine);
+            }
+        }
+        let apps = &self.config.im_apps;
+        if !apps.is_empty() && !apps.iter().any(|app| self.matches_app(app, window)) {
+            return;
+        }
+        if let Some(engine) = self.engines.get(key) {
+            info!("Switching {framework:?} to {engine} for {key}");
+            framework.activate(engine);
+        }
+        self.im_key = Some(key.to_string());
+    }
+
+    /// Freezes the focused window's current layout until unpinned: focus
+    /// restores it over any rule, and switches made there are not kept.
+    fn pin(&mut self) {
+        let Some(pin) = self.focused_pin.clone().filter(|_| self.prev_id.is_some()) else {
+            warn!("No window is focused, nothing to pin");
+            return;
+        };
+        let layouts = self._get_lang();
+        info!("Pinning {pin} to {layouts:?}");
+        self.pinned.insert(pin, layouts);
+    }
+
+    fn unpin(&mut self) {
+        let Some(pin) = self.focused_pin.clone().filter(|_| self.prev_id.is_some()) else {
+            return;
+        };
+        if self.pinned.remove(&pin).is_some() {
+            info!("Unpinned {pin}");
+        }
+    }
+
+    /// Pin keys of a remembered entry: those of its open windows, or the
+    /// workspace key itself.
+    fn pins_of(&self, key: &str) -> Vec<String> {
+        if key.starts_with(WORKSPACE_KEY) {
+            return vec![key.to_string()];
+        }
+        self.containers
+            .iter()
+            .filter(|(_, (_, window_key))| window_key == key)
+            .map(|(id, _)| format!("con:{id}"))
+            .collect()
+    }
+
+    /// Memory keys holding a default rather than a layout the user chose,
+    /// for `status`.
+    fn default_keys(&self) -> Vec<String> {
+        let mut keys: Vec<String> = self
+            .state
+            .iter()
+            .filter(|(_, layouts)| !layouts.values().any(|stored| stored.user))
+            .map(|(key, _)| key.clone())
+            .collect();
+        keys.sort();
+        keys
+    }
+
+    /// Memory keys with a pin, for `status`.
+    fn pinned_keys(&self) -> Vec<String> {
+        let mut keys: Vec<String> = self
+            .pinned
+            .keys()
+            .map(|pin| {
+                pin.strip_prefix("con:")
+                    .and_then(|id| self.containers.get(&id.parse().ok()?))
+                    .map_or_else(|| pin.clone(), |(_, key)| key.clone())
+            })
+            .collect();
+        keys.sort();
+        keys.dedup();
+        keys
+    }
+
+    fn is_pinned(&self) -> bool {
+        self.prev_id.is_some()
+            && self
+                .focused_pin
+                .as_ref()
+                .is_some_and(|pin| self.pinned.contains_key(pin))
+    }
+
+    fn active_layout_name(&mut self) -> Option<String> {
+        self.keyboards()
+            .into_iter()
+            .next()
+            .and_then(|input| input.xkb_active_layout_name)
+    }
+
+    /// Shows the focused window and its layout in `systemctl status`.
+    fn notify_status(&mut self) {
+        if !systemd::enabled() {
+            return;
+        }
+        let layout = self.active_layout_name().unwrap_or_default();
+        let status = match &self.prev_id {
+            _ if self.locked => format!("locked, {layout}"),
+            _ if self.freeze => format!("frozen, {layout}"),
+            _ if self.paused => format!("paused, {layout}"),
+            Some(key) => format!("{layout} in {key}"),
+            None => layout,
+        };
+        if status != self.notified_status {
+            systemd::notify(&format!("STATUS={status}"));
+            self.notified_status = status;
+        }
+    }
+
+    fn publish_layout(&mut self, app_id: Option<&str>) {
+        if self.mqtt.is_none() && self.dbus.is_none() {
+            return;
+        }
+        let Some(layout) = self.active_layout_name() else {
+            return;
+        };
+        if let Some(mqtt) = &mut self.mqtt {
+            mqtt.publish(&layout, app_id);
+        }
+        if let Some(dbus) = &mut self.dbus {
+            dbus.publish(&layout, app_id);
+        }
+    }
+
+    /// Shows the active layout in the title of `con_id`, or of the window
+    /// tagged last when `None`, and removes the tag from the previous window.
+    fn update_title_tag(&mut self, con_id: Option<i64>) {
+        if !self.config.title_tag {
+            return;
+        }
+        let con_id = con_id.or(self.tagged);
+        if let Some(prev) = self.tagged.filter(|prev| Some(*prev) != con_id) {
+            let _ = self
+                .comm_conn
+                .run_command(format!("[con_id={prev}] title_format \"%title\""));
+        }
+        if let Some(id) = con_id {
+            if let Some(layout) = self.active_layout_name() {
+                let _ = self.comm_conn.run_command(format!(
+                    "[con_id={id}] title_format \"%title [{}]\"",
+                    short_layout_tag(&layout)
+                ));
+            }
+        }
+        self.tagged = con_id;
+    }
+
+    fn clear_title_tag(&mut self) {
+        if let Some(id) = self.tagged.take() {
+            let _ = self
+                .comm_conn
+                .run_command(format!("[con_id={id}] title_format \"%title\""));
+        }
+    }
+
+    /// Keyboards often come back from suspend on layout 0. Time spent
+    /// suspended shows up as CLOCK_BOOTTIME running ahead of CLOCK_MONOTONIC,
+    /// so a jump between two events means the system was asleep.
+    fn check_resume(&mut self) {
+        let now = suspended_for();
+        let slept = now.saturating_sub(self.suspended_for);
+        self.suspended_for = now;
+        if slept < Duration::from_secs(1) {
+            return;
+        }
+        info!("System resumed after {slept:?} asleep, re-applying layouts");
+        let mut targets: Vec<(String, i32)> = self
+            .inputs
+            .iter()
+            .filter_map(|input| Some((input.identifier.clone(), input.xkb_active_layout_index?)))
+            .collect();
+        for (input_id, lo_idx) in self.default_targets() {
+            if !targets.iter().any(|(id, _)| *id == input_id) {
+                targets.push((input_id, lo_idx));
+            }
+        }
+        self.switch_layouts(&targets);
+    }
+
+    /// Keyboards left out by --inputs/--ignore-inputs are never saved or
+    /// switched.
+    fn manages_input(&self, input: &Input) -> bool {
+        input.input_type == "keyboard"
+            && filter_allows(&self.config.inputs, &input.identifier)
+            && !self
+                .config
+                .ignore_inputs
+                .iter()
+                .any(|pattern| glob::matches(pattern, &input.identifier))
+    }
+
+    fn on_input(&mut self, input: &Input) {
+        if !self.manages_input(input) {
+            return;
+        }
+        match self
+            .inputs
+            .iter_mut()
+            .find(|known| known.identifier == input.identifier)
+        {
+            Some(known) => *known = input.clone(),
+            None => self.inputs.push(input.clone()),
+        }
+    }
+
+    /// A freshly plugged keyboard starts on layout 0. It gets what it had for
+    /// the focused window before, else the layout the other keyboards show
+    /// for that window, else its default.
+    fn on_input_added(&mut self, input: &Input) {
+        if !self.manages_input(input) || self.frozen() {
+            return;
+        }
+        let stored = self
+            .prev_id
+            .as_ref()
+            .and_then(|key| self.state.get(key))
+            .and_then(|map| map.get(&self.storage_id(input)))
+            .and_then(|stored| stored.resolve(&input.xkb_layout_names));
+        let lo_idx = stored.or_else(|| {
+            let name = self
+                .keyboards()
+                .into_iter()
+                .filter(|other| other.identifier != input.identifier)
+                .find_map(|other| other.xkb_active_layout_name)?;
+            input
+                .xkb_layout_names
+                .iter()
+                .position(|lo_name| *lo_name == name)
+                .map(|lo_idx| lo_idx as i32)
+        });
+        let targets = match lo_idx {
+            Some(lo_idx) => vec![(input.identifier.clone(), lo_idx)],
+            None => self
+                .default_targets()
+                .into_iter()
+                .filter(|(id, _)| *id == input.identifier)
+                .collect(),
+        };
+        if !targets.is_empty() {
+            info!(
+                "Keyboard {} plugged in, applying {targets:?}",
+                input.identifier
+            );
+            self.switch_layouts(&targets);
+        }
+    }
+
+    fn on_input_removed(&mut self, input: &Input) {
+        self.inputs
+            .retain(|known| known.identifier != input.identifier);
+    }
+
+    fn refresh_inputs(&mut self) {
+        match self.comm_conn.get_inputs() {
+            Ok(inputs) => {
+                self.inputs.clear();
+                for input in &inputs {
+                    self.on_input(input);
+                }
+            }
+            // The cache stays as it was; sway events keep it current anyway.
+            Err(err) => warn!("Cannot query inputs: {err}"),
+        }
+        self.refresh_seats();
+    }
+
+    fn refresh_seats(&mut self) {
+        self.multi_seat = self
+            .comm_conn
+            .get_seats()
+            .is_ok_and(|seats| seats.len() > 1);
+        if !self.multi_seat {
+            self.seat = None;
+        }
+    }
+
+    /// Cached keyboards, queried again only when nothing is cached. With
+    /// several seats only those of the seat that last changed focus.
+    fn keyboards(&mut self) -> Vec<Input> {
+        if self.inputs.is_empty() {
+            debug!("No cached keyboards, asking sway");
+            self.refresh_inputs();
+        }
+        if self.seat.is_none() {
+            return self.inputs.clone();
+        }
+        self.inputs
+            .iter()
+            .filter(|input| self.seat_devices.contains(&input.identifier))
+            .cloned()
+            .collect()
+    }
+
+    /// On a multi-seat setup, makes the seat focusing `con_id` the current
+    /// one: its keyboards are the only ones saved and switched, and each
+    /// seat keeps its own previously focused window.
+    fn enter_seat(&mut self, con_id: i64) {
+        if !self.multi_seat {
+            return;
+        }
+        let Ok(seats) = self.comm_conn.get_seats() else {
+            return;
+        };
+        let Some(seat) = seats.into_iter().find(|seat| seat.focus == con_id) else {
+            return;
+        };
+        if self.seat.as_ref() != Some(&seat.name) {
+            debug!("Focus changed on seat {}", seat.name);
+            if let Some(old) = self.seat.take() {
+                self.seat_prev.insert(old, self.prev_id.take());
+            }
+            self.prev_id = self.seat_prev.remove(&seat.name).flatten();
+            self.seat = Some(seat.name);
+        }
+        self.seat_devices = seat
+            .devices
+            .into_iter()
+            .map(|input| input.identifier)
+            .collect();
+    }
+
+    /// Handles `swaymsg -t send_tick "xkb-switcher:<command>"`, so the daemon
+    /// can be driven from sway keybindings.
+    fn on_tick(&mut self, payload: &str) {
+        let Some(command) = tick_command(payload) else {
+            return;
+        };
+        info!("Got tick command: {command}");
+        if let Some(seat) = command.strip_prefix("layer-focus ") {
+            return self.on_layer_focus(seat, true);
+        }
+        if let Some(seat) = command.strip_prefix("layer-unfocus ") {
+            return self.on_layer_focus(seat, false);
+        }
+        if let Some(lang) = command.trim().strip_prefix("set-current ") {
+            if let Err(err) = self.set_window_layout(None, lang) {
+                warn!("Cannot set the layout: {err}");
+            }
+            return;
+        }
+        match command.trim() {
+            "pause" => self.pause(),
+            "resume" => self.paused = false,
+            "toggle" if self.paused => self.paused = false,
+            "toggle" => self.pause(),
+            "forget" => {
+                if let Some(key) = self.prev_id.clone() {
+                    self.forget(&key);
+                }
+            }
+            "lock" => self.lock(),
+            "unlock" => self.unlock(),
+            "freeze" => self.freeze(),
+            "unfreeze" => self.freeze = false,
+            "dump" => self.dump_state(),
+            "pin" => self.pin(),
+            "unpin" => self.unpin(),
+            "toggle-pin" if self.is_pinned() => self.unpin(),
+            "toggle-pin" => self.pin(),
+            "forget-all" => self.forget_all(),
+            other => warn!(
+                "Unknown tick command: {other:?}, expected one of {}",
+                TICK_COMMANDS.join(", ")
+            ),
+        }
+    }
+
+    /// Drops the memory of one window. The focused window gets the default
+    /// layout right away.
+    fn forget(&mut self, key: &str) {
+        self.state.remove(key);
+        self.unfocused_at.remove(key);
+        self.engines.remove(key);
+        self.dirty = true;
+        if self.prev_id.as_deref() == Some(key) {
+            self.apply_default_lang();
+        }
+    }
+
+    /// Drops the memory of every window, including what closing one would
+    /// drop, pins and what the state file still holds. The focused window
+    /// gets the default layout right away.
+    fn forget_all(&mut self) {
+        self.state.clear();
+        self.unfocused_at.clear();
+        self.identities.clear();
+        self.engines.clear();
+        self.hidden.clear();
+        self.pinned.clear();
+        self.workspace_layouts.clear();
+        self.im_key = None;
+        self.saved = persist::Saved::default();
+        self.dirty = true;
+        if self.prev_id.is_some() {
+            self.apply_default_lang();
+        }
+    }
+
+    /// Answers the requests queued on the control socket.
+    fn serve_control(&mut self) {
+        let requests: Vec<control::Request> = match &self.control {
+            Some(requests) => requests.try_iter().collect(),
+            None => return,
+        };
+        for request in requests {
+            info!("Got control command: {}", request.command);
+            let response = self
+                .control_command(&request.command)
+                .unwrap_or_else(|err| json!({ "error": err }));
+            request.reply(response);
+        }
+    }
+
+    /// Runs a line of the control socket: words, where the rest of the line
+    /// after the command is its one argument, e.g. `forget <window>`, or a
+    /// JSON object like `{"command": "set", "window": ..., "layout": ...}`
+    /// for arguments that may contain spaces.
+    fn control_command(&mut self, command: &str) -> Result<Value, String> {
+        if command.starts_with('{') {
+            let request: Value = serde_json::from_str(command)
+                .map_err(|err| format!("invalid JSON command: {err}"))?;
+            let name = request["command"]
+                .as_str()
+                .ok_or_else(|| format!("no command in {command}"))?;
+            let (window, lang) = (request["window"].as_str(), request["layout"].as_str());
+            return self.control(name, window, lang);
+        }
+        match command
+            .split_once(' ')
+            .map(|(name, rest)| (name, rest.trim()))
+        {
+            None => self.control(command, None, None),
+            Some((name, "")) => self.control(name, None, None),
+            Some(("tab", rest)) => {
+                let (browser, report) = rest.split_once(' ').unwrap_or((rest, ""));
+                let browser = browser
+                    .parse()
+                    .map_err(|_| format!("invalid browser pid {browser:?}"))?;
+                let report: Value = serde_json::from_str(report)
+                    .map_err(|err| format!("invalid tab report: {err}"))?;
+                self.on_tab_report(browser, &report);
+                Ok(json!({ "ok": true }))
+            }
+            // Only the layout may contain spaces here.
+            Some(("set", rest)) => match rest.split_once(' ') {
+                Some((window, lang)) => self.control("set", Some(window), Some(lang)),
+                None => self.control("set", Some(rest), None),
+            },
+            Some(("set-current", lang)) => self.control("set-current", None, Some(lang)),
+            Some((name, window)) => self.control(name, Some(window), None),
+        }
+    }
+
+    fn control(
+        &mut self,
+        name: &str,
+        window: Option<&str>,
+        lang: Option<&str>,
+    ) -> Result<Value, String> {
+        match (name, window, lang) {
+            ("status", None, None) => Ok(self.status()),
+            ("pause", None, None) => {
+                self.pause();
+                Ok(json!({ "ok": true, "paused": true }))
+            }
+            ("resume", None, None) => {
+                self.paused = false;
+                Ok(json!({ "ok": true, "paused": false }))
+            }
+            ("freeze", None, None) => {
+                self.freeze();
+                Ok(json!({ "ok": true, "frozen": true }))
+            }
+            ("unfreeze", None, None) => {
+                self.freeze = false;
+                Ok(json!({ "ok": true, "frozen": false }))
+            }
+            ("forget", window, None) => {
+                let key = self.resolve_window(window)?;
+                self.forget(&key);
+                Ok(json!({ "ok": true, "window": key }))
+            }
+            ("pin", window, None) => {
+                let key = self.resolve_window(window)?;
+                let layouts = match self.prev_id == Some(key.clone()) {
+                    true => self._get_lang(),
+                    false => self
+                        .state
+                        .get(&key)
+                        .cloned()
+                        .ok_or_else(|| format!("nothing is remembered for {key}"))?,
+                };
+                let pins = self.pins_of(&key);
+                if pins.is_empty() {
+                    return Err(format!("{key} has no open window"));
+                }
+                for pin in pins {
+                    self.pinned.insert(pin, layouts.clone());
+                }
+                Ok(json!({ "ok": true, "window": key }))
+            }
+            ("unpin", window, None) => {
+                let key = self.resolve_window(window)?;
+                for pin in self.pins_of(&key) {
+                    self.pinned.remove(&pin);
+                }
+                Ok(json!({ "ok": true, "window": key }))
+            }
+            ("set", Some(window), Some(lang)) => self.set_window_layout(Some(window), lang),
+            ("set-current", None, Some(lang)) => self.set_window_layout(None, lang),
+            _ => Err(format!(
+                "unknown command {name:?} with these arguments, expected one of {}",
+                CONTROL_COMMANDS.join(", ")
+            )),
+        }
+    }
+
+    /// Gives a window a layout as if the user had switched to it, the
+    /// focused one right away. Without a window it is the focused one, for
+    /// keybindings that would otherwise switch behind the daemon's back.
+    fn set_window_layout(&mut self, window: Option<&str>, lang: &str) -> Result<Value, String> {
+        let key = self.resolve_window(window)?;
+        let map = self.layout_map(lang.trim())?;
+        if self.prev_id.as_deref() == Some(key.as_str()) {
+            let targets = self.resolve_inputs(map.clone());
+            self.switch_layouts(&targets);
+        }
+        self.state.insert(key.clone(), map);
+        self.dirty = true;
+        Ok(json!({ "ok": true, "window": key }))
+    }
+
+    /// Records the site a browser window shows, which for the focused
+    /// window makes switching to a tab of another site a focus change.
+    /// Reports without a window drop all of the browser's.
+    fn on_tab_report(&mut self, browser: i32, report: &Value) {
+        let Some(window) = report["window"].as_i64() else {
+            debug!("Browser {browser} went away");
+            self.tabs.remove(&browser);
+            return;
+        };
+        let focused = self
+            .focused_window
+            .clone()
+            .filter(|focused| focused.pid == Some(browser));
+        let before = focused.as_ref().map(|focused| self.make_map_key(focused));
+        let tabs = self.tabs.entry(browser).or_default();
+        let old = tabs
+            .iter()
+            .position(|tab| tab.window == window)
+            .map(|index| tabs.remove(index));
+        if report["closed"].as_bool().unwrap_or_default() {
+            if tabs.is_empty() {
+                self.tabs.remove(&browser);
+            }
+            return;
+        }
+        let tab = Tab {
+            window,
+            title: report["title"].as_str().unwrap_or_default().to_string(),
+            site: report["site"].as_str().unwrap_or_default().to_string(),
+        };
+        debug!("Browser {browser} window {window} shows {}", tab.site);
+        let Some(mut focused) = focused else {
+            tabs.push(tab);
+            return;
+        };
+        // Sway's title may still name the tab left; the tab that replaced
+        // it is what the window shows now.
+        let title = focused.title.as_deref().unwrap_or_default();
+        if old.is_some_and(|old| shows(title, &old.title)) {
+            focused.title = Some(tab.title.clone());
+            self.focused_window = Some(focused.clone());
+        }
+        tabs.push(tab);
+        if self.frozen() || self.prev_id != before {
+            return;
+        }
+        let key = self.make_map_key(&focused);
+        if self.prev_id.as_deref() != Some(key.as_str()) {
+            self.trigger = format!("tab {key}");
+            self.on_focus(&focused, &key);
+            self.publish_layout(focused.app_id.as_deref());
+        }
+    }
+
+    fn status(&mut self) -> Value {
+        let windows: serde_json::Map<String, Value> = self
+            .state
+            .iter()
+            .map(|(key, layouts)| {
+                let layouts: serde_json::Map<String, Value> = layouts
+                    .iter()
+                    .map(|(input, stored)| (input.clone(), json!(stored.name)))
+                    .collect();
+                (key.clone(), Value::Object(layouts))
+            })
+            .collect();
+        json!({
+            "paused": self.paused,
+            "locked": self.locked,
+            "frozen": self.freeze,
+            "mode": format!("{:?}", self.config.mode).to_lowercase(),
+            "focused": self.prev_id,
+            "pinned": self.is_pinned(),
+            "pinned_windows": self.pinned_keys(),
+            "defaults": self.default_keys(),
+            "layout": self.active_layout_name(),
+            "windows": windows,
+            "stats": self.stats.to_json(),
+        })
+    }
+
+    /// Logs every remembered window with its layout per input.
+    fn dump_state(&mut self) {
+        let layout = self.active_layout_name();
+        info!(
+            target: "dump",
+            "paused={} focused={:?} layout={layout:?}, {} remembered",
+            self.paused,
+            self.prev_id,
+            self.state.len()
+        );
+        let mut keys: Vec<&String> = self.state.keys().collect();
+        keys.sort();
+        for key in keys {
+            let layouts: Vec<String> = self.state[key]
+                .iter()
+                .map(|(input, stored)| format!("{input}={:?} (#{})", stored.name, stored.index))
+                .collect();
+            info!(target: "dump", "{key}: {}", layouts.join(", "));
+        }
+    }
+
+    /// Loads the keymap of an --xkb-file into the keyboards, or with `None`
+    /// the one of the sway config back.
+    fn load_keymap(&mut self, xkb_file: Option<String>) {
+        let file = match &xkb_file {
+            Some(path) => format!("\"{}\"", path.replace('"', "\\\"")),
+            None => "-".to_string(),
+        };
+        let command = self
+            .keyboards()
+            .iter()
+            .map(|input| format!("input {} xkb_file {file}", input.identifier))
+            .collect::<Vec<_>>()
+            .join("; ");
+        self.xkb_file = xkb_file;
+        if self.config.dry_run {
+            info!(target: "dry-run", "{command}");
+            return;
+        }
+        info!("Loading keymap {file}");
+        match self.comm_conn.run_command(command) {
+            Ok(outcomes) => {
+                for err in outcomes.into_iter().filter_map(Result::err) {
+                    warn!("Cannot load keymap {file}: {err}");
+                }
+            }
+            Err(err) => warn!("Cannot load keymap {file}: {err}"),
+        }
+        self.refresh_inputs();
+    }
+
+    /// With --reset-on-exit, leaves the keyboards on the default layout, or
+    /// the first one without a default.
+    fn reset_layouts(&mut self) {
+        info!("Resetting keyboards to the default layout");
+        if !self.apply_default_lang() {
+            self.switch_layouts(&[(ALL_KEYBOARDS.to_string(), 0)]);
+        }
+    }
+
+    /// Memory key named by a control command: `focused` (or nothing), a
+    /// memory key, a con_id, or a workspace name in --mode workspace.
+    fn resolve_window(&self, window: Option<&str>) -> Result<String, String> {
+        let window = match window {
+            None | Some("focused") => {
+                return self
+                    .prev_id
+                    .clone()
+                    .ok_or_else(|| "no window is focused".to_string())
+            }
+            Some(window) => window,
+        };
+        if self.state.contains_key(window) {
+            return Ok(window.to_string());
+        }
+        if self.config.mode == Mode::Workspace {
+            return Ok(workspace_key(window));
+        }
+        match window.parse::<i64>() {
+            Ok(id) => Ok(self
+                .containers
+                .get(&id)
+                .map_or_else(|| id.to_string(), |(_, key)| key.clone())),
+            Err(_) => Err(format!("unknown window {window}")),
+        }
+    }
+
+    /// Stored form of a layout given by name or index, as `_get_lang` would
+    /// have recorded it had the user switched to it.
+    fn layout_map(&mut self, lang: &str) -> Result<persist::Layouts, String> {
+        let mut map = persist::Layouts::default();
+        for input in &self.keyboards() {
+            let names = &input.xkb_layout_names;
+            let lo_idx = match lang.parse::<usize>() {
+                Ok(lo_idx) if lo_idx < names.len() => Some(lo_idx),
+                _ => find_layout(names, lang),
+            };
+            if let Some(lo_idx) = lo_idx {
+                let id = match self.config.compat {
+                    Some(Compat::Swaykbdd) => ALL_KEYBOARDS.to_string(),
+                    None if self.config.sync_inputs => ALL_KEYBOARDS.to_string(),
+                    None => self.storage_id(input),
+                };
+                map.entry(id).or_insert_with(|| StoredLayout {
+                    user: true,
+                    ..StoredLayout::of(input, lo_idx as i32)
+                });
+            }
+        }
+        if map.is_empty() {
+            let available = self.available_layouts();
+            return Err(format!(
+                "no keyboard has a layout {lang}{}, available: {}",
+                did_you_mean(lang, &available),
+                available.join(", ")
+            ));
+        }
+        Ok(map)
+    }
+
+    /// Swaps in the connection opened after sway came back. Inputs may have
+    /// changed meanwhile, so their cached state is read again.
+    fn reconnected(&mut self, comm_conn: Box<dyn Compositor>) {
+        self.comm_conn = Box::new(stats::Counted::new(comm_conn, self.stats.clone()));
+        self.refresh_inputs();
+        self.refresh_device_default();
+    }
+
+    /// Swaps in a re-read config while keeping the remembered layouts.
+    /// --sandbox, --daemonize and the paths only take effect at startup.
+    fn reload(&mut self, config: Config) {
+        if (&config.mqtt, &config.mqtt_topic) != (&self.config.mqtt, &self.config.mqtt_topic) {
+            self.mqtt = config
+                .mqtt
+                .clone()
+                .map(|addr| mqtt::Publisher::new(addr, config.mqtt_topic.clone()));
+        }
+        if config.dbus != self.config.dbus {
+            self.dbus = config.dbus.then(dbus_service);
+        }
+        if self.config.title_tag && !config.title_tag {
+            self.clear_title_tag();
+        }
+        self.config = config;
+        self.watch_layers();
+        self.refresh_inputs();
+        self.refresh_device_default();
+        self.check_default_lang();
+        info!("Reloaded the config: {:?}", self.config);
+    }
+
+    /// Stops following focus until resumed. The focused window's layout is
+    /// saved first so switches made while paused are not attributed to it.
+    fn pause(&mut self) {
+        if let Some(key) = self.prev_id.take() {
+            self.save_layout(key);
+        }
+        self.paused = true;
+    }
+
+    /// Map sizes for the stats, to spot state growing on long uptimes.
+    fn record_sizes(&self) {
+        self.stats.set_sizes(vec![
+            ("remembered", self.state.len()),
+            ("unfocused", self.unfocused_at.len()),
+            ("containers", self.containers.len()),
+            ("identities", self.identities.len()),
+            ("pinned", self.pinned.len()),
+            ("engines", self.engines.len()),
+            ("workspaces", self.workspace_layouts.len()),
+            ("tabs", self.tabs.values().map(Vec::len).sum()),
+            (
+                "saved",
+                self.saved.windows.len() + self.saved.workspaces.len(),
+            ),
+        ]);
+    }
+
+    /// Keeps the current layout in every window, e.g. for a presentation:
+    /// unlike `pause`, the focused window does not get it saved either.
+    fn freeze(&mut self) {
+        self.prev_id = None;
+        self.freeze = true;
+    }
+
+    fn frozen(&self) -> bool {
+        self.paused || self.locked || self.freeze
+    }
+
+    /// Freezes the state while the screen is locked: the focused window's
+    /// layout is saved, and focus changes and keyboards plugged in until
+    /// `unlock` are ignored.
+    fn lock(&mut self) {
+        if self.locked {
+            return;
+        }
+        if let Some(key) = self.prev_id.clone().filter(|_| !self.frozen()) {
+            self.save_layout(key);
+        }
+        self.locked = true;
+    }
+
+    /// The locker may have left the keyboards on another layout (the one
+    /// the password was typed in), so the focused window gets its
+    /// remembered layout back.
+    fn unlock(&mut self) {
+        if !std::mem::take(&mut self.locked) || self.frozen() {
+            return;
+        }
+        let Some(key) = self.prev_id.clone() else {
+            return;
+        };
+        let pinned = self
+            .focused_pin
+            .as_ref()
+            .and_then(|pin| self.pinned.get(pin));
+        let Some(map) = pinned.or_else(|| self.state.get(&key)).cloned() else {
+            return;
+        };
+        debug!("Unlocked, restoring the layout of {key}");
+        let targets = self.resolve_inputs(map);
+        self.switch_layouts(&targets);
+    }
+
+    fn on_close(&mut self, key: &str) {
+        info!("Closed window: {}", key);
+        self.state.remove(key);
+        self.unfocused_at.remove(key);
+        self.identities.remove(key);
+        self.engines.remove(key);
+        self.hidden.retain(|hidden| hidden != key);
+        if self.im_key.as_deref() == Some(key) {
+            self.im_key = None;
+        }
+        self.dirty = true;
+        if self.prev_id == Some(key.to_string()) {
+            self.prev_id = None;
+        }
+        for prev_id in self.seat_prev.values_mut() {
+            if prev_id.as_deref() == Some(key) {
+                *prev_id = None;
+            }
+        }
+    }
+
+    /// Some windows change app_id after startup (Electron splash screens,
+    /// Xwayland reparenting). Move whatever was stored under the key derived
+    /// from the old app_id to the new key.
+    fn rekey_on_app_change(&mut self, window: &Window, key: &str) {
+        let seen = (window.app_id.clone(), key.to_string());
+        let Some((old_app_id, old_key)) = self.containers.insert(window.id, seen) else {
+            return;
+        };
+        // A shared key stays with the windows of the group or app.
+        if old_app_id == window.app_id || old_key == key || is_shared_key(&old_key) {
+            return;
+        }
+        info!(
+            "Window changed app_id from {:?} to {:?}, re-keying {} to {}",
+            old_app_id, window.app_id, old_key, key
+        );
+        if let Some(map) = self.state.remove(&old_key) {
+            self.state.entry(key.to_string()).or_insert(map);
+        }
+        if self.prev_id.as_deref() == Some(old_key.as_str()) {
+            self.prev_id = Some(key.to_string());
+        }
+    }
+
+    /// Hands a window without memory the layout it had before the daemon
+    /// restarted, and keeps track of what identifies it in the state file.
+    fn restore_saved(&mut self, window: &Window, key: &str) {
+        if self.persist_path.is_none() {
+            return;
+        }
+        let saved = match key.strip_prefix(WORKSPACE_KEY) {
+            Some(name) => self.saved.workspaces.remove(name),
+            None => {
+                let Some(app) = window.app_id.as_ref().or(window.class.as_ref()) else {
+                    return;
+                };
+                let title = window.title.clone().unwrap_or_default();
+                self.identities
+                    .insert(key.to_string(), (app.clone(), title.clone()));
+                if self.state.contains_key(key) {
+                    return;
+                }
+                self.saved.take(app, &title)
+            }
+        };
+        if let Some(layouts) = saved {
+            if !self.state.contains_key(key) {
+                debug!("Restoring saved layouts for {key}");
+                self.state.insert(key.to_string(), layouts);
+            }
+        }
+    }
+
+    /// Writes the state file at most every ten seconds while layouts change,
+    /// or right away when `now` is set.
+    fn persist(&mut self, now: bool) {
+        let Some(path) = &self.persist_path else {
+            return;
+        };
+        if !self.dirty || (!now && self.persisted_at.elapsed() < Duration::from_secs(10)) {
+            return;
+        }
+        let mut saved = self.saved.clone();
+        let persist_user_only = self.config.persist_user_only;
+        let persisted = |layouts: &persist::Layouts| {
+            !persist_user_only || layouts.values().any(|stored| stored.user)
+        };
+        saved.windows.retain(|(_, _, layouts)| persisted(layouts));
+        saved.workspaces.retain(|_, layouts| persisted(layouts));
+        for (key, layouts) in self.state.iter().filter(|(_, layouts)| persisted(layouts)) {
+            if let Some(name) = key.strip_prefix(WORKSPACE_KEY) {
+                saved.workspaces.insert(name.to_string(), layouts.clone());
+            } else if let Some((app, title)) = self.identities.get(key) {
+                saved
+                    .windows
+                    .push((app.clone(), title.clone(), layouts.clone()));
+            }
+        }
+        match persist::store(path, &saved) {
+            Ok(()) => debug!("Saved layouts to {}", path.display()),
+            Err(err) => warn!("Cannot save layouts to {}: {err}", path.display()),
+        }
+        self.dirty = false;
+        self.persisted_at = Instant::now();
+    }
+
+    fn _set_lang(&mut self, window: &Window, key: &str) {
+        let _span = span!("restore");
+        let focus = |reason: &str| format!("focus {}: {reason}", watch_name(window, key));
+        if let Some(lang) = self.app_rule(&self.config.forced_layouts, window) {
+            debug!("{key} is forced to {lang}");
+            self.watch(|| focus("forced by --force-layout"));
+            let targets = self.layout_targets(&lang);
+            self.switch_layouts(&targets);
+        } else if let Some(map) = self.state.get(key).cloned() {
+            self.watch(|| focus("restored"));
+            let targets = self.resolve_inputs(map);
+            let targets = self.clamp_targets(window, targets);
+            self.switch_layouts(&targets);
+        } else if let Some(lang) = self.app_rule(&self.config.app_defaults, window) {
+            debug!("No layout remembered for {key}, using the app default {lang}");
+            self.watch(|| focus("nothing remembered, --app-default"));
+            let targets = self.layout_targets(&lang);
+            self.switch_layouts(&targets);
+        } else if let Some(map) = self.workspace_layout() {
+            debug!("No layout remembered for {key}, using its workspace's last layout");
+            self.watch(|| focus("nothing remembered, the workspace's last layout"));
+            let targets = self.resolve_inputs(map);
+            let targets = self.clamp_targets(window, targets);
+            self.switch_layouts(&targets);
+        } else if self.config.continuity {
+            debug!("No layout remembered for {key}, keeping the current one");
+            self.watch(|| focus("nothing remembered, kept the current layout"));
+        } else {
+            self.watch(|| focus("nothing remembered, default layout"));
+            self.apply_default_lang();
+        }
+    }
+
+    /// With --allowed-layouts, keyboards headed for a layout the app is not
+    /// allowed get the first allowed one they have instead.
+    fn clamp_targets(
+        &mut self,
+        window: &Window,
+        targets: Vec<(String, i32)>,
+    ) -> Vec<(String, i32)> {
+        let Some((_, allowed)) = self
+            .config
+            .allowed_layouts
+            .iter()
+            .find(|(pattern, _)| self.matches_app(pattern, window))
+            .cloned()
+        else {
+            return targets;
+        };
+        let keyboards = self.keyboards();
+        let mut clamped = vec![];
+        let mut changed = false;
+        for (input_id, lo_idx) in &targets {
+            let inputs = keyboards
+                .iter()
+                .filter(|input| input_id == ALL_KEYBOARDS || input.identifier == *input_id);
+            for input in inputs {
+                let indices: Vec<i32> = allowed
+                    .iter()
+                    .filter_map(|lang| find_layout(&input.xkb_layout_names, lang))
+                    .map(|idx| idx as i32)
+                    .collect();
+                match indices.first() {
+                    Some(first) if !indices.contains(lo_idx) => {
+                        debug!(
+                            "{} is not allowed layout {lo_idx}, using {first}",
+                            input.identifier
+                        );
+                        clamped.push((input.identifier.clone(), *first));
+                        changed = true;
+                    }
+                    _ => clamped.push((input.identifier.clone(), *lo_idx)),
+                }
+            }
+        }
+        if changed {
+            self.collapse_targets(clamped)
+        } else {
+            targets
+        }
+    }
+
+    /// Moves what is remembered for a workspace to its new name.
+    fn rename_workspace(&mut self, old: &str, new: &str) {
+        debug!("Workspace {old} renamed to {new}");
+        let (old_key, new_key) = (workspace_key(old), workspace_key(new));
+        if let Some(layouts) = self.state.remove(&old_key) {
+            self.state.insert(new_key.clone(), layouts);
+            self.dirty = true;
+        }
+        if let Some(at) = self.unfocused_at.remove(&old_key) {
+            self.unfocused_at.insert(new_key.clone(), at);
+        }
+        if let Some(layouts) = self.workspace_layouts.remove(old) {
+            self.workspace_layouts.insert(new.to_string(), layouts);
+        }
+        if let Some(layouts) = self.saved.workspaces.remove(old) {
+            self.saved.workspaces.insert(new.to_string(), layouts);
+            self.dirty = true;
+        }
+        if self.prev_id.as_deref() == Some(old_key.as_str()) {
+            self.prev_id = Some(new_key);
+        }
+        if self.focused_workspace.as_deref() == Some(old) {
+            self.focused_workspace = Some(new.to_string());
+        }
+    }
+
+    /// Learns the names of the existing workspaces, for rename events.
+    fn track_workspaces(&mut self) {
+        if let Ok(workspaces) = self.comm_conn.get_workspaces() {
+            self.workspace_names = workspaces.into_iter().map(|ws| (ws.id, ws.name)).collect();
+        }
+    }
+
+    fn workspace_layout(&self) -> Option<persist::Layouts> {
+        let workspace = self.focused_workspace.as_ref()?;
+        self.workspace_layouts.get(workspace).cloned()
+    }
+
+    /// Identifier under which an input's layout is stored. With
+    /// --group-inputs, identical models (and keyboards that show up as several
+    /// devices) share one vendor:product entry.
+    fn storage_id(&self, input: &Input) -> String {
+        match (self.config.group_inputs, input.vendor, input.product) {
+            (true, Some(vendor), Some(product)) => format!("{vendor}:{product}"),
+            _ => input.identifier.clone(),
+        }
+    }
+
+    /// Turns a stored map into per-device switch commands. Stored maps may
+    /// reference keyboards that are gone (docked -> undocked); then the
+    /// remaining keyboards get the layout most of the stored inputs had.
+    fn resolve_inputs(&mut self, map: persist::Layouts) -> Vec<(String, i32)> {
+        if let Some(stored) = map.get(ALL_KEYBOARDS) {
+            if !self.config.sync_inputs {
+                return vec![(ALL_KEYBOARDS.to_string(), stored.index)];
+            }
+            let stored = stored.clone();
+            let mut targets = vec![];
+            for input in self.keyboards() {
+                match stored.resolve(&input.xkb_layout_names) {
+                    Some(lo_idx) => targets.push((input.identifier, lo_idx)),
+                    None => debug!("{} has no layout {:?}", input.identifier, stored.name),
+                }
+            }
+            if targets.is_empty() {
+                info!(
+                    "Remembered layout {:?} no longer configured, using the default",
+                    stored.name
+                );
+                return self.default_targets();
+            }
+            return self.collapse_targets(targets);
+        }
+        let keyboards: Vec<(String, Input)> = self
+            .keyboards()
+            .into_iter()
+            .map(|input| (self.storage_id(&input), input))
+            .collect();
+
+        let present = |id: &String| keyboards.iter().any(|(storage_id, _)| storage_id == id);
+        let majority = if map.keys().all(present) {
+            None
+        } else {
+            let mut counts: Vec<(&StoredLayout, usize)> = vec![];
+            for stored in map.values() {
+                match counts
+                    .iter_mut()
+                    .find(|(known, _)| known.name == stored.name)
+                {
+                    Some((_, count)) => *count += 1,
+                    None => counts.push((stored, 1)),
+                }
+            }
+            let majority = counts
+                .into_iter()
+                .max_by_key(|(stored, count)| (*count, std::cmp::Reverse(stored.index)))
+                .map(|(stored, _)| stored.clone());
+            info!("Stored inputs missing, applying layout {majority:?} to remaining keyboards");
+            majority
+        };
+
+        let mut targets = vec![];
+        let mut gone = vec![];
+        for (storage_id, kbd) in keyboards {
+            let Some(stored) = map.get(&storage_id).or(majority.as_ref()) else {
+                continue;
+            };
+            match stored.resolve(&kbd.xkb_layout_names) {
+                Some(lo_idx) => targets.push((kbd.identifier, lo_idx)),
+                None => gone.push(kbd.identifier),
+            }
+        }
+        // The remembered layout was removed from the sway config.
+        if !gone.is_empty() {
+            info!("Remembered layout no longer configured on {gone:?}, using the default");
+            let defaults = self.default_targets();
+            targets.extend(defaults.into_iter().filter(|(id, _)| gone.contains(id)));
+        }
+        targets
+    }
+
+    fn default_lang(&self) -> Option<&String> {
+        self.device_default
+            .as_ref()
+            .or(self.config.default_lang.as_ref())
+    }
+
+    /// A device default applies while a matching keyboard is connected; the
+    /// first matching entry wins over --default-lang.
+    fn refresh_device_default(&mut self) {
+        if self.config.device_defaults.is_empty() {
+            return;
+        }
+        let inputs = self.keyboards();
+        let device_default = self
+            .config
+            .device_defaults
+            .iter()
+            .find(|(pattern, _)| {
+                inputs
+                    .iter()
+                    .any(|input| glob::matches(pattern, &input.identifier))
+            })
+            .map(|(_, lang)| lang.clone());
+        if device_default != self.device_default {
+            info!(
+                "Default layout from connected devices: {:?}",
+                device_default
+            );
+            self.device_default = device_default;
+        }
+    }
+
+    /// Per-input layout indices the default language resolves to.
+    fn default_targets(&mut self) -> Vec<(String, i32)> {
+        let lang = self.default_lang().cloned();
+        if self.config.input_defaults.is_empty() {
+            return match lang {
+                Some(lang) => self.layout_targets(&lang),
+                None => vec![],
+            };
+        }
+        let mut targets = vec![];
+        for input in self.keyboards() {
+            let lang = self
+                .config
+                .input_defaults
+                .iter()
+                .find(|(pattern, _)| glob::matches(pattern, &input.identifier))
+                .map(|(_, lang)| lang)
+                .or(lang.as_ref());
+            let Some(lang) = lang else {
+                continue;
+            };
+            if let Some(lo_idx) = find_layout(&input.xkb_layout_names, lang) {
+                targets.push((input.identifier.clone(), lo_idx as i32));
+            }
+        }
+        targets
+    }
+
+    fn layout_targets(&mut self, lang: &str) -> Vec<(String, i32)> {
+        if self.config.compat == Some(Compat::Swaykbdd) {
+            if let Ok(lo_idx) = lang.parse::<i32>() {
+                return vec![(ALL_KEYBOARDS.to_string(), lo_idx)];
+            }
+        }
+        let mut targets = vec![];
+        for input in self.keyboards() {
+            if let Some(lo_idx) = find_layout(&input.xkb_layout_names, lang) {
+                targets.push((input.identifier.clone(), lo_idx as i32));
+            }
+        }
+        self.collapse_targets(targets)
+    }
+
+    /// With --sync-inputs, targets moving every keyboard to the same index
+    /// become one `input type:keyboard` command.
+    fn collapse_targets(&mut self, targets: Vec<(String, i32)>) -> Vec<(String, i32)> {
+        if !self.config.sync_inputs {
+            return targets;
+        }
+        let Some(&(_, lo_idx)) = targets.first() else {
+            return targets;
+        };
+        let keyboards = self.keyboards();
+        let everywhere = keyboards.len() == targets.len()
+            && targets.iter().all(|(_, idx)| *idx == lo_idx)
+            && keyboards
+                .iter()
+                .all(|input| targets.iter().any(|(id, _)| *id == input.identifier));
+        if everywhere {
+            vec![(ALL_KEYBOARDS.to_string(), lo_idx)]
+        } else {
+            targets
+        }
+    }
+
+    /// Layout names of all keyboards, for error messages.
+    fn available_layouts(&mut self) -> Vec<String> {
+        let mut names: Vec<String> = vec![];
+        for input in self.keyboards() {
+            for name in input.xkb_layout_names {
+                if !names.contains(&name) {
+                    names.push(name);
+                }
+            }
+        }
+        names
+    }
+
+    /// A --default-lang that matches no keyboard would silently never apply.
+    fn check_default_lang(&mut self) {
+        let Some(lang) = self.config.default_lang.clone() else {
+            return;
+        };
+        let keyboards = self.keyboards();
+        if keyboards.is_empty() {
+            return;
+        }
+        if !keyboards
+            .iter()
+            .any(|input| find_layout(&input.xkb_layout_names, &lang).is_some())
+        {
+            let available = self.available_layouts();
+            error!(
+                "--default-lang {lang} matches no keyboard layout{}, available: {}",
+                did_you_mean(&lang, &available),
+                available.join(", ")
+            );
+        }
+    }
+
+    fn apply_default_lang(&mut self) -> bool {
+        let targets = self.default_targets();
+        self.switch_layouts(&targets);
+        !targets.is_empty()
+    }
+
+    fn send_switch(&mut self, targets: &[(String, i32)]) {
+        if let Some(chord) = self.config.switch_chord.clone() {
+            return self.chord_switch(&chord, targets);
+        }
+        let targets = self.expand_all_keyboards(targets);
+        for (input_id, lo_idx) in &targets {
+            match input_id.as_str() {
+                ALL_KEYBOARDS => {
+                    for input in self.keyboards() {
+                        self.requested.insert(input.identifier, *lo_idx);
+                    }
+                }
+                input_id => {
+                    self.requested.insert(input_id.to_string(), *lo_idx);
+                }
+            }
+        }
+        let applied = match targets.as_slice() {
+            [] => return,
+            [(input_id, lo_idx)] => self
+                .comm_conn
+                .set_layout(input_id, *lo_idx)
+                .map(|applied| vec![applied]),
+            // One round trip for all keyboards instead of one each.
+            targets => self.comm_conn.set_layouts(targets),
+        };
+        let applied = applied.unwrap_or_else(|err| {
+            warn!("Switching layouts failed: {err}");
+            vec![false; targets.len()]
+        });
+        for ((input_id, lo_idx), applied) in targets.iter().zip(applied) {
+            let _span = span!("input", id = input_id, layout = lo_idx);
+            if applied {
+                debug!("Switched to layout index {lo_idx}");
+            } else {
+                warn!("Switching by index failed, stepping relatively");
+                self.step_layouts(input_id, *lo_idx);
+            }
+        }
+    }
+
+    /// With input filters, "all keyboards" means the managed ones only.
+    fn expand_all_keyboards(&mut self, targets: &[(String, i32)]) -> Vec<(String, i32)> {
+        let filtered = !self.config.inputs.is_empty() || !self.config.ignore_inputs.is_empty();
+        if !filtered && self.seat.is_none() {
+            return targets.to_vec();
+        }
+        let keyboards = self.keyboards();
+        let mut expanded = vec![];
+        for (input_id, lo_idx) in targets {
+            if input_id == ALL_KEYBOARDS {
+                expanded.extend(
+                    keyboards
+                        .iter()
+                        .map(|input| (input.identifier.clone(), *lo_idx)),
+                );
+            } else {
+                expanded.push((input_id.clone(), *lo_idx));
+            }
+        }
+        expanded
+    }
+
+    /// With --switch-chord, layouts are cycled by typing the xkb group switch
+    /// chord instead of IPC commands, for clients that only react to that.
+    /// The chord switches the whole seat, so one press count per target is
+    /// taken from the first keyboard that needs to move.
+    fn chord_switch(&mut self, chord: &str, targets: &[(String, i32)]) {
+        let Ok(inputs) = self.comm_conn.get_inputs() else {
+            return;
+        };
+        for (input_id, lo_idx) in targets {
+            let steps = inputs
+                .iter()
+                .filter(|input| self.manages_input(input))
+                .filter(|input| input_id == ALL_KEYBOARDS || input.identifier == *input_id)
+                .filter_map(|input| {
+                    let count = input.xkb_layout_names.len() as i32;
+                    let active = input.xkb_active_layout_index?;
+                    (count > 0).then(|| (lo_idx - active).rem_euclid(count))
+                })
+                .find(|steps| *steps > 0);
+            if let Some(steps) = steps {
+                let _span = span!("input", id = input_id, layout = lo_idx);
+                debug!("Pressing {chord} {steps} times");
+                if let Err(err) = chord::press(chord, steps as usize) {
+                    error!("Cannot press switch chord {chord}: {err}");
+                }
+            }
+        }
+    }
+
+    /// Reaches `lo_idx` with `xkb_switch_layout next`/`prev` steps counted
+    /// from the cached active index, for setups where the absolute form is
+    /// rejected.
+    fn step_layouts(&mut self, input_id: &str, lo_idx: i32) {
+        let current: Vec<(String, i32)> = self
+            .inputs
+            .iter()
+            .filter(|input| input_id == ALL_KEYBOARDS || input.identifier == input_id)
+            .filter_map(|input| Some((input.identifier.clone(), input.xkb_active_layout_index?)))
+            .collect();
+        if current.is_empty() {
+            warn!("No cached layout index for {input_id}, cannot step");
+        }
+        for (id, idx) in current {
+            let (direction, steps) = if lo_idx >= idx {
+                ("next", lo_idx - idx)
+            } else {
+                ("prev", idx - lo_idx)
+            };
+            for _ in 0..steps {
+                let _ = self
+                    .comm_conn
+                    .run_command(format!("input {id} xkb_switch_layout {direction}"));
+            }
+            if let Some(input) = self.inputs.iter_mut().find(|input| input.identifier == id) {
+                input.xkb_active_layout_index = Some(lo_idx);
+            }
+        }
+    }
+
+    fn switch_layouts(&mut self, targets: &[(String, i32)]) {
+        // Nothing switches, so neither popups nor hooks are due.
+        if self.config.dry_run {
+            for (input_id, lo_idx) in &self.expand_all_keyboards(targets) {
+                info!(target: "dry-run", "input {input_id} xkb_switch_layout {lo_idx}");
+            }
+            return;
+        }
+        let changes = self.layout_changes(targets);
+        // Targets already active are sent too, but change nothing.
+        let app = self.focused_app.as_deref().unwrap_or("-");
+        for (input_id, _, layout) in &changes {
+            self.stats.switch(app, layout);
+            self.watch(|| format!("  {layout} on {input_id}"));
+        }
+        self.send_switch(targets);
+        if self.config.verify && !targets.is_empty() {
+            self.verify_layouts(targets);
+        }
+        if let (Some(notifier), Some((_, _, layout))) = (self.config.notify, changes.first()) {
+            if !self.config.quiet_hours.is_some_and(notify::QuietHours::now) {
+                notifier.show(layout, &self.trigger);
+            }
+        }
+        if let Some(command) = &self.config.on_switch {
+            for (input_id, lo_idx, layout) in &changes {
+                hook::run(
+                    command,
+                    &[
+                        ("LAYOUT_NAME", layout.clone()),
+                        ("LAYOUT_INDEX", lo_idx.to_string()),
+                        ("INPUT_ID", input_id.clone()),
+                        ("APP_ID", self.focused_app.clone().unwrap_or_default()),
+                    ],
+                );
+            }
+        }
+    }
+
+    /// Keyboards that switching to `targets` actually changes, as input id,
+    /// layout index and name, judged by the cached active layouts.
+    fn layout_changes(&mut self, targets: &[(String, i32)]) -> Vec<(String, i32, String)> {
+        let keyboards = self.keyboards();
+        let mut changes = vec![];
+        for (input_id, lo_idx) in targets {
+            for input in &keyboards {
+                if (input_id != ALL_KEYBOARDS && input.identifier != *input_id)
+                    || input.xkb_active_layout_index == Some(*lo_idx)
+                {
+                    continue;
+                }
+                if let Some(name) = input.xkb_layout_names.get(*lo_idx as usize) {
+                    changes.push((input.identifier.clone(), *lo_idx, name.clone()));
+                }
+            }
+        }
+        changes
+    }
+
+    /// Targets whose keyboards do not report the requested layout as active.
+    fn unapplied(&mut self, targets: &[(String, i32)]) -> Vec<(String, i32)> {
+        let Ok(inputs) = self.comm_conn.get_inputs() else {
+            return vec![];
+        };
+        targets
+            .iter()
+            .filter(|(input_id, lo_idx)| {
+                inputs.iter().any(|input| {
+                    self.manages_input(input)
+                        && (input_id == ALL_KEYBOARDS || input.identifier == *input_id)
+                        && (*lo_idx as usize) < input.xkb_layout_names.len()
+                        && input.xkb_active_layout_index != Some(*lo_idx)
+                })
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Reads the layouts back after switching and retries once, since sway
+    /// occasionally drops a switch without reporting an error.
+    fn verify_layouts(&mut self, targets: &[(String, i32)]) {
+        let missed = self.unapplied(targets);
+        if missed.is_empty() {
+            return;
+        }
+        warn!("sway did not apply layout switch {missed:?}, retrying");
+        self.send_switch(&missed);
+        let missed = self.unapplied(&missed);
+        if !missed.is_empty() {
+            error!("sway ignored layout switch {missed:?} twice, giving up");
+        }
+    }
+
+    /// Whether the window's app_id or class matches `pattern`, either as a
+    /// case-insensitive glob or, without wildcards, as the same app. Patterns
+    /// `pid:<pid>` and `cgroup:<glob>` match the window's process instead,
+    /// for flatpak'd or containerized apps whose app_id is no help.
+    fn matches_app(&self, pattern: &str, window: &Window) -> bool {
+        if let Some(pid) = pattern.strip_prefix("pid:") {
+            return window.pid.is_some_and(|window| window.to_string() == pid);
+        }
+        if let Some(pattern) = pattern.strip_prefix("cgroup:") {
+            return window
+                .pid
+                .and_then(cgroup)
+                .is_some_and(|cgroup| glob::matches(pattern, &cgroup));
+        }
+        [window.app_id.as_deref(), window.class.as_deref()]
+            .into_iter()
+            .flatten()
+            .any(|app_id| {
+                if pattern.contains(['*', '?']) {
+                    glob::matches(&pattern.to_lowercase(), &app_id.to_lowercase())
+                } else {
+                    self.same_app(pattern, app_id)
+                }
+            })
+    }
+
+    /// First mark of the window matching --group-marks.
+    fn group_mark<'a>(&self, window: &'a Window) -> Option<&'a str> {
+        window
+            .marks
+            .iter()
+            .find(|mark| {
+                self.config
+                    .group_marks
+                    .iter()
+                    .any(|g| glob::matches(g, mark))
+            })
+            .map(String::as_str)
+    }
+
+    fn is_ignored(&self, window: &Window) -> bool {
+        (self.config.ignore_floating && window.floating)
+            || self
+                .config
+                .ignored_apps
+                .iter()
+                .any(|ignored| self.matches_app(ignored, window))
+    }
+
+    fn is_secure_prompt(&self, window: &Window) -> bool {
+        self.config
+            .secure_apps
+            .iter()
+            .any(|secure| self.matches_app(secure, window))
+    }
+
+    /// Layout of the first --force-layout or --app-default rule matching the
+    /// window.
+    fn app_rule(&self, rules: &[(String, String)], window: &Window) -> Option<String> {
+        rules
+            .iter()
+            .find(|(pattern, _)| self.matches_app(pattern, window))
+            .map(|(_, lang)| lang.clone())
+    }
+
+    /// Whether the focused workspace and its output pass the --workspaces and
+    /// --outputs filters.
+    fn is_managed(&mut self) -> bool {
+        if self.config.workspaces.is_empty() && self.config.outputs.is_empty() {
+            return true;
+        }
+        let Some(workspace) = self.focused_workspace() else {
+            return true;
+        };
+        filter_allows(&self.config.workspaces, &workspace.name)
+            && filter_allows(&self.config.outputs, &workspace.output)
+    }
+
+    fn focused_workspace(&mut self) -> Option<Workspace> {
+        self.comm_conn
+            .get_workspaces()
+            .ok()?
+            .into_iter()
+            .find(|ws| ws.focused)
+    }
+
+    /// Key the focused window's layout is remembered under: the window itself
+    /// or, with --mode workspace, the workspace it is on.
+    fn focus_key(&mut self, window: &Window) -> String {
+        if self.config.mode == Mode::Workspace {
+            if let Some(workspace) = self.focused_workspace() {
+                return workspace_key(&workspace.name);
+            }
+        }
+        self.make_map_key(window)
+    }
+
+    /// Windows outside the managed workspaces/outputs are left alone: the
+    /// previous window keeps its memory and nothing is switched.
+    fn on_unmanaged_focus(&mut self) {
+        if let Some(key) = self.prev_id.take() {
+            self.save_layout(key);
+        }
+        debug!("Focused window outside managed workspaces/outputs, leaving layout alone");
+    }
+
+    /// Password prompts always get the default layout (or the first one) and
+    /// are never remembered, so a non-Latin layout cannot break password entry.
+    fn on_secure_focus(&mut self) {
+        if let Some(key) = self.prev_id.take() {
+            self.save_layout(key);
+        }
+        info!("Focused a password prompt, forcing the default layout");
+        if !self.apply_default_lang() {
+            self.switch_layouts(&[(ALL_KEYBOARDS.to_string(), 0)]);
+        }
+    }
+
+    /// Layout forced by --ssh-layout for the host the window is connected to.
+    fn ssh_layout(&self, window: &Window) -> Option<(String, String)> {
+        if self.config.ssh_layouts.is_empty() {
+            return None;
+        }
+        let host = ssh::host(window.title.as_deref(), window.pid)?;
+        self.config
+            .ssh_layouts
+            .iter()
+            .find(|(pattern, _)| glob::matches(pattern, &host))
+            .map(|(_, lang)| (host, lang.clone()))
+    }
+
+    /// Like a password prompt, an ssh session to a configured host is not
+    /// remembered: it always gets its layout and leaves the terminal's own
+    /// memory untouched.
+    fn on_ssh_focus(&mut self, host: &str, lang: &str) {
+        if let Some(key) = self.prev_id.take() {
+            self.save_layout(key);
+        }
+        info!("Focused an ssh session to {host}, switching to {lang}");
+        let targets = self.layout_targets(lang);
+        self.switch_layouts(&targets);
+    }
+
+    fn _get_lang(&mut self) -> persist::Layouts {
+        let mut input_map = persist::Layouts::default();
+        for input in self.keyboards() {
+            // Briefly missing while sway reloads its input config.
+            let Some(lo_idx) = input.xkb_active_layout_index else {
+                debug!("{} has no active layout, skipping", input.identifier);
+                continue;
+            };
+            if self.config.compat == Some(Compat::Swaykbdd) || self.config.sync_inputs {
+                input_map.insert(ALL_KEYBOARDS.to_string(), StoredLayout::of(&input, lo_idx));
+                break;
+            }
+            input_map
+                .entry(self.storage_id(&input))
+                .or_insert_with(|| StoredLayout::of(&input, lo_idx));
+        }
+        input_map
+    }
+
+    fn logical_app_id<'a>(&'a self, app_id: &'a str) -> &'a str {
+        match self.config.app_aliases.get(app_id) {
+            Some(alias) => alias,
+            None => app_id,
+        }
+    }
+
+    fn same_app(&self, a: &str, b: &str) -> bool {
+        let (a, b) = (self.logical_app_id(a), self.logical_app_id(b));
+        if a.eq_ignore_ascii_case(b) {
+            return true;
+        }
+        // Flatpak app_ids are reverse-DNS (org.mozilla.firefox); treat the last
+        // segment as the native app_id when only one side is reverse-DNS.
+        let native = |id: &str| id.matches('.').count() >= 2;
+        match (native(a), native(b)) {
+            (true, false) => a.rsplit('.').next().unwrap().eq_ignore_ascii_case(b),
+            (false, true) => b.rsplit('.').next().unwrap().eq_ignore_ascii_case(a),
+            _ => false,
+        }
+    }
+
+    fn is_tabbed(&self, app_id: &str) -> bool {
+        self.config
+            .tabbed
+            .iter()
+            .any(|tabbed| self.same_app(tabbed, app_id))
+    }
+
+    /// The stable part of a tabbed app's title, e.g. without an unread
+    /// counter. Titles the app's --tabbed-key does not match are used whole.
+    fn title_key<'a>(&self, app_id: &str, title: &'a str) -> &'a str {
+        let Some((_, regex)) = self
+            .config
+            .tabbed_keys
+            .iter()
+            .find(|(app, _)| self.same_app(app, app_id))
+        else {
+            return title;
+        };
+        match regex.captures(title) {
+            Some(captures) => captures
+                .get(1)
+                .or_else(|| captures.get(0))
+                .map_or(title, |m| m.as_str()),
+            None => title,
+        }
+    }
+
+    /// Whether a title change of this window can change its key.
+    fn tracks_title(&self, node: &Node) -> bool {
+        if self.config.mode != Mode::Window || !node.focused {
+            return false;
+        }
+        match self.key_by(&Window::from(node)) {
+            KeyBy::Window => {
+                node.app_id.as_deref().is_some_and(|id| self.is_tabbed(id))
+                    || node.pid.is_some_and(|pid| self.tabs.contains_key(&pid))
+            }
+            KeyBy::App => false,
+            KeyBy::AppTitle => true,
+        }
+    }
+
+    /// --key-by strategy for the window; windows without an app_id or class
+    /// can only be keyed by themselves.
+    fn key_by(&self, window: &Window) -> KeyBy {
+        if window.app_id.is_none() && window.class.is_none() {
+            return KeyBy::Window;
+        }
+        self.config
+            .app_key_by
+            .iter()
+            .find(|(pattern, _)| self.matches_app(pattern, window))
+            .map_or(self.config.key_by, |(_, key_by)| *key_by)
+    }
+
+    /// Site of the active tab, for browser windows the extension reports.
+    /// A browser process has many windows, told apart by the tab title
+    /// their own title starts with.
+    fn tab_site(&self, window: &Window) -> Option<&str> {
+        let tabs = self.tabs.get(&window.pid?)?;
+        let title = window.title.as_deref().unwrap_or_default();
+        let tab = match tabs.as_slice() {
+            [tab] => tab,
+            tabs => tabs.iter().rev().find(|tab| shows(title, &tab.title))?,
+        };
+        Some(tab.site.as_str()).filter(|site| !site.is_empty())
+    }
+
+    fn make_map_key(&self, window: &Window) -> String {
+        if let Some(mark) = self.group_mark(window) {
+            return format!("{MARK_KEY}{mark}");
+        }
+        let app = window.app_id.as_ref().or(window.class.as_ref());
+        match (self.key_by(window), app) {
+            (KeyBy::App, Some(app)) => return format!("{APP_KEY}{app}"),
+            (KeyBy::AppTitle, Some(app)) => {
+                let title = window.title.as_deref().unwrap_or_default();
+                let part = match self.tab_site(window) {
+                    Some(site) => site,
+                    None => self.title_key(app, title),
+                };
+                return format!("{APP_KEY}{app}:{part}");
+            }
+            _ => {}
+        }
+        let mut key = window.id.to_string();
+        if let Some(site) = self.tab_site(window) {
+            key.push_str(site);
+        } else if let Some(app_id) = &window.app_id {
+            if self.is_tabbed(app_id) {
+                if let Some(name) = &window.title {
+                    key.push_str(self.title_key(app_id, name))
+                }
+            }
+        }
+        key
+    }
+}
+
+/// A window as `watch` names it, e.g. `firefox#1421`, or the memory key of
+/// a workspace.
+fn watch_name(window: &Window, key: &str) -> String {
+    match (window.id, window.app_id.as_ref().or(window.class.as_ref())) {
+        (0, _) => key.to_string(),
+        (id, Some(app)) => format!("{app}#{id}"),
+        (id, None) => format!("#{id}"),
+    }
+}
+
+/// Whether a browser window titled `window_title` shows the tab titled
+/// `tab_title`, e.g. `vk.com — Mozilla Firefox` and `vk.com`.
+fn shows(window_title: &str, tab_title: &str) -> bool {
+    !tab_title.is_empty() && window_title.starts_with(tab_title)
+}
+
+/// Glob list where `!pattern` entries exclude. Without any positive entry
+/// everything not excluded is allowed.
+fn filter_allows(filter: &[String], name: &str) -> bool {
+    let mut included = None;
+    for pattern in filter {
+        match pattern.strip_prefix('!') {
+            Some(pattern) if glob::matches(pattern, name) => return false,
+            Some(_) => {}
+            None => {
+                included = Some(included.unwrap_or(false) || glob::matches(pattern, name));
+            }
+        }
+    }
+    included.unwrap_or(true)
+}
+
+fn suspended_for() -> Duration {
+    let clock = |id| {
+        let mut ts = libc::timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        };
+        unsafe { libc::clock_gettime(id, &mut ts) };
+        Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32)
+    };
+    clock(libc::CLOCK_BOOTTIME).saturating_sub(clock(libc::CLOCK_MONOTONIC))
+}
+
+/// Memory key prefix in --mode workspace.
+const WORKSPACE_KEY: &str = "workspace:";
+/// Prefix of keys shared by the windows carrying a --group-marks mark.
+const MARK_KEY: &str = "mark:";
+/// Prefix of keys shared by the windows of an app with --key-by app.
+const APP_KEY: &str = "app:";
+
+/// Keys not owned by a single window, which outlive its close.
+fn is_shared_key(key: &str) -> bool {
+    key.starts_with(MARK_KEY) || key.starts_with(APP_KEY)
+}
+
+fn workspace_key(name: &str) -> String {
+    format!("{WORKSPACE_KEY}{name}")
+}
+
+fn short_layout_tag(layout: &str) -> String {
+    layout
+        .split(|c: char| !c.is_alphanumeric())
+        .find(|word| !word.is_empty())
+        .unwrap_or(layout)
+        .chars()
+        .take(2)
+        .flat_map(char::to_uppercase)
+        .collect()
+}
+
+fn normalize_layout_name(name: &str) -> String {
+    let spaced = name.replace('(', " (").replace(')', ") ");
+    spaced
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase()
+        .replace("( ", "(")
+        .replace(" )", ")")
+}
+
+fn layout_name_eq(sway_name: &str, wanted: &str) -> bool {
+    sway_name == wanted || normalize_layout_name(sway_name) == normalize_layout_name(wanted)
+}
+
+/// Finds a layout given as it is named by sway ("Russian (phonetic)"), as
+/// an xkb code ("ru", "us(dvorak)") or as whole words of the name
+/// ("phonetic"). Words naming several layouts name none, rather than
+/// whichever comes first.
+fn find_layout(names: &[String], wanted: &str) -> Option<usize> {
+    if let Some(lo_idx) = names.iter().position(|name| layout_name_eq(name, wanted)) {
+        return Some(lo_idx);
+    }
+    for description in xkb::descriptions(wanted) {
+        if let Some(lo_idx) = names.iter().position(|name| name == description) {
+            return Some(lo_idx);
+        }
+    }
+    let words = |text: &str| -> Vec<String> {
+        text.to_lowercase()
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|word| !word.is_empty())
+            .map(str::to_string)
+            .collect()
+    };
+    let wanted_words = words(wanted);
+    if wanted_words.is_empty() {
+        return None;
+    }
+    let matching: Vec<usize> = names
+        .iter()
+        .enumerate()
+        .filter(|(_, name)| {
+            let name = words(name);
+            name.windows(wanted_words.len())
+                .any(|window| window == wanted_words)
+        })
+        .map(|(lo_idx, _)| lo_idx)
+        .collect();
+    match matching[..] {
+        [lo_idx] => Some(lo_idx),
+        [] => None,
+        _ => {
+            let names: Vec<&str> = matching.iter().map(|&lo_idx| &*names[lo_idx]).collect();
+            warn!(
+                "Layout {wanted} could be any of {}, give its full name",
+                names.join(", ")
+            );
+            None
+        }
+    }
+}
+
+fn is_leaf(node: &Node) -> bool {
+    node.pid.is_some() || node.app_id.is_some() || node.window_properties.is_some()
+}
+
+/// The cgroup of a process, e.g.
+/// `/user.slice/user-1000.slice/user@1000.service/app.slice/app-flatpak-org.mozilla.firefox-4242.scope`:
+/// the unified hierarchy's, or on cgroup v1 systemd's.
+fn cgroup(pid: i32) -> Option<String> {
+    let cgroups = fs::read_to_string(format!("/proc/{pid}/cgroup")).ok()?;
+    // Lines are <id>:<controllers>:<path>.
+    let hierarchies: Vec<(&str, &str)> = cgroups
+        .lines()
+        .filter_map(|line| line.split_once(':')?.1.split_once(':'))
+        .collect();
+    ["", "name=systemd"].into_iter().find_map(|wanted| {
+        hierarchies
+            .iter()
+            .find(|(controllers, _)| *controllers == wanted)
+            .map(|(_, path)| path.to_string())
+    })
+}
+
+/// Whether the window belongs to the scratchpad, shown or not.
+fn in_scratchpad(node: &Node) -> bool {
+    matches!(
+        node.scratchpad_state,
+        Some(ScratchpadState::Fresh | ScratchpadState::Changed)
+    )
+}
+
+/// A scratchpad window just sent back to the scratchpad.
+fn is_hidden_scratchpad(node: &Node) -> bool {
+    in_scratchpad(node) && node.visible == Some(false)
+}
+
+/// Split/parent containers (e.g. after `focus parent`) are not windows. Walk
+/// their focus stack down to the view that was focused last inside them.
+fn focused_leaf(mut node: Node) -> Option<Node> {
+    while !is_leaf(&node) {
+        let id = *node.focus.first()?;
+        node = node
+            .nodes
+            .into_iter()
+            .chain(node.floating_nodes)
+            .find(|child| child.id == id)?;
+    }
+    Some(node)
+}
+
+/// A restarted sway listens on a new socket, named after its pid, so the
+/// SWAYSOCK inherited from the old instance points nowhere. Picks the newest
+/// socket of this user next to it.
+fn restarted_swaysock() -> Option<PathBuf> {
+    let current = compositor::socket()?;
+    if current.exists() {
+        return None;
+    }
+    let dir = current
+        .parent()
+        .map(Path::to_path_buf)
+        .unwrap_or_else(paths::xdg_runtime_dir);
+    let prefix = format!("sway-ipc.{}.", unsafe { libc::getuid() });
+    std::fs::read_dir(dir)
+        .ok()?
+        .flatten()
+        .filter(|entry| {
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            name.starts_with(&prefix) && name.ends_with(".sock")
+        })
+        .filter_map(|entry| Some((entry.metadata().ok()?.modified().ok()?, entry.path())))
+        .max()
+        .map(|(_, path)| path)
+}
+
+/// Retries connecting with exponential backoff after the event connection
+/// dropped, e.g. because sway restarted. Remembered layouts are kept; gives
+/// up after five minutes.
+fn reconnect(state: &mut LayoutState) -> Result<(), Error> {
+    let deadline = Instant::now() + Duration::from_secs(300);
+    let mut delay = Duration::from_millis(250);
+    loop {
+        std::thread::sleep(delay);
+        if let Some(sock) = restarted_swaysock() {
+            info!("Sway socket moved to {}", sock.display());
+            compositor::socket_moved(sock);
+        }
+        match compositor::connect() {
+            Ok(conn) => {
+                info!("Reconnected to sway-ipc: {:?}", conn);
+                state.reconnected(Box::new(conn));
+                return Ok(());
+            }
+            Err(err) if Instant::now() < deadline => {
+                warn!("Cannot reconnect to sway: {err}, retrying in {delay:?}");
+                delay = (delay * 2).min(Duration::from_secs(30));
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Applies --sandbox. Must run before any thread is spawned, see
+/// `sandbox::apply`.
+fn apply_sandbox() -> std::io::Result<()> {
+    xkb::preload();
+    let state_dir = paths::state_dir();
+    let _ = std::fs::create_dir_all(&state_dir);
+    let sway_dir = env::var_os("SWAYSOCK")
+        .map(std::path::PathBuf::from)
+        .and_then(|sock| sock.parent().map(|dir| dir.to_path_buf()))
+        .unwrap_or_else(paths::xdg_runtime_dir);
+    let mut write = vec![state_dir.as_path()];
+    // Rotation creates a new file next to it.
+    if let Some(log_dir) = span::log_file().and_then(|file| file.parent()) {
+        write.push(log_dir);
+    }
+    let runtime_dir = paths::runtime_dir();
+    sandbox::apply(
+        &[
+            &sway_dir,
+            &paths::config_home(),
+            Path::new("/proc"),
+            Path::new("/dev"),
+        ],
+        &write,
+        // For the control socket.
+        &[&runtime_dir],
+    )
+}
+
+fn event_loop(state: &mut LayoutState, cli: &Matches) -> Result<(), Error> {
+    let mut subscriptions = vec![EventType::Window, EventType::Input, EventType::Tick];
+    if state.config.mode == Mode::Workspace || state.config.workspace_fallback {
+        subscriptions.push(EventType::Workspace);
+    }
+    let events = state.comm_conn.subscribe(&subscriptions)?;
+    state.track_workspaces();
+    systemd::notify("READY=1");
+    for event in events {
+        let event = match event {
+            Ok(event) => event,
+            Err(err) if error::is_unreadable_event(&err) => {
+                warn!("Skipping an event that cannot be read: {err}");
+                continue;
+            }
+            Err(err) => return Err(err),
+        };
+        if let Event::Tick(tick) = &event {
+            if tick_command(&tick.payload) == Some("reload") {
+                systemd::notify("RELOADING=1");
+                let config = Settings::load(cli).and_then(|settings| {
+                    settings.warn_skipped();
+                    build_config(&settings).map_err(|err| settings.explain(err))
+                });
+                match config {
+                    Ok(config) => state.reload(config),
+                    Err(err) => error!("Not reloading the config: {err}"),
+                }
+                systemd::notify("READY=1");
+                continue;
+            }
+            if tick_command(&tick.payload) == Some("watchdog") {
+                systemd::notify("WATCHDOG=1");
+                continue;
+            }
+            if tick_command(&tick.payload) == Some("quit") {
+                info!("Shutting down");
+                systemd::notify("STOPPING=1");
+                return Ok(());
+            }
+        }
+        state.stats.event();
+        state.handle_event(event);
+        state.notify_status();
+        state.record_sizes();
+    }
+    Ok(())
+}
+
+fn start(config: Config, settings: Settings) -> Result<(), error::Fatal> {
+    let comm_conn = compositor::connect().map_err(error::Fatal::Connect)?;
+    let mut state = LayoutState::new(Box::new(comm_conn), config);
+    state.check_default_lang();
+    systemd::start_watchdog(own_tick("watchdog"));
+    state.watch_layers();
+    if let Ok(version) = state.comm_conn.get_version() {
+        crash::set_sway_version(version.human_readable);
+    }
+    let watchers = state.watchers.clone();
+    match control::listen(&paths::control_socket(), own_tick("control"), watchers) {
+        Ok(requests) => state.control = Some(requests),
+        Err(err) => warn!("Control socket not available: {err}"),
+    }
+    if let Some(addr) = &state.config.metrics {
+        if let Err(err) = state.stats.serve(addr) {
+            warn!("Cannot serve metrics on {addr}: {err}");
+        }
+    }
+    #[cfg(feature = "http")]
+    if let Some(addr) = settings.opt_str("http") {
+        if let Err(err) = http::serve(&addr) {
+            warn!("Cannot serve the HTTP API on {addr}: {err}");
+        }
+    }
+    info!("State: {:?}", state);
+    info!("Entering main event loop.");
+
+    while let Err(err) = event_loop(&mut state, &settings.cli) {
+        error!("Lost connection to sway: {err}, reconnecting");
+        state.persist(true);
+        if let Err(err) = reconnect(&mut state) {
+            state.clear_title_tag();
+            return Err(error::Fatal::Disconnected(err));
+        }
+    }
+    state.persist(true);
+    state.clear_title_tag();
+    if state.xkb_file.is_some() {
+        state.load_keymap(None);
+    }
+    if state.config.reset_on_exit {
+        state.reset_layouts();
+    }
+
+    info!("Main event loop finished.");
+    Ok(())
+}
+
+fn split_list(list: Option<String>) -> Vec<String> {
+    list.map(|list| {
+        list.split(',')
+            .filter(|item| !item.is_empty())
+            .map(str::to_string)
+            .collect()
+    })
+    .unwrap_or_default()
+}
+
+/// -v and -q step from the default of errors only; --log-level wins over
+/// both and RUST_LOG.
+fn log_options(settings: &Settings) -> span::LogOptions {
+    let level = match settings.opt_str("log-level") {
+        Some(level) => match level.parse::<log::LevelFilter>() {
+            Ok(level) => Some(level),
+            Err(_) => {
+                eprintln!(
+                    "Invalid --log-level {level}, \
+                         expected off, error, warn, info, debug or trace"
+                );
+                std::process::exit(1)
+            }
+        },
+        None if settings.opt_present("quiet") => Some(log::LevelFilter::Off),
+        None => None,
+    };
+    let verbosity = match settings.opt_count("verbose") {
+        0 => None,
+        1 => Some(log::LevelFilter::Info),
+        2 => Some(log::LevelFilter::Debug),
+        _ => Some(log::LevelFilter::Trace),
+    };
+    let json = match settings.opt_str("log-format").as_deref() {
+        None | Some("text") => false,
+        Some("json") => true,
+        Some(other) => {
+            eprintln!("Invalid --log-format {other}, expected text or json");
+            std::process::exit(1)
+        }
+    };
+    span::LogOptions {
+        plain: settings.opt_present("daemonize"),
+        level,
+        verbosity,
+        file: settings.opt_str("log-file").map(PathBuf::from),
+        json,
+    }
+}
+
+fn print_usage(program: &str, opts: Options) {
+    let brief = format!(
+        "Usage: {program} [options] [run]\n       {program} setup\n       \
+         {program} migrate [-- <options of the Python sway-xkb-switcher>]\n       \
+         {program} [options] simulate [--app_id <id>] [--class <class>] [--title <title>] [--workspace <ws>]\n       \
+         {program} status [--json] | set <window> <layout> | set-current <layout> | forget [<window>] | pause | resume | freeze | unfreeze | watch\n       \
+         {program} waybar\n       \
+         {program} native-host [manifest firefox|chromium [<extension-id>]]\n       \
+         {program} completions bash|zsh|fish\n       \
+         {program} gen-schema"
+    );
+    print!("{}", opts.usage(&brief));
+    println!(
+        "\nWhile running, control it with swaymsg -t send_tick \"{TICK_PREFIX}<command>\",\n\
+         where <command> is one of\n{}\n\
+         Append @<name> to reach only the instance with that --instance-name.\n\
+         Send lock and unlock around the screen locker, e.g. from swayidle.\n\n\
+         Apps in options are app_ids or classes, globs allowed; pid:<pid> and\n\
+         cgroup:<glob>, e.g. 'cgroup:*app-flatpak-org.mozilla.firefox-*', match\n\
+         the window's process instead.\n\n\
+         Every long option can also be set as an environment variable, e.g.\n\
+         {}DEFAULT_LANG=us, or in the config file; the command line wins.",
+        wrap(&TICK_COMMANDS.join(", "), "    ", 76),
+        config_file::ENV_PREFIX
+    );
+}
+
+/// `, did you mean <name>?` for the one of `names` closest to a misspelled
+/// `name`, or nothing.
+fn did_you_mean(name: &str, names: &[String]) -> String {
+    config_file::nearest(name, names.iter().map(String::as_str))
+        .map(|nearest| format!(", did you mean {nearest}?"))
+        .unwrap_or_default()
+}
+
+/// Breaks `text` into indented lines of at most `width` columns.
+fn wrap(text: &str, indent: &str, width: usize) -> String {
+    let mut lines = vec![];
+    let mut line = indent.to_string();
+    for word in text.split(' ') {
+        if line.len() > indent.len() && line.len() + 1 + word.len() > width {
+            lines.push(std::mem::replace(&mut line, indent.to_string()));
+        }
+        if line.len() > indent.len() {
+            line.push(' ');
+        }
+        line.push_str(word);
+    }
+    lines.push(line);
+    lines.join("\n")
+}
+
+/// Options from the command line, falling back to `SWAY_XKB_*` variables,
+/// then to the config file.
+#[derive(Debug, Clone)]
+struct Settings {
+    cli: Matches,
+    env: Option<Matches>,
+    file: Option<Matches>,
+    /// Why variables were left out of `env`, warned about once logging is
+    /// set up.
+    skipped: Vec<String>,
+}
+
+impl Settings {
+    /// Reads the environment and the config file named by --config, or the
+    /// default one if it exists.
+    fn load(cli: &Matches) -> Result<Settings, String> {
+        let (env, skipped) = config_file::from_env(&options())?;
+        let file = match cli.opt_str("config") {
+            Some(path) => match config_file::load(Path::new(&path), &options())? {
+                Some(file) => Some(file),
+                None => return Err(format!("config file {path} does not exist")),
+            },
+            None => config_file::load(&paths::config_file(), &options())?,
+        };
+        Ok(Settings {
+            cli: cli.clone(),
+            env,
+            file,
+            skipped,
+        })
+    }
+
+    /// Warns about the variables left out.
+    fn warn_skipped(&self) {
+        for skipped in &self.skipped {
+            warn!("Ignoring {skipped}");
+        }
+    }
+
+    /// Where options are looked up, first match wins.
+    fn layers(&self) -> impl Iterator<Item = &Matches> {
+        std::iter::once(&self.cli)
+            .chain(self.env.as_ref())
+            .chain(self.file.as_ref())
+    }
+
+    fn opt_str(&self, name: &str) -> Option<String> {
+        self.layers().find_map(|layer| layer.opt_str(name))
+    }
+
+    fn opt_strs(&self, name: &str) -> Vec<String> {
+        self.layers()
+            .map(|layer| layer.opt_strs(name))
+            .find(|strs| !strs.is_empty())
+            .unwrap_or_default()
+    }
+
+    fn opt_count(&self, name: &str) -> usize {
+        self.layers()
+            .map(|layer| layer.opt_count(name))
+            .find(|count| *count > 0)
+            .unwrap_or_default()
+    }
+
+    fn opt_present(&self, name: &str) -> bool {
+        self.layers().any(|layer| layer.opt_present(name))
+    }
+
+    /// Points an error about an option at the line of the config file
+    /// setting it, when that is where its value came from.
+    fn explain(&self, err: String) -> String {
+        let Some(name) = OPTIONS
+            .iter()
+            .map(|spec| spec.long)
+            .filter(|long| err.contains(&format!("--{long}")))
+            .max_by_key(|long| long.len())
+        else {
+            return err;
+        };
+        let from_file = self
+            .file
+            .as_ref()
+            .is_some_and(|file| file.opt_present(name))
+            && !self.cli.opt_present(name)
+            && !self.env.as_ref().is_some_and(|env| env.opt_present(name));
+        if !from_file {
+            return err;
+        }
+        let path = self
+            .cli
+            .opt_str("config")
+            .map_or_else(paths::config_file, PathBuf::from);
+        match config_file::position_of(&path, name) {
+            Some(at) => format!("{at}: {err}"),
+            None => err,
+        }
+    }
+}
+
+/// Options that cannot be combined, checked wherever they come from.
+const CONFLICTS: &[(&str, &str)] = &[
+    ("verbose", "quiet"),
+    ("group-inputs", "sync-inputs"),
+    ("compat", "sync-inputs"),
+    ("compat", "group-inputs"),
+    ("dry-run", "switch-chord"),
+    ("continuity", "workspace-fallback"),
+];
+
+fn check_conflicts(settings: &Settings) -> Result<(), String> {
+    for (a, b) in CONFLICTS {
+        if settings.opt_present(a) && settings.opt_present(b) {
+            return Err(format!("--{a} and --{b} cannot be used together"));
+        }
+    }
+    if settings.opt_str("mode").as_deref() == Some("workspace") {
+        for option in ["workspace-fallback", "group-marks"] {
+            if settings.opt_present(option) {
+                return Err(format!("--{option} only applies to --mode window"));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// How an option takes its value, i.e. which getopts call declares it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Arity {
+    Flag,
+    /// A switch counted when repeated, e.g. -vv.
+    FlagMulti,
+    Value,
+    /// A value per occurrence.
+    Multi,
+    /// A switch with an optional value.
+    FlagValue,
+}
+
+/// One command-line option, declared by `options()` and offered by the
+/// shell completions.
+#[derive(Debug)]
+struct OptSpec {
+    short: &'static str,
+    long: &'static str,
+    arity: Arity,
+    /// What the value looks like, empty for switches.
+    hint: &'static str,
+    /// A few words for completion menus.
+    summary: &'static str,
+    help: &'static str,
+}
+
+const OPTIONS: &[OptSpec] = &[
+    OptSpec {
+        short: "D",
+        long: "default-lang",
+        arity: Arity::Multi,
+        hint: "[input_glob=]<xkb_layout_name>",
+        summary: "Default layout, optionally per keyboard",
+        help: "Set default language to use. Check man sway-ipc for more info on \
+             <xkb_layout_name>; xkb codes like 'ru' or 'us(dvorak)' and parts of the name work \
+             too. Entries like 'ZSA_*=Russian' set it per keyboard by input identifier glob. \
+             Repeatable.",
+    },
+    OptSpec {
+        short: "T",
+        long: "tabbed-apps",
+        arity: Arity::Value,
+        hint: "[app_ids ...]",
+        summary: "Apps whose tabs each keep a layout",
+        help: "Set tabbed apps list.",
+    },
+    OptSpec {
+        short: "",
+        long: "tabbed-key",
+        arity: Arity::Multi,
+        hint: "<app>=<regex>",
+        summary: "Regex picking the stable part of a tabbed app's title",
+        help: "Key a tabbed app's tabs by the first capture group of a regex on the title, e.g. \
+             firefox='^(?:\\(\\d+\\) )?(.*?)(?: — Mozilla Firefox)?$'. Repeatable.",
+    },
+    OptSpec {
+        short: "",
+        long: "mode",
+        arity: Arity::Value,
+        hint: "window|workspace",
+        summary: "Remember a layout per window or per workspace",
+        help: "Remember a layout per window (default) or per workspace.",
+    },
+    OptSpec {
+        short: "",
+        long: "key-by",
+        arity: Arity::Value,
+        hint: "[app=]window|app|app+title[,...]",
+        summary: "Remember layouts per window, app or app and title",
+        help: "Remember layouts per window (default), per app, so new windows start with the \
+             app's last layout, or per app and title. Entries like 'org.telegram.desktop=app' \
+             choose per app (globs allowed).",
+    },
+    OptSpec {
+        short: "C",
+        long: "continuity",
+        arity: Arity::Flag,
+        hint: "",
+        summary: "Keep the current layout in windows without memory",
+        help: "Windows without a remembered layout keep the current layout instead of the default.",
+    },
+    OptSpec {
+        short: "",
+        long: "workspace-fallback",
+        arity: Arity::Flag,
+        hint: "",
+        summary: "Give windows without memory their workspace's last layout",
+        help: "Windows without a remembered layout get the layout last used on their workspace \
+             instead of the default.",
+    },
+    OptSpec {
+        short: "",
+        long: "device-default",
+        arity: Arity::Multi,
+        hint: "<input_glob>=<xkb_layout_name>",
+        summary: "Default layout while a matching keyboard is connected",
+        help: "Default language while a keyboard matching the glob is connected, e.g. \
+             '*ISO_Keyboard*=German'. Takes precedence over --default-lang. Repeatable.",
+    },
+    OptSpec {
+        short: "",
+        long: "force-layout",
+        arity: Arity::Multi,
+        hint: "<app>=<xkb_layout_name>",
+        summary: "Layout an app always gets",
+        help: "Apps (app_id or class, globs allowed) that always get this layout, whatever was \
+             typed in them before, e.g. 'foot=English (US)'. Repeatable.",
+    },
+    OptSpec {
+        short: "",
+        long: "xkb-file",
+        arity: Arity::Multi,
+        hint: "<app>=<path>",
+        summary: "Keymap file an app's windows get",
+        help: "Apps whose windows get the complete keymap of this .xkb file, e.g. \
+             'plover=/home/me/steno.xkb'; the regular layouts come back when focus leaves them. \
+             Layouts typed there are not remembered. Repeatable.",
+    },
+    OptSpec {
+        short: "",
+        long: "allowed-layouts",
+        arity: Arity::Multi,
+        hint: "<app>=<xkb_layout_name>|...",
+        summary: "Layouts an app is restricted to",
+        help: "Layouts apps are restricted to, separated by |; a remembered layout outside the \
+             list is replaced by the first one, e.g. 'code=English (US)|English (intl.)'. \
+             Repeatable.",
+    },
+    OptSpec {
+        short: "",
+        long: "app-default",
+        arity: Arity::Multi,
+        hint: "<app>=<xkb_layout_name>",
+        summary: "Layout for an app's windows without memory",
+        help: "Layout for apps without a remembered layout, instead of --default-lang. Repeatable.",
+    },
+    OptSpec {
+        short: "",
+        long: "ssh-layout",
+        arity: Arity::Multi,
+        hint: "<host_glob>=<xkb_layout_name>",
+        summary: "Layout for terminals running ssh to a host",
+        help: "Layout for terminals running ssh to a host matching the glob, read from the ssh \
+             process below the window or an 'ssh user@host' title, e.g. 'prod-*=us'. Repeatable.",
+    },
+    OptSpec {
+        short: "",
+        long: "group-inputs",
+        arity: Arity::Flag,
+        hint: "",
+        summary: "Treat keyboards with the same vendor and product as one",
+        help: "Treat keyboards with the same vendor and product id as one device.",
+    },
+    OptSpec {
+        short: "",
+        long: "sync-inputs",
+        arity: Arity::Flag,
+        hint: "",
+        summary: "Keep all keyboards on the same layout",
+        help: "Keep all keyboards on the same layout: one layout is remembered per window and \
+             switched with a single command.",
+    },
+    OptSpec {
+        short: "",
+        long: "revert-after",
+        arity: Arity::Value,
+        hint: "<minutes>",
+        summary: "Forget layouts of windows unfocused this long",
+        help: "Forget a window's layout after it has been unfocused for this many minutes.",
+    },
+    OptSpec {
+        short: "",
+        long: "debounce",
+        arity: Arity::Value,
+        hint: "<milliseconds>",
+        summary: "Only act on focus that stays this long",
+        help: "Only act on a focus change once focus stayed for this long, e.g. 100 with \
+             focus_follows_mouse, so windows swept over are neither saved nor restored.",
+    },
+    OptSpec {
+        short: "",
+        long: "max-entries",
+        arity: Arity::Value,
+        hint: "<n>",
+        summary: "Remember at most this many windows",
+        help: "Remember at most this many windows, forgetting the least recently focused (default \
+             1000, 0 for no limit).",
+    },
+    OptSpec {
+        short: "",
+        long: "workspaces",
+        arity: Arity::Value,
+        hint: "[workspaces ...]",
+        summary: "Only manage these workspaces",
+        help: "Only manage these workspaces (globs, prefix ! to exclude), e.g. '!9'.",
+    },
+    OptSpec {
+        short: "",
+        long: "outputs",
+        arity: Arity::Value,
+        hint: "[outputs ...]",
+        summary: "Only manage these outputs",
+        help: "Only manage these outputs (globs, prefix ! to exclude).",
+    },
+    OptSpec {
+        short: "",
+        long: "inputs",
+        arity: Arity::Value,
+        hint: "[input_globs ...]",
+        summary: "Only manage these keyboards",
+        help: "Only manage keyboards whose identifier matches (globs, prefix ! to exclude).",
+    },
+    OptSpec {
+        short: "",
+        long: "ignore-inputs",
+        arity: Arity::Value,
+        hint: "[input_globs ...]",
+        summary: "Never switch these keyboards",
+        help: "Never save or switch layouts of these keyboards, e.g. macro pads (globs).",
+    },
+    OptSpec {
+        short: "",
+        long: "no-persist",
+        arity: Arity::Flag,
+        hint: "",
+        summary: "Do not save layouts for the next start",
+        help: "Do not save remembered layouts to the state dir for the next start.",
+    },
+    OptSpec {
+        short: "",
+        long: "persist-user-only",
+        arity: Arity::Flag,
+        hint: "",
+        summary: "Save only layouts chosen by hand",
+        help: "Save only layouts switched to by hand (or with set) for the next start, not \
+             defaults windows merely kept, so those follow a changed --default-lang.",
+    },
+    OptSpec {
+        short: "",
+        long: "verify",
+        arity: Arity::Flag,
+        hint: "",
+        summary: "Check that sway applied each switch",
+        help: "Read layouts back after switching and retry once if sway ignored the switch.",
+    },
+    OptSpec {
+        short: "",
+        long: "dry-run",
+        arity: Arity::Flag,
+        hint: "",
+        summary: "Log switches instead of making them",
+        help: "Follow focus and log the xkb_switch_layout commands that would be sent, without \
+             switching anything.",
+    },
+    OptSpec {
+        short: "",
+        long: "switch-chord",
+        arity: Arity::Value,
+        hint: "<chord>",
+        summary: "Switch by typing this xkb group toggle",
+        help: "Switch layouts by typing this xkb group toggle through wtype instead of IPC \
+             commands, e.g. 'alt+Shift_L'.",
+    },
+    OptSpec {
+        short: "",
+        long: "app-alias",
+        arity: Arity::Value,
+        hint: "[app_id=alias ...]",
+        summary: "Treat app_ids as the same app",
+        help: "Treat app_ids as the same app, e.g. org.mozilla.firefox=firefox.",
+    },
+    OptSpec {
+        short: "",
+        long: "profile",
+        arity: Arity::FlagValue,
+        hint: "<file>",
+        summary: "Log span timings",
+        help: "Log span timings, optionally appending folded stacks for flamegraphs to <file>.",
+    },
+    OptSpec {
+        short: "v",
+        long: "verbose",
+        arity: Arity::FlagMulti,
+        hint: "",
+        summary: "Log more",
+        help: "Log more: once for info, twice for debug, three times for trace.",
+    },
+    OptSpec {
+        short: "q",
+        long: "quiet",
+        arity: Arity::Flag,
+        hint: "",
+        summary: "Do not log anything",
+        help: "Do not log anything, not even errors.",
+    },
+    OptSpec {
+        short: "",
+        long: "log-level",
+        arity: Arity::Value,
+        hint: "off|error|warn|info|debug|trace",
+        summary: "Log at this level",
+        help: "Log at this level, overriding -v, -q and RUST_LOG.",
+    },
+    OptSpec {
+        short: "",
+        long: "log-format",
+        arity: Arity::Value,
+        hint: "text|json",
+        summary: "Log as text or JSON",
+        help: "Log as text (default) or as one JSON object per line, with span fields such as \
+             window.id, window.app_id, input.id and input.layout.",
+    },
+    OptSpec {
+        short: "",
+        long: "log-file",
+        arity: Arity::Value,
+        hint: "<path>",
+        summary: "Log to this file",
+        help: "Log to this file instead of stderr, moving it to <path>.1 at 10 MiB.",
+    },
+    OptSpec {
+        short: "",
+        long: "on-switch",
+        arity: Arity::Value,
+        hint: "<command>",
+        summary: "Shell command run for every switch",
+        help: "Run this shell command for every keyboard the daemon switches, with LAYOUT_NAME, \
+             LAYOUT_INDEX, INPUT_ID and APP_ID set. With --sandbox only programs in the system \
+             directories can run.",
+    },
+    OptSpec {
+        short: "",
+        long: "layer-focus",
+        arity: Arity::Value,
+        hint: "ignore|default",
+        summary: "Handle launchers and other layer-shell surfaces",
+        help: "Notice when a layer-shell surface such as a launcher takes the keyboard, which \
+             sway reports only as a seat focusing nothing, by asking sway five times a \
+             second. With ignore the window gets its layout back after, whatever was typed \
+             there; with default the surface also gets the default layout meanwhile.",
+    },
+    OptSpec {
+        short: "",
+        long: "input-method",
+        arity: Arity::Value,
+        hint: "fcitx5|ibus",
+        summary: "Also remember the fcitx5 or IBus engine",
+        help: "Also remember the active fcitx5 or IBus engine per window, switched through \
+             fcitx5-remote or ibus.",
+    },
+    OptSpec {
+        short: "",
+        long: "im-apps",
+        arity: Arity::Value,
+        hint: "<app_id,...>",
+        summary: "Apps whose input-method engine is remembered",
+        help: "Only remember the input-method engine of these apps; others keep whatever engine \
+             is active.",
+    },
+    OptSpec {
+        short: "",
+        long: "notify",
+        arity: Arity::FlagValue,
+        hint: "notify-send|swayosd",
+        summary: "Show a popup on every switch",
+        help: "Show a popup when the daemon switches the layout, through notify-send (default) or \
+             swayosd. None is shown while mako or dunst is in do-not-disturb mode.",
+    },
+    OptSpec {
+        short: "",
+        long: "quiet-hours",
+        arity: Arity::Value,
+        hint: "<HH:MM-HH:MM>",
+        summary: "No popups between these times",
+        help: "Do not show --notify popups between these local times, e.g. 22:00-07:00.",
+    },
+    OptSpec {
+        short: "",
+        long: "reset-on-exit",
+        arity: Arity::Flag,
+        hint: "",
+        summary: "Switch to the default layout when stopped",
+        help: "Switch all keyboards to the default layout when stopped by SIGTERM or SIGINT.",
+    },
+    OptSpec {
+        short: "",
+        long: "replace",
+        arity: Arity::Flag,
+        hint: "",
+        summary: "Replace the running instance",
+        help: "Stop an instance already running in this session and take over.",
+    },
+    OptSpec {
+        short: "d",
+        long: "daemonize",
+        arity: Arity::Flag,
+        hint: "",
+        summary: "Detach from the terminal",
+        help: "Detach from the terminal, log to the state dir and write a pidfile.",
+    },
+    OptSpec {
+        short: "",
+        long: "compat",
+        arity: Arity::Value,
+        hint: "<name>",
+        summary: "Mimic another switcher",
+        help: "Mimic another switcher. swaykbdd: one layout per window for all keyboards, -D may \
+             be an index.",
+    },
+    OptSpec {
+        short: "",
+        long: "mqtt",
+        arity: Arity::Value,
+        hint: "<host:port>",
+        summary: "Publish the layout to an MQTT broker",
+        help: "Publish the active layout and focused app to an MQTT broker.",
+    },
+    OptSpec {
+        short: "",
+        long: "dbus",
+        arity: Arity::Flag,
+        hint: "",
+        summary: "Serve the layout on D-Bus",
+        help: "Own org.sway.XkbSwitcher on the session bus (org.sway.XkbSwitcher.<name> with \
+             --instance-name), with the focused window's Layout and AppId as properties that \
+             signal their changes, and Pause, Resume and Forget methods.",
+    },
+    OptSpec {
+        short: "",
+        long: "metrics",
+        arity: Arity::Value,
+        hint: "<host:port>",
+        summary: "Serve Prometheus metrics",
+        help: "Serve event, switch and error counters in the Prometheus text format over HTTP, \
+             e.g. on 127.0.0.1:9101.",
+    },
+    OptSpec {
+        short: "",
+        long: "http",
+        arity: Arity::Value,
+        hint: "<host:port>",
+        summary: "Serve a JSON API and a dashboard over HTTP",
+        help: "Serve GET /state, GET /focused, POST /set, /pause, /resume, /pin, /unpin and \
+             /forget as JSON over HTTP on a loopback address, e.g. 127.0.0.1:8765, with a \
+             dashboard of the remembered windows at /. Needs a build with the http feature.",
+    },
+    OptSpec {
+        short: "",
+        long: "mqtt-topic",
+        arity: Arity::Value,
+        hint: "<topic>",
+        summary: "MQTT topic to publish to",
+        help: "MQTT topic to publish to (default: sway-xkb-switcher).",
+    },
+    OptSpec {
+        short: "",
+        long: "ignore-apps",
+        arity: Arity::Value,
+        hint: "[app_ids ...]",
+        summary: "Apps whose focus is ignored",
+        help: "Apps whose focus is ignored entirely, e.g. launchers and on-screen keyboards; the \
+             previously focused window keeps its layout (globs allowed).",
+    },
+    OptSpec {
+        short: "",
+        long: "ignore-floating",
+        arity: Arity::Flag,
+        hint: "",
+        summary: "Ignore floating dialogs",
+        help: "Do not track floating windows other than scratchpad ones, e.g. file pickers; their \
+             parent keeps the layout it had when the dialog opened.",
+    },
+    OptSpec {
+        short: "",
+        long: "group-marks",
+        arity: Arity::Value,
+        hint: "[marks ...]",
+        summary: "Marks whose windows share a layout",
+        help: "Windows carrying a sway mark matching one of these globs, e.g. lang:*, share one \
+             layout per mark instead of one per window.",
+    },
+    OptSpec {
+        short: "",
+        long: "secure-apps",
+        arity: Arity::Value,
+        hint: "[app_ids ...]",
+        summary: "Apps that always get the default layout",
+        help: "Apps that always get the default layout and are never remembered (default: \
+             pinentry*, polkit agents, askpass helpers; empty to disable).",
+    },
+    OptSpec {
+        short: "",
+        long: "title-tag",
+        arity: Arity::Flag,
+        hint: "",
+        summary: "Show the layout in the window title",
+        help: "Show the active layout in the focused window's title, e.g. \"[EN]\".",
+    },
+    OptSpec {
+        short: "",
+        long: "sandbox",
+        arity: Arity::Flag,
+        hint: "",
+        summary: "Restrict filesystem and syscall access",
+        help: "Restrict filesystem access with Landlock and block unneeded syscalls with seccomp.",
+    },
+    OptSpec {
+        short: "",
+        long: "swaynag",
+        arity: Arity::Flag,
+        hint: "",
+        summary: "Show fatal errors with swaynag",
+        help: "Show fatal errors with swaynag.",
+    },
+    OptSpec {
+        short: "",
+        long: "state-dir",
+        arity: Arity::Value,
+        hint: "<dir>",
+        summary: "Directory for state, sockets and logs",
+        help: "Keep state, pidfile, sockets and logs in this directory.",
+    },
+    OptSpec {
+        short: "",
+        long: "instance-name",
+        arity: Arity::Value,
+        hint: "<name>",
+        summary: "Name of this instance, to run several side by side",
+        help: "Name of this instance, e.g. one per seat or for trying a config: instances \
+             with different names keep separate control sockets, state and lock files, \
+             and \"xkb-switcher:<command>@<name>\" ticks reach only the one so named. \
+             Subcommands such as status talk to the instance of this name.",
+    },
+    OptSpec {
+        short: "",
+        long: "namespace",
+        arity: Arity::Value,
+        hint: "<name>",
+        summary: "Keep files apart under this name",
+        help: "Prefix the names of the state, socket and lock files with this name, e.g. to \
+             try a config beside the usual one. Unlike --instance-name it does not change \
+             which ticks reach the daemon, and the two combine.",
+    },
+    OptSpec {
+        short: "c",
+        long: "config",
+        arity: Arity::Value,
+        hint: "<file>",
+        summary: "Config file to read",
+        help: "Read settings from this TOML file instead of \
+             $XDG_CONFIG_HOME/sway-xkb-switcher/config.toml. Command line options take precedence.",
+    },
+    OptSpec {
+        short: "h",
+        long: "help",
+        arity: Arity::Flag,
+        hint: "",
+        summary: "Print this help menu",
+        help: "Print this help menu",
+    },
+];
+
+fn options() -> Options {
+    let mut opts = Options::new();
+    opts.parsing_style(ParsingStyle::StopAtFirstFree);
+    for spec in OPTIONS {
+        let (short, long, hint, help) = (spec.short, spec.long, spec.hint, spec.help);
+        match spec.arity {
+            Arity::Flag => opts.optflag(short, long, help),
+            Arity::FlagMulti => opts.optflagmulti(short, long, help),
+            Arity::Value => opts.optopt(short, long, help, hint),
+            Arity::Multi => opts.optmulti(short, long, help, hint),
+            Arity::FlagValue => opts.optflagopt(short, long, help, hint),
+        };
+    }
+    opts
+}
+
+/// Parses a repeatable `pattern=layout` option, one pair per occurrence as
+/// layout names may contain commas.
+fn layout_pairs(
+    settings: &Settings,
+    name: &str,
+    expected: &str,
+) -> Result<Vec<(String, String)>, String> {
+    let mut pairs = vec![];
+    for pair in settings.opt_strs(name) {
+        match pair.split_once('=') {
+            Some((pattern, lang)) => pairs.push((pattern.to_string(), lang.to_string())),
+            None => return Err(format!("Invalid --{name} {pair}, expected {expected}")),
+        }
+    }
+    info!("{name}: {:?}", pairs);
+    Ok(pairs)
+}
+
+fn build_config(settings: &Settings) -> Result<Config, String> {
+    check_conflicts(settings)?;
+    if cfg!(not(feature = "http")) && settings.opt_present("http") {
+        return Err("--http needs a build with the http feature".into());
+    }
+    let mut default_lang = None;
+    let mut input_defaults = vec![];
+    // Layout names may contain commas, e.g. "English (US, intl., with dead
+    // keys)", so each entry is its own occurrence.
+    for entry in settings.opt_strs("default-lang") {
+        match entry.split_once('=') {
+            Some((pattern, lang)) => input_defaults.push((pattern.to_string(), lang.to_string())),
+            None if default_lang.is_none() => default_lang = Some(entry),
+            None => {
+                return Err(format!(
+                    "--default-lang {entry}: only one layout may be given without input_glob="
+                ))
+            }
+        }
+    }
+    info!("default-lang: {:?} {:?}", &default_lang, &input_defaults);
+
+    let mut tabbed_apps: Vec<String> = vec![];
+    if let Some(apps) = settings.opt_str("tabbed-apps") {
+        for app in apps.split(",") {
+            tabbed_apps.push(app.to_string())
+        }
+    }
+    info!("tabbed-apps: {:?}", tabbed_apps);
+
+    let mut tabbed_keys = vec![];
+    for entry in settings.opt_strs("tabbed-key") {
+        let Some((app, pattern)) = entry.split_once('=') else {
+            return Err(format!(
+                "Invalid --tabbed-key {entry}, expected <app>=<regex>"
+            ));
+        };
+        let regex = regex::Regex::new(pattern)
+            .map_err(|err| format!("Invalid --tabbed-key regex for {app}: {err}"))?;
+        tabbed_keys.push((app.to_string(), regex));
+    }
+    info!("tabbed-key: {:?}", tabbed_keys);
+
+    let mut app_aliases: FxHashMap<String, String> = FxHashMap::default();
+    if let Some(aliases) = settings.opt_str("app-alias") {
+        for alias in aliases.split(',') {
+            match alias.split_once('=') {
+                Some((app_id, name)) => {
+                    app_aliases.insert(app_id.to_string(), name.to_string());
+                }
+                None => {
+                    return Err(format!(
+                        "Invalid app alias {alias}, expected <app_id>=<alias>"
+                    ));
+                }
+            }
+        }
+    }
+    info!("app-alias: {:?}", app_aliases);
+
+    let device_defaults = layout_pairs(settings, "device-default", "<input_glob>=<layout>")?;
+    let ssh_layouts = layout_pairs(settings, "ssh-layout", "<host_glob>=<layout>")?;
+    let forced_layouts = layout_pairs(settings, "force-layout", "<app>=<layout>")?;
+    let xkb_files = layout_pairs(settings, "xkb-file", "<app>=<path>")?;
+    let app_defaults = layout_pairs(settings, "app-default", "<app>=<layout>")?;
+    let allowed_layouts = layout_pairs(settings, "allowed-layouts", "<app>=<layout>|...")?
+        .into_iter()
+        .map(|(app, langs)| (app, langs.split('|').map(str::to_string).collect()))
+        .collect();
+
+    let switch_chord = settings.opt_str("switch-chord");
+    if let Some(chord) = &switch_chord {
+        if let Err(err) = chord::validate(chord) {
+            return Err(format!("Invalid switch chord {chord}: {err}"));
+        }
+    }
+
+    let secure_apps: Vec<String> = match settings.opt_str("secure-apps") {
+        Some(apps) => apps
+            .split(',')
+            .filter(|app| !app.is_empty())
+            .map(str::to_string)
+            .collect(),
+        None => SECURE_APPS.iter().map(|app| app.to_string()).collect(),
+    };
+    info!("secure-apps: {:?}", secure_apps);
+    let ignored_apps = split_list(settings.opt_str("ignore-apps"));
+    info!("ignore-apps: {:?}", ignored_apps);
+
+    let compat = match settings.opt_str("compat").as_deref() {
+        None => None,
+        Some("swaykbdd") => Some(Compat::Swaykbdd),
+        Some(other) => {
+            return Err(format!("Unknown compat mode {other}"));
+        }
+    };
+    info!("compat: {:?}", compat);
+
+    let mut key_by = KeyBy::default();
+    let mut app_key_by = vec![];
+    for entry in settings
+        .opt_str("key-by")
+        .iter()
+        .flat_map(|list| list.split(','))
+    {
+        let (app, name) = match entry.split_once('=') {
+            Some((app, name)) => (Some(app), name),
+            None => (None, entry),
+        };
+        let Some(strategy) = KeyBy::parse(name) else {
+            return Err(format!(
+                "Invalid --key-by {entry}, expected window, app or app+title"
+            ));
+        };
+        match app {
+            Some(app) => app_key_by.push((app.to_string(), strategy)),
+            None => key_by = strategy,
+        }
+    }
+    info!("key-by: {key_by:?}, per app: {app_key_by:?}");
+
+    let mode = match settings.opt_str("mode").as_deref() {
+        None | Some("window") => Mode::Window,
+        Some("workspace") => Mode::Workspace,
+        Some(other) => {
+            return Err(format!(
+                "Unknown --mode {other}, expected window or workspace"
+            ))
+        }
+    };
+    info!("mode: {:?}", mode);
+
+    let revert_after = settings
+        .opt_str("revert-after")
+        .map(|minutes| match minutes.parse::<u64>() {
+            Ok(minutes) => Ok(Duration::from_secs(minutes * 60)),
+            Err(err) => Err(format!("Invalid --revert-after {minutes}: {err}")),
+        })
+        .transpose()?;
+    info!("revert-after: {:?}", revert_after);
+
+    let debounce = settings
+        .opt_str("debounce")
+        .map(|ms| match ms.parse::<u64>() {
+            Ok(0) => Ok(None),
+            Ok(ms) => Ok(Some(Duration::from_millis(ms))),
+            Err(err) => Err(format!("Invalid --debounce {ms}: {err}")),
+        })
+        .transpose()?
+        .flatten();
+    info!("debounce: {debounce:?}");
+
+    let input_method = match settings.opt_str("input-method") {
+        None => None,
+        Some(name) => match input_method::Framework::parse(&name) {
+            Some(framework) => Some(framework),
+            None => {
+                return Err(format!(
+                    "Invalid --input-method {name}, expected fcitx5 or ibus"
+                ))
+            }
+        },
+    };
+    info!("input-method: {input_method:?}");
+
+    let layer_focus = match settings.opt_str("layer-focus").as_deref() {
+        None => None,
+        Some("ignore") => Some(LayerFocus::Ignore),
+        Some("default") => Some(LayerFocus::Default),
+        Some(other) => {
+            return Err(format!(
+                "Invalid --layer-focus {other}, expected ignore or default"
+            ))
+        }
+    };
+    info!("layer-focus: {layer_focus:?}");
+
+    let notify = match settings.opt_present("notify") {
+        false => None,
+        true => {
+            let name = settings
+                .opt_str("notify")
+                .unwrap_or_else(|| "notify-send".to_string());
+            match notify::Notifier::parse(&name) {
+                Some(notifier) => Some(notifier),
+                None => {
+                    return Err(format!(
+                        "Invalid --notify {name}, expected notify-send or swayosd"
+                    ))
+                }
+            }
+        }
+    };
+
+    let quiet_hours = match settings.opt_str("quiet-hours") {
+        None => None,
+        Some(hours) => Some(
+            notify::QuietHours::parse(&hours)
+                .ok_or_else(|| format!("Invalid --quiet-hours {hours}, expected HH:MM-HH:MM"))?,
+        ),
+    };
+
+    let max_entries = match settings.opt_str("max-entries") {
+        Some(max) => max
+            .parse::<usize>()
+            .map_err(|err| format!("Invalid --max-entries {max}: {err}"))?,
+        None => MAX_ENTRIES,
+    };
+    info!("max-entries: {max_entries}");
+
+    Ok(Config {
+        default_lang,
+        input_defaults,
+        tabbed: tabbed_apps,
+        tabbed_keys,
+        app_aliases,
+        compat,
+        mode,
+        key_by,
+        app_key_by,
+        continuity: settings.opt_present("continuity"),
+        workspace_fallback: settings.opt_present("workspace-fallback"),
+        mqtt: settings.opt_str("mqtt"),
+        dbus: settings.opt_present("dbus"),
+        metrics: settings.opt_str("metrics"),
+        secure_apps,
+        ignored_apps,
+        ignore_floating: settings.opt_present("ignore-floating"),
+        group_marks: split_list(settings.opt_str("group-marks")),
+        sandbox: settings.opt_present("sandbox"),
+        title_tag: settings.opt_present("title-tag"),
+        device_defaults,
+        ssh_layouts,
+        forced_layouts,
+        xkb_files,
+        allowed_layouts,
+        app_defaults,
+        group_inputs: settings.opt_present("group-inputs"),
+        sync_inputs: settings.opt_present("sync-inputs"),
+        revert_after,
+        debounce,
+        max_entries,
+        workspaces: split_list(settings.opt_str("workspaces")),
+        outputs: split_list(settings.opt_str("outputs")),
+        inputs: split_list(settings.opt_str("inputs")),
+        ignore_inputs: split_list(settings.opt_str("ignore-inputs")),
+        verify: settings.opt_present("verify"),
+        dry_run: settings.opt_present("dry-run"),
+        state_file: (!settings.opt_present("no-persist")).then(paths::state_file),
+        persist_user_only: settings.opt_present("persist-user-only"),
+        switch_chord,
+        reset_on_exit: settings.opt_present("reset-on-exit"),
+        notify,
+        quiet_hours,
+        on_switch: settings.opt_str("on-switch"),
+        input_method,
+        im_apps: split_list(settings.opt_str("im-apps")),
+        layer_focus,
+        mqtt_topic: settings
+            .opt_str("mqtt-topic")
+            .unwrap_or_else(|| "sway-xkb-switcher".to_string()),
+    })
+}
+
+/// The command line entry point.
+pub fn run() {
+    let args: Vec<String> = env::args().collect();
+    let program = args[0].clone();
+
+    let opts = options();
+
+    let matches = match opts.parse(&args[1..]) {
+        Ok(m) => m,
+        Err(f) => {
+            span::init_logger(span::LogOptions::default());
+            error!("Error parsing opts {}", f);
+            std::process::exit(1)
+        }
+    };
+
+    let settings = Settings::load(&matches);
+    span::init_logger(match &settings {
+        Ok(settings) => log_options(settings),
+        Err(_) => log_options(&Settings {
+            cli: matches.clone(),
+            env: None,
+            file: None,
+            skipped: vec![],
+        }),
+    });
+    info!("Arguments: {:?}", args);
+    if let Ok(settings) = &settings {
+        settings.warn_skipped();
+    }
+
+    if matches.opt_present("h") {
+        print_usage(&program, opts);
+        return;
+    }
+
+    // Before the settings are checked, as setup may be what fixes them.
+    if matches.free.first().map(String::as_str) == Some("setup") {
+        let config = matches.opt_str("config").map(PathBuf::from);
+        if let Err(err) = setup::run(config.unwrap_or_else(paths::config_file)) {
+            error!("Setup failed: {err}");
+            std::process::exit(1)
+        }
+        return;
+    }
+    if matches.free.first().map(String::as_str) == Some("gen-schema") {
+        println!("{:#}", config_file::schema());
+        return;
+    }
+    if matches.free.first().map(String::as_str) == Some("migrate") {
+        let config = matches.opt_str("config").map(PathBuf::from);
+        let config = config.unwrap_or_else(paths::config_file);
+        if let Err(err) = migrate::run(config, &matches.free[1..]) {
+            error!("Migration failed: {err}");
+            std::process::exit(1)
+        }
+        return;
+    }
+
+    let settings = match settings {
+        Ok(settings) => settings,
+        Err(err) => {
+            error!("{err}");
+            std::process::exit(1)
+        }
+    };
+
+    paths::configure(
+        settings.opt_str("state-dir").map(PathBuf::from),
+        settings.opt_str("namespace"),
+        settings.opt_str("instance-name"),
+    );
+
+    if settings.opt_present("profile") {
+        let folded = settings.opt_str("profile").map(|path| {
+            File::options()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .unwrap_or_else(|err| {
+                    error!("Cannot open profile output {path}: {err}");
+                    std::process::exit(1)
+                })
+        });
+        span::enable_profiling(folded);
+    }
+
+    let subcommand = matches.free.first().map(String::as_str);
+    if subcommand.is_some_and(|command| client::COMMANDS.contains(&command)) {
+        if let Err(err) = client::run(&matches.free) {
+            error!("{err}");
+            std::process::exit(1)
+        }
+        return;
+    }
+    if subcommand == Some("completions") {
+        let program = Path::new(&program)
+            .file_name()
+            .map_or(program.clone(), |name| name.to_string_lossy().into_owned());
+        let shell = matches.free.get(1).map(String::as_str).unwrap_or_default();
+        match completions::generate(shell, &program) {
+            Ok(script) => print!("{script}"),
+            Err(err) => {
+                error!("{err}");
+                std::process::exit(1)
+            }
+        }
+        return;
+    }
+    if subcommand == Some("native-host") || native_host::launched_by_browser(&matches.free) {
+        let result = match &matches.free[..] {
+            [_, manifest, browser, extension @ ..] if manifest == "manifest" => {
+                native_host::manifest(browser, extension.first().map(String::as_str))
+                    .map(|manifest| println!("{manifest:#}"))
+            }
+            [_, manifest, ..] if manifest == "manifest" => Err(format!(
+                "expected native-host manifest {} [<extension-id>]",
+                native_host::BROWSERS.join("|")
+            )),
+            _ => native_host::run().map_err(|err| err.to_string()),
+        };
+        if let Err(err) = result {
+            error!("{err}");
+            std::process::exit(1)
+        }
+        return;
+    }
+    if subcommand == Some("waybar") {
+        if let Err(err) = waybar::run() {
+            error!("{err}");
+            std::process::exit(1)
+        }
+        return;
+    }
+    if !matches!(subcommand, None | Some("run" | "simulate")) {
+        error!("Unknown subcommand {:?}", matches.free[0]);
+        print_usage(&program, opts);
+        std::process::exit(1)
+    }
+
+    let config = match build_config(&settings).map_err(|err| settings.explain(err)) {
+        Ok(config) => config,
+        Err(err) => {
+            error!("{err}");
+            std::process::exit(1)
+        }
+    };
+
+    if matches.free.first().map(String::as_str) == Some("simulate") {
+        if let Err(err) = simulate::run(config, &matches.free[1..]) {
+            error!("Simulation failed: {err}");
+            std::process::exit(1)
+        }
+        return;
+    }
+
+    let mut lock = match instance::acquire(&paths::lock_file(), matches.opt_present("replace")) {
+        Ok(lock) => lock,
+        Err(err) => {
+            error!("Not starting: {err}");
+            std::process::exit(1)
+        }
+    };
+
+    if settings.opt_present("daemonize") {
+        if let Err(err) = daemon::daemonize(&paths::log_file(), &paths::pid_file()) {
+            error!("Failed to daemonize: {err}");
+            std::process::exit(1)
+        }
+        if let Err(err) = lock.write_pid() {
+            warn!("Cannot update the lock file: {err}");
+        }
+    }
+
+    if settings.opt_present("swaynag") {
+        let log_file = settings.opt_present("daemonize").then(paths::log_file);
+        nag::enable(log_file);
+    }
+
+    crash::install_hook();
+    crash::set_summary(format!("{:?}", config));
+    // Before the first thread, so that all of them are sandboxed.
+    if config.sandbox {
+        if let Err(err) = apply_sandbox() {
+            error!("Not starting unsandboxed: {err}");
+            std::process::exit(1)
+        }
+    }
+    let quit = own_tick("quit");
+    if let Err(err) = signals::install(vec![
+        (libc::SIGHUP, own_tick("reload")),
+        (libc::SIGUSR1, own_tick("dump")),
+        (libc::SIGTERM, quit.clone()),
+        (libc::SIGINT, quit),
+    ]) {
+        warn!("Cannot handle SIGHUP, SIGUSR1 and SIGTERM through the event loop: {err}");
+    }
+    if let Err(err) = start(config, settings) {
+        error!("{err}");
+        nag::fatal(&err.to_string());
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf};
+
+    use super::*;
+    use crate::compositor::Mock;
+
+    const KBD: &str = "1:1:Keyboard";
+
+    fn config(args: &[&str]) -> Config {
+        let mut args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        args.push("--no-persist".to_string());
+        Config::from_args(&args).unwrap()
+    }
+
+    fn daemon(config: Config) -> (LayoutState, Mock) {
+        let mock = Mock::with_keyboards(&[(KBD, &["English (US)", "Russian"])]);
+        (LayoutState::new(Box::new(mock.clone()), config), mock)
+    }
+
+    fn window(id: i64, app_id: &str, title: &str) -> Window {
+        Window {
+            id,
+            app_id: Some(app_id.to_string()),
+            class: None,
+            title: Some(title.to_string()),
+            pid: None,
+            marks: vec![],
+            floating: false,
+        }
+    }
+
+    fn focus(state: &mut LayoutState, window: &Window) {
+        let key = state.make_map_key(window);
+        state.on_focus(window, &key);
+        // What sway's input events would tell the daemon.
+        state.refresh_inputs();
+    }
+
+    fn type_layout(state: &mut LayoutState, mock: &Mock, index: i32) {
+        let input = mock.type_layout(KBD, index);
+        state.on_input(&input);
+        state.on_layout_change(&input);
+    }
+
+    fn last_switch(mock: &Mock) -> Option<(String, i32)> {
+        mock.switches().pop()
+    }
+
+    fn temp_state_file(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "sway-xkb-switcher-test-{}-{name}.json",
+            std::process::id()
+        ))
+    }
+
+    #[test]
+    fn focus_restores_the_layout_typed_in_a_window() {
+        let (mut state, mock) = daemon(config(&["-D", "English (US)"]));
+        let (terminal, browser) = (window(1, "foot", "~"), window(2, "firefox", "vk.com"));
+        focus(&mut state, &browser);
+        type_layout(&mut state, &mock, 1);
+        focus(&mut state, &terminal);
+        assert_eq!(last_switch(&mock), Some((KBD.to_string(), 0)));
+        focus(&mut state, &browser);
+        assert_eq!(last_switch(&mock), Some((KBD.to_string(), 1)));
+        assert_eq!(state.state["2"][KBD].name, "Russian");
+    }
+
+    #[test]
+    fn windows_without_memory_get_the_default_layout() {
+        let (mut state, mock) = daemon(config(&["-D", "Russian"]));
+        focus(&mut state, &window(1, "foot", "~"));
+        assert_eq!(last_switch(&mock), Some((KBD.to_string(), 1)));
+    }
+
+    #[test]
+    fn close_forgets_the_window() {
+        let (mut state, mock) = daemon(config(&[]));
+        focus(&mut state, &window(1, "foot", "~"));
+        type_layout(&mut state, &mock, 1);
+        focus(&mut state, &window(2, "foot", "~"));
+        assert!(state.state.contains_key("1"));
+        state.on_close("1");
+        assert!(!state.state.contains_key("1"));
+        assert_eq!(state.prev_id.as_deref(), Some("2"));
+    }
+
+    #[test]
+    fn forget_all_drops_pins() {
+        let (mut state, mock) = daemon(config(&["-D", "English (US)"]));
+        let terminal = window(1, "foot", "~");
+        focus(&mut state, &terminal);
+        type_layout(&mut state, &mock, 1);
+        state.pin();
+        state.forget_all();
+        state.refresh_inputs();
+        assert_eq!(last_switch(&mock), Some((KBD.to_string(), 0)));
+        focus(&mut state, &window(2, "foot", "~"));
+        focus(&mut state, &terminal);
+        assert_eq!(last_switch(&mock), Some((KBD.to_string(), 0)));
+    }
+
+    #[test]
+    fn saved_layouts_survive_a_restart() {
+        let path = temp_state_file("restart");
+        let persisted = || Config {
+            state_file: Some(path.clone()),
+            ..config(&["-D", "English (US)"])
+        };
+
+        let (mut state, mock) = daemon(persisted());
+        focus(&mut state, &window(1, "foot", "notes"));
+        type_layout(&mut state, &mock, 1);
+        focus(&mut state, &window(2, "firefox", "vk.com"));
+        state.persist(true);
+
+        // Container ids change across restarts; app and title do not.
+        let (mut state, mock) = daemon(persisted());
+        focus(&mut state, &window(7, "foot", "notes"));
+        assert_eq!(last_switch(&mock), Some((KBD.to_string(), 1)));
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn layout_names_may_contain_commas() {
+        let config = config(&[
+            "-D",
+            "English (US, intl., with dead keys)",
+            "-D",
+            "ZSA_*=Russian",
+            "--app-default",
+            "foot=English (US, intl., with dead keys)",
+        ]);
+        assert_eq!(
+            config.default_lang.as_deref(),
+            Some("English (US, intl., with dead keys)")
+        );
+        assert_eq!(
+            config.input_defaults,
+            [("ZSA_*".to_string(), "Russian".to_string())]
+        );
+        assert_eq!(
+            config.app_defaults,
+            [(
+                "foot".to_string(),
+                "English (US, intl., with dead keys)".to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn ticks_addressed_to_another_instance_are_ignored() {
+        assert_eq!(tick_command("xkb-switcher:pause"), Some("pause"));
+        assert_eq!(tick_command("xkb-switcher:pause@default"), Some("pause"));
+        assert_eq!(tick_command("xkb-switcher:pause@seat1"), None);
+        assert_eq!(tick_command("pause"), None);
+        assert_eq!(tick_command(&own_tick("quit")), Some("quit"));
+    }
+
+    #[test]
+    fn host_sections_override_matching_machines_only() {
+        let path = temp_state_file("hosts").with_extension("toml");
+        fs::write(
+            &path,
+            "default-lang = \"English (US)\"\n\
+             [[host]]\nname = \"*\"\ndefault-lang = \"German\"\n\
+             [[host]]\nname = \"no-such-host\"\ndefault-lang = \"French\"\n",
+        )
+        .unwrap();
+        let file = config_file::load(&path, &options()).unwrap().unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(file.opt_strs("default-lang"), ["German"]);
+    }
+
+    #[test]
+    fn apps_match_by_pid_and_cgroup() {
+        let (state, _) = daemon(config(&[]));
+        let pid = std::process::id() as i32;
+        let own = Window {
+            pid: Some(pid),
+            ..window(1, "flatpak-app", "")
+        };
+        assert!(state.matches_app(&format!("pid:{pid}"), &own));
+        assert!(!state.matches_app("pid:1", &own));
+        assert!(state.matches_app("cgroup:/*", &own));
+        assert!(!state.matches_app("cgroup:/*", &window(2, "foot", "~")));
+    }
+
+    #[test]
+    fn included_rules_are_merged() {
+        let dir = temp_state_file("include").with_extension("d");
+        fs::create_dir_all(dir.join("rules.d")).unwrap();
+        fs::write(
+            dir.join("config.toml"),
+            "include = [\"rules.d/*.toml\"]\ndefault-lang = \"German\"\n\
+             app-default = { foot = \"English (US)\" }\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("rules.d/browsers.toml"),
+            "default-lang = \"French\"\napp-default = { firefox = \"Russian\" }\n",
+        )
+        .unwrap();
+        let file = config_file::load(&dir.join("config.toml"), &options());
+        fs::remove_dir_all(&dir).unwrap();
+        let file = file.unwrap().unwrap();
+        assert_eq!(file.opt_strs("default-lang"), ["German"]);
+        assert_eq!(
+            file.opt_strs("app-default"),
+            ["firefox=Russian", "foot=English (US)"]
+        );
+    }
+
+    #[test]
+    fn layouts_typed_while_frozen_are_not_saved() {
+        let (mut state, mock) = daemon(config(&["-D", "English (US)"]));
+        let (terminal, browser) = (window(1, "foot", "~"), window(2, "firefox", "vk.com"));
+        focus(&mut state, &terminal);
+        state.on_tick("xkb-switcher:freeze");
+        type_layout(&mut state, &mock, 1);
+        state.on_tick("xkb-switcher:unfreeze");
+        focus(&mut state, &browser);
+        focus(&mut state, &terminal);
+        assert_eq!(last_switch(&mock), Some((KBD.to_string(), 0)));
+    }
+
+    #[test]
+    fn renamed_workspaces_keep_their_layout() {
+        let (mut state, mock) = daemon(config(&["--mode", "workspace"]));
+        let workspace = Window::default();
+        state.on_focus(&workspace, &workspace_key("1"));
+        type_layout(&mut state, &mock, 1);
+        state.on_focus(&workspace, &workspace_key("2"));
+        state.refresh_inputs();
+        state.rename_workspace("1", "mail");
+        state.on_focus(&workspace, &workspace_key("mail"));
+        assert_eq!(last_switch(&mock), Some((KBD.to_string(), 1)));
+        assert!(!state.state.contains_key(&workspace_key("1")));
+    }
+
+    #[test]
+    fn migrate_carries_over_known_options() {
+        let args: Vec<String> = ["--default_lang", "English (US)", "-T", "firefox", "--bogus"]
+            .iter()
+            .map(|arg| arg.to_string())
+            .collect();
+        assert_eq!(
+            migrate::translate(&args).unwrap(),
+            "default-lang = [\"English (US)\"]\ntabbed-apps = \"firefox\"\n"
+        );
+    }
+
+    #[test]
+    fn xkb_files_are_loaded_while_their_app_is_focused() {
+        let (mut state, mock) = daemon(config(&["--xkb-file", "plover=/steno.xkb"]));
+        let (terminal, steno) = (window(1, "foot", "~"), window(2, "plover", ""));
+        focus(&mut state, &terminal);
+        type_layout(&mut state, &mock, 1);
+        focus(&mut state, &steno);
+        assert_eq!(
+            mock.commands().last().unwrap(),
+            &format!("input {KBD} xkb_file \"/steno.xkb\"")
+        );
+        focus(&mut state, &terminal);
+        assert!(mock.commands().contains(&format!("input {KBD} xkb_file -")));
+        assert_eq!(last_switch(&mock), Some((KBD.to_string(), 1)));
+    }
+
+    #[test]
+    fn control_pins_a_remembered_window() {
+        let (mut state, mock) = daemon(config(&["-D", "English (US)"]));
+        let (terminal, browser) = (window(1, "foot", "~"), window(2, "firefox", "vk.com"));
+        state.rekey_on_app_change(&terminal, "1");
+        focus(&mut state, &terminal);
+        type_layout(&mut state, &mock, 1);
+        focus(&mut state, &browser);
+        state.control_command("pin 1").unwrap();
+        assert_eq!(state.status()["pinned_windows"], json!(["1"]));
+        focus(&mut state, &terminal);
+        type_layout(&mut state, &mock, 0);
+        focus(&mut state, &browser);
+        focus(&mut state, &terminal);
+        assert_eq!(last_switch(&mock), Some((KBD.to_string(), 1)));
+        state.control_command("unpin 1").unwrap();
+        assert_eq!(state.status()["pinned_windows"], json!([]));
+    }
+
+    #[test]
+    fn watchers_hear_why_a_layout_was_chosen() {
+        let (mut state, mock) = daemon(config(&["-D", "English (US)"]));
+        let (watcher, daemon_end) = std::os::unix::net::UnixStream::pair().unwrap();
+        state.watchers.add(daemon_end);
+        focus(&mut state, &window(1, "foot", "~"));
+        type_layout(&mut state, &mock, 1);
+        focus(&mut state, &window(2, "firefox", "vk.com"));
+        drop(state);
+        let feed = std::io::read_to_string(watcher).unwrap();
+        assert_eq!(
+            feed,
+            "focus foot#1: nothing remembered, default layout\n\
+             saved Russian for 1\n\
+             focus firefox#2: nothing remembered, default layout\n  \
+             English (US) on 1:1:Keyboard\n"
+        );
+    }
+
+    #[test]
+    fn only_user_set_layouts_are_persisted_if_asked() {
+        let path = temp_state_file("user-only");
+        let daemon_with = |path: &PathBuf| {
+            daemon(Config {
+                state_file: Some(path.clone()),
+                ..config(&["-D", "English (US)", "--persist-user-only"])
+            })
+        };
+        let (terminal, browser) = (window(1, "foot", "~"), window(2, "firefox", "vk.com"));
+        let (mut state, mock) = daemon_with(&path);
+        focus(&mut state, &terminal);
+        type_layout(&mut state, &mock, 1);
+        focus(&mut state, &browser);
+        focus(&mut state, &terminal);
+        assert_eq!(state.status()["defaults"], json!(["2"]));
+        state.persist(true);
+        let saved = persist::load(&path).unwrap().unwrap();
+        let _ = fs::remove_file(&path);
+        let apps: Vec<&str> = saved.windows.iter().map(|(app, _, _)| &**app).collect();
+        assert_eq!(apps, ["foot"]);
+    }
+
+    #[test]
+    fn set_current_switches_and_remembers() {
+        let (mut state, mock) = daemon(config(&["-D", "English (US)"]));
+        let (terminal, browser) = (window(1, "foot", "~"), window(2, "firefox", "vk.com"));
+        focus(&mut state, &terminal);
+        state.on_tick("xkb-switcher:set-current Russian");
+        assert_eq!(last_switch(&mock), Some((KBD.to_string(), 1)));
+        assert!(state.state["1"][KBD].user);
+        // Sway reports the switch back.
+        type_layout(&mut state, &mock, 1);
+        focus(&mut state, &browser);
+        focus(&mut state, &terminal);
+        assert_eq!(last_switch(&mock), Some((KBD.to_string(), 1)));
+        assert!(state.control_command("set-current Klingon").is_err());
+    }
+
+    #[test]
+    fn schema_covers_the_config_file() {
+        let schema = config_file::schema();
+        let settings = &schema["properties"];
+        assert_eq!(settings["default-lang"]["anyOf"][1]["type"], "array");
+        assert_eq!(settings["no-persist"]["type"], "boolean");
+        assert_eq!(settings["mode"]["enum"], json!(["window", "workspace"]));
+        assert!(settings["include"].is_object());
+        assert!(settings.get("replace").is_none());
+        assert_eq!(settings["host"]["items"]["required"], json!(["name"]));
+        assert_eq!(schema["additionalProperties"], false);
+    }
+
+    #[test]
+    fn config_errors_point_at_the_line() {
+        let path = temp_state_file("diagnostics").with_extension("toml");
+        let load = |text: &str| {
+            fs::write(&path, text).unwrap();
+            config_file::load(&path, &options())
+        };
+        let err = load("mode = \"window\"\n  defaut-lang = \"us\"\n").unwrap_err();
+        assert!(err.ends_with(
+            ": line 2, column 3: unknown setting defaut-lang, did you mean default-lang?"
+        ));
+        let err = load("tabbed-apps = [\"foot\", { x = 1 }]\n").unwrap_err();
+        assert!(err.ends_with(
+            ": line 1, column 1: tabbed-apps[1]: expected a string or integer, found table { x = 1 }"
+        ));
+        let file = load("\n[[host]]\nname = \"*\"\nmode = \"windw\"\n").unwrap();
+        let cli = options()
+            .parse(["--config", path.to_str().unwrap()])
+            .unwrap();
+        let settings = Settings {
+            cli,
+            env: None,
+            file,
+            skipped: vec![],
+        };
+        let err = settings.explain(build_config(&settings).unwrap_err());
+        let _ = fs::remove_file(&path);
+        assert!(
+            err.ends_with(": line 4, column 1: Unknown --mode windw, expected window or workspace")
+        );
+
+        let (mut state, _) = daemon(config(&[]));
+        let err = state.control_command("set 1 Rusian").unwrap_err();
+        assert!(err.contains("did you mean Russian?"), "{err}");
+    }
+
+    #[test]
+    fn quiet_hours_may_span_midnight() {
+        let night = notify::QuietHours::parse("22:00-07:30").unwrap();
+        assert!(night.contains(23 * 60) && night.contains(7 * 60 + 29));
+        assert!(!night.contains(7 * 60 + 30) && !night.contains(12 * 60));
+        let talk = notify::QuietHours::parse("14:00-15:00").unwrap();
+        assert!(talk.contains(14 * 60 + 30) && !talk.contains(15 * 60));
+        assert_eq!(notify::QuietHours::parse("24:00-07:00"), None);
+        assert!(Config::from_args(&["--quiet-hours=9-17".into()]).is_err());
+    }
+
+    #[test]
+    fn old_state_files_are_migrated_and_kept() {
+        let path = temp_state_file("migrate");
+        let old = r#"{"version": 1, "windows": [{"app": "foot", "title": "~", "layouts": {"kbd": 1}}],
+            "workspaces": {"1": {"kbd": 0}}}"#;
+        fs::write(&path, old).unwrap();
+        let saved = persist::load(&path).unwrap().unwrap();
+        let backup = path.with_extension("json.v1.bak");
+        let kept = fs::read_to_string(&backup);
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(&backup);
+        assert_eq!(kept.unwrap(), old);
+        let (app, _, layouts) = &saved.windows[0];
+        assert_eq!(
+            (&**app, layouts["kbd"].index, layouts["kbd"].user),
+            ("foot", 1, true)
+        );
+        assert_eq!(saved.workspaces["1"]["kbd"].index, 0);
+    }
+
+    #[test]
+    fn sandbox_covers_threads_spawned_after_it() {
+        let dir = temp_state_file("sandbox").with_extension("d");
+        fs::create_dir_all(&dir).unwrap();
+        let (inside, outside) = (dir.join("inside"), temp_state_file("sandbox-outside"));
+        fs::write(&inside, "").unwrap();
+        fs::write(&outside, "").unwrap();
+        let (allowed, paths) = (dir.clone(), (inside.clone(), outside.clone()));
+        // Only this thread and its own are restricted, not the harness.
+        let opened = std::thread::spawn(move || {
+            sandbox::landlock(&[&allowed], &[], &[], &[])?;
+            let (inside, outside) = paths;
+            let opened = std::thread::spawn(move || {
+                (
+                    fs::File::open(inside).is_ok(),
+                    fs::File::open(outside).map(drop).map_err(|err| err.kind()),
+                )
+            });
+            Ok::<_, std::io::Error>(opened.join().unwrap())
+        })
+        .join()
+        .unwrap();
+        let _ = fs::remove_dir_all(&dir);
+        let _ = fs::remove_file(&outside);
+        match opened {
+            Ok(opened) => assert_eq!(opened, (true, Err(std::io::ErrorKind::PermissionDenied))),
+            Err(err) => eprintln!("Landlock is not available, not testing it: {err}"),
+        }
+    }
+
+    #[test]
+    fn control_commands_take_keys_with_spaces() {
+        let (mut state, _) = daemon(config(&[]));
+        let key = "app:foot:vim notes.txt";
+        state
+            .state
+            .insert(key.to_string(), persist::Layouts::default());
+        let set = json!({ "command": "set", "window": key, "layout": "Russian" });
+        state.control_command(&set.to_string()).unwrap();
+        assert_eq!(state.state[key][KBD].name, "Russian");
+        state.control_command(&format!("forget {key}")).unwrap();
+        assert!(!state.state.contains_key(key));
+        state.control_command("set 42 English (US)").unwrap();
+        assert_eq!(state.state["42"][KBD].name, "English (US)");
+        assert!(state.control_command("status now").is_err());
+    }
+
+    #[cfg(feature = "http")]
+    #[test]
+    fn http_api_turns_down_other_sites() {
+        use std::io::{Read, Write};
+        let addr = http::serve("127.0.0.1:0").unwrap();
+        let status = |request: String| {
+            let mut stream = std::net::TcpStream::connect(addr).unwrap();
+            stream.write_all(request.as_bytes()).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response.split(' ').nth(1).unwrap().parse::<u16>().unwrap()
+        };
+        let host = format!("Host: {addr}\r\n");
+        let json = "Content-Type: application/json\r\n";
+        // Nothing is posted to a route that exists, in case a daemon runs.
+        let post =
+            |headers: String| format!("POST /none HTTP/1.1\r\n{headers}Content-Length: 0\r\n\r\n");
+        assert_eq!(status(format!("GET / HTTP/1.1\r\n{host}\r\n")), 200);
+        assert_eq!(
+            status("GET / HTTP/1.1\r\nHost: rebound.example\r\n\r\n".into()),
+            403
+        );
+        assert_eq!(status("GET /state HTTP/1.1\r\n\r\n".into()), 403);
+        assert_eq!(
+            status(post(format!("{host}Origin: http://evil.example\r\n{json}"))),
+            403
+        );
+        assert_eq!(
+            status(post(format!("{host}Content-Type: text/plain\r\n"))),
+            415
+        );
+        assert_eq!(status(post(host.clone())), 415);
+        assert_eq!(
+            status(post(format!("{host}Origin: http://{addr}\r\n{json}"))),
+            404
+        );
+    }
+
+    #[test]
+    fn layouts_are_not_guessed_from_parts_of_names() {
+        let names =
+            |names: &[&str]| -> Vec<String> { names.iter().map(|name| name.to_string()).collect() };
+        assert_eq!(find_layout(&names(&["Russian", "German"]), "us"), None);
+        assert_eq!(find_layout(&names(&["Georgian", "German"]), "ger"), None);
+        let phonetic = names(&["Russian (phonetic)", "Ukrainian (phonetic)"]);
+        assert_eq!(find_layout(&phonetic, "phonetic"), None);
+        assert_eq!(find_layout(&phonetic, "ukrainian phonetic"), Some(1));
+        assert_eq!(
+            find_layout(&names(&["English (US)", "Russian (phonetic)"]), "phonetic"),
+            Some(1)
+        );
+    }
+
+    #[test]
+    fn namespaces_and_instances_name_files_together() {
+        assert_eq!(paths::session_name("wayland-1", None, None), "wayland-1");
+        assert_eq!(
+            paths::session_name("wayland-1", None, Some("work")),
+            "wayland-1-work"
+        );
+        assert_eq!(
+            paths::session_name("wayland-1", Some("exp"), Some("work")),
+            "exp-wayland-1-work"
+        );
+        assert_eq!(
+            paths::state_file_name(&paths::session_name("wayland-1", Some("exp"), None)),
+            "state-exp-wayland-1.json"
+        );
+        let cli = options()
+            .parse(["--namespace=exp", "--instance-name=work"])
+            .unwrap();
+        let settings = Settings {
+            cli,
+            env: None,
+            file: None,
+            skipped: vec![],
+        };
+        assert!(build_config(&settings).is_ok());
+    }
+
+    #[test]
+    fn unknown_variables_are_skipped_with_a_hint() {
+        let vars = [
+            ("SWAY_XKB_DEFAULT_LAGN", "Russian"),
+            ("SWAY_XKB_REPLACE", "1"),
+            ("SWAY_XKB_DEFAULT_LANG", "English (US)"),
+            ("HOME", "/root"),
+        ]
+        .map(|(name, value)| (name.to_string(), value.to_string()));
+        let (env, skipped) = config_file::from_vars(&options(), vars).unwrap();
+        assert_eq!(
+            env.unwrap().opt_str("default-lang").as_deref(),
+            Some("English (US)")
+        );
+        assert_eq!(
+            skipped,
+            [
+                "SWAY_XKB_DEFAULT_LAGN: unknown setting default-lagn, did you mean default-lang?",
+                "SWAY_XKB_REPLACE: replace can only be given on the command line",
+            ]
+        );
+    }
+
+    /// A daemon with one keyboard on each of the seats `seat0` and `seat1`.
+    fn two_seats() -> (LayoutState, Mock) {
+        let layouts: &[&str] = &["English (US)", "Russian"];
+        let mock = Mock::with_keyboards(&[("1:1:Left", layouts), ("2:2:Right", layouts)])
+            .with_seats(&[("seat0", &["1:1:Left"]), ("seat1", &["2:2:Right"])]);
+        let mut state = LayoutState::new(Box::new(mock.clone()), config(&["-D", "English (US)"]));
+        state.refresh_inputs();
+        (state, mock)
+    }
+
+    fn seat_focus(state: &mut LayoutState, mock: &Mock, seat: &str, window: &Window) {
+        mock.focus_seat(seat, window.id);
+        state.enter_seat(window.id);
+        focus(state, window);
+    }
+
+    fn seat_type(state: &mut LayoutState, mock: &Mock, input: &str, index: i32) {
+        let input = mock.type_layout(input, index);
+        state.on_input(&input);
+        state.on_layout_change(&input);
+    }
+
+    fn active(mock: &Mock, input: &str) -> Option<i32> {
+        let inputs = mock.inputs.borrow();
+        let keyboard = inputs.iter().find(|keyboard| keyboard.identifier == input);
+        keyboard.and_then(|keyboard| keyboard.xkb_active_layout_index)
+    }
+
+    #[test]
+    fn seats_keep_their_own_layouts() {
+        let (mut state, mock) = two_seats();
+        let (left, right) = (window(1, "foot", "~"), window(2, "firefox", "vk.com"));
+        seat_focus(&mut state, &mock, "seat0", &left);
+        seat_type(&mut state, &mock, "1:1:Left", 1);
+        let switches = mock.switches().len();
+        seat_focus(&mut state, &mock, "seat1", &right);
+        assert_eq!(active(&mock, "1:1:Left"), Some(1));
+        assert_eq!(active(&mock, "2:2:Right"), Some(0));
+        assert!(mock.switches()[switches..]
+            .iter()
+            .all(|(input, _)| input != "1:1:Left"));
+        // Leaving the window on its own seat saves only that seat's keyboard.
+        seat_focus(&mut state, &mock, "seat0", &window(3, "foot", "notes"));
+        assert_eq!(active(&mock, "1:1:Left"), Some(0));
+        assert_eq!(state.state["1"]["1:1:Left"].name, "Russian");
+        assert!(!state.state["1"].contains_key("2:2:Right"));
+        seat_focus(&mut state, &mock, "seat0", &left);
+        assert_eq!(active(&mock, "1:1:Left"), Some(1));
+        assert_eq!(active(&mock, "2:2:Right"), Some(0));
+    }
+
+    #[test]
+    fn focus_on_one_seat_leaves_the_other_alone() {
+        let (mut state, mock) = two_seats();
+        let (left, right, other) = (
+            window(1, "foot", "~"),
+            window(2, "firefox", "vk.com"),
+            window(3, "foot", "notes"),
+        );
+        seat_focus(&mut state, &mock, "seat0", &left);
+        seat_type(&mut state, &mock, "1:1:Left", 1);
+        seat_focus(&mut state, &mock, "seat1", &right);
+        seat_type(&mut state, &mock, "2:2:Right", 1);
+        let switches = mock.switches().len();
+        seat_focus(&mut state, &mock, "seat1", &other);
+        assert_eq!(active(&mock, "1:1:Left"), Some(1));
+        assert_eq!(active(&mock, "2:2:Right"), Some(0));
+        assert_eq!(mock.switches()[switches..], [("2:2:Right".to_string(), 0)]);
+        // Back on seat0, its own window comes back, not seat1's.
+        seat_focus(&mut state, &mock, "seat0", &left);
+        assert_eq!(active(&mock, "1:1:Left"), Some(1));
+        assert_eq!(active(&mock, "2:2:Right"), Some(0));
+        assert_eq!(state.state["2"]["2:2:Right"].name, "Russian");
+    }
+
+    fn remembered(state: &LayoutState) -> Vec<&str> {
+        let mut keys: Vec<&str> = state.state.keys().map(String::as_str).collect();
+        keys.sort();
+        keys
+    }
+
+    #[test]
+    fn least_recently_focused_windows_go_first_at_the_cap() {
+        let (mut state, _mock) = daemon(config(&["--max-entries", "2"]));
+        for id in 1..=4 {
+            focus(&mut state, &window(id, "foot", "~"));
+        }
+        assert_eq!(remembered(&state), ["2", "3"]);
+        // Focus makes a window the most recent one, and keeps it while focused.
+        focus(&mut state, &window(2, "foot", "~"));
+        assert_eq!(remembered(&state), ["2", "4"]);
+        focus(&mut state, &window(5, "foot", "~"));
+        focus(&mut state, &window(6, "foot", "~"));
+        assert_eq!(remembered(&state), ["2", "5"]);
+    }
+
+    #[test]
+    fn windows_unfocused_past_revert_after_are_dropped() {
+        let (mut state, mock) = daemon(config(&["-D", "English (US)", "--revert-after", "1"]));
+        let (terminal, browser) = (window(1, "foot", "~"), window(2, "firefox", "vk.com"));
+        focus(&mut state, &browser);
+        type_layout(&mut state, &mock, 1);
+        focus(&mut state, &terminal);
+        let long_ago = Instant::now() - Duration::from_secs(120);
+        state.unfocused_at.insert("2".to_string(), long_ago);
+        focus(&mut state, &window(3, "foot", "notes"));
+        assert_eq!(remembered(&state), ["1"]);
+        focus(&mut state, &browser);
+        assert_eq!(last_switch(&mock), Some((KBD.to_string(), 0)));
+    }
+
+    /// Sway telling that `window` got focus.
+    fn focus_event(window: &Window) -> Event {
+        let rect = serde_json::json!({"x": 0, "y": 0, "width": 800, "height": 600});
+        let container = serde_json::json!({
+            "id": window.id,
+            "name": window.title,
+            "type": "con",
+            "border": "none",
+            "current_border_width": 0,
+            "layout": "none",
+            "percent": 1.0,
+            "rect": rect,
+            "window_rect": rect,
+            "deco_rect": rect,
+            "geometry": rect,
+            "urgent": false,
+            "focused": true,
+            "focus": [],
+            "nodes": [],
+            "floating_nodes": [],
+            "sticky": false,
+            "app_id": window.app_id,
+        });
+        let event = serde_json::json!({"change": "focus", "container": container});
+        Event::Window(Box::new(serde_json::from_value(event).unwrap()))
+    }
+
+    #[test]
+    fn a_burst_of_focus_changes_ends_in_one_switch() {
+        let (mut state, mock) = daemon(config(&["-D", "English (US)", "--debounce", "100"]));
+        state.debounce_timer = Some(debounce::Timer::idle());
+        let browser = window(1, "firefox", "vk.com");
+        focus(&mut state, &browser);
+        type_layout(&mut state, &mock, 1);
+        focus(&mut state, &window(4, "foot", "~"));
+        let commands = mock.commands().len();
+        for swept in [
+            window(2, "foot", "notes"),
+            window(3, "mpv", "film"),
+            browser,
+        ] {
+            state.handle_event(focus_event(&swept));
+        }
+        let tick = || {
+            let tick = serde_json::json!({"first": false, "payload": own_tick("debounce")});
+            Event::Tick(serde_json::from_value(tick).unwrap())
+        };
+        // The timer firing for an earlier deadline changes nothing yet.
+        state.handle_event(tick());
+        assert_eq!(mock.commands().len(), commands);
+        // The delay passing.
+        state.pending_focus.as_mut().unwrap().1 = Instant::now();
+        state.handle_event(tick());
+        assert_eq!(
+            mock.commands()[commands..],
+            [format!("input {KBD} xkb_switch_layout 1")]
+        );
+        // Windows swept over were neither saved nor given a layout.
+        assert_eq!(remembered(&state), ["1", "4"]);
+    }
+
+    #[test]
+    fn keyboards_switch_in_one_command() {
+        let layouts: &[&str] = &["English (US)", "Russian"];
+        let mock = Mock::with_keyboards(&[("1:1:Laptop", layouts), ("2:2:Desk", layouts)]);
+        let mut state = LayoutState::new(Box::new(mock.clone()), config(&["-D", "English (US)"]));
+        let (terminal, browser) = (window(1, "foot", "~"), window(2, "firefox", "vk.com"));
+        focus(&mut state, &browser);
+        for input in ["1:1:Laptop", "2:2:Desk"] {
+            let input = mock.type_layout(input, 1);
+            state.on_input(&input);
+            state.on_layout_change(&input);
+        }
+        focus(&mut state, &terminal);
+        let commands = mock.commands().len();
+        focus(&mut state, &browser);
+        assert_eq!(
+            mock.commands()[commands..],
+            ["input 1:1:Laptop xkb_switch_layout 1; input 2:2:Desk xkb_switch_layout 1"]
+        );
+    }
+
+    fn stored(entries: &[(&str, &str, i32)]) -> persist::Layouts {
+        entries
+            .iter()
+            .map(|(input, name, index)| {
+                let layout = StoredLayout {
+                    name: name.to_string(),
+                    index: *index,
+                    user: true,
+                };
+                (input.to_string(), layout)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn keyboards_that_are_gone_pass_their_layout_on() {
+        let (mut state, mock) = daemon(config(&["-D", "English (US)"]));
+        // Remembered while docked, where Russian came after German.
+        let docked = stored(&[
+            ("9:9:Dock", "Russian", 2),
+            ("8:8:Numpad", "Russian", 2),
+            ("7:7:Old", "English (US)", 1),
+        ]);
+        state.state.insert("2".to_string(), docked);
+        focus(&mut state, &window(1, "foot", "~"));
+        focus(&mut state, &window(2, "firefox", "vk.com"));
+        assert_eq!(last_switch(&mock), Some((KBD.to_string(), 1)));
+    }
+
+    #[test]
+    fn layouts_no_longer_configured_fall_back_to_the_default() {
+        let (mut state, mock) = daemon(config(&["-D", "English (US)"]));
+        focus(&mut state, &window(1, "foot", "~"));
+        type_layout(&mut state, &mock, 1);
+        state
+            .state
+            .insert("2".to_string(), stored(&[(KBD, "German", 1)]));
+        focus(&mut state, &window(2, "firefox", "vk.com"));
+        assert_eq!(last_switch(&mock), Some((KBD.to_string(), 0)));
+        // The same when the keyboard that had it is gone as well.
+        focus(&mut state, &window(1, "foot", "~"));
+        assert_eq!(last_switch(&mock), Some((KBD.to_string(), 1)));
+        state
+            .state
+            .insert("3".to_string(), stored(&[("9:9:Dock", "German", 0)]));
+        focus(&mut state, &window(3, "foot", "notes"));
+        assert_eq!(last_switch(&mock), Some((KBD.to_string(), 0)));
+    }
+
+    #[test]
+    fn dbus_interface_serves_the_layout_and_runs_methods() {
+        use std::{
+            process::{Command, Stdio},
+            sync::mpsc,
+            thread,
+        };
+        let socket = temp_state_file("bus");
+        let address = format!("unix:path={}", socket.display());
+        let Ok(mut bus) = Command::new("dbus-daemon")
+            .args(["--session", "--nofork", &format!("--address={address}")])
+            .stderr(Stdio::null())
+            .spawn()
+        else {
+            return; // No bus to test against.
+        };
+        for _ in 0..50 {
+            if socket.exists() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        let (commands, called) = mpsc::channel();
+        let mut service = dbus::Service::start(address.clone(), dbus::bus_name("default"), {
+            move |command| commands.send(command.to_string()).unwrap()
+        });
+        service.publish("Russian", Some("firefox"));
+        let send = |args: &[&str]| {
+            let output = Command::new("dbus-send")
+                .arg(format!("--bus={address}"))
+                .args([
+                    "--print-reply",
+                    "--dest=org.sway.XkbSwitcher",
+                    "/org/sway/XkbSwitcher",
+                ])
+                .args(args)
+                .output()
+                .ok()?;
+            output
+                .status
+                .success()
+                .then(|| String::from_utf8_lossy(&output.stdout).into_owned())
+        };
+        let get = |name: &str| {
+            let name = format!("string:{name}");
+            send(&[
+                "org.freedesktop.DBus.Properties.Get",
+                "string:org.sway.XkbSwitcher",
+                &name,
+            ])
+        };
+        // The service comes up on a thread of its own.
+        let mut layout = None;
+        for _ in 0..50 {
+            layout = get("Layout").filter(|reply| reply.contains("\"Russian\""));
+            if layout.is_some() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        let app_id = get("AppId");
+        let paused = send(&["org.sway.XkbSwitcher.Pause"]);
+        let unknown = get("Nothing");
+        let _ = bus.kill();
+        let _ = bus.wait();
+        let _ = fs::remove_file(&socket);
+        if layout.is_none() && Command::new("dbus-send").arg("--help").output().is_err() {
+            return; // No client to test with.
+        }
+        assert!(layout.is_some());
+        assert!(app_id.unwrap().contains("string \"firefox\""));
+        assert!(paused.is_some());
+        assert_eq!(
+            called.recv_timeout(Duration::from_secs(1)).as_deref(),
+            Ok("pause")
+        );
+        assert_eq!(unknown, None);
+    }
+
+    #[test]
+    fn typing_in_a_launcher_does_not_stick_to_the_window() {
+        let (mut state, mock) = daemon(config(&["-D", "English (US)", "--layer-focus", "ignore"]));
+        let browser = window(1, "firefox", "vk.com");
+        focus(&mut state, &browser);
+        type_layout(&mut state, &mock, 1);
+        state.on_tick(&own_tick("layer-focus seat0"));
+        type_layout(&mut state, &mock, 0);
+        state.on_tick(&own_tick("layer-unfocus seat0"));
+        assert_eq!(last_switch(&mock), Some((KBD.to_string(), 1)));
+        state.refresh_inputs();
+        // A window the launcher opened does not save it either.
+        state.on_tick(&own_tick("layer-focus seat0"));
+        type_layout(&mut state, &mock, 0);
+        focus(&mut state, &window(2, "foot", "~"));
+        state.on_tick(&own_tick("layer-unfocus seat0"));
+        assert_eq!(state.state["1"][KBD].name, "Russian");
+    }
+
+    #[test]
+    fn launchers_can_get_the_default_layout() {
+        let (mut state, mock) = daemon(config(&["-D", "English (US)", "--layer-focus", "default"]));
+        focus(&mut state, &window(1, "firefox", "vk.com"));
+        type_layout(&mut state, &mock, 1);
+        state.on_tick(&own_tick("layer-focus seat0"));
+        assert_eq!(last_switch(&mock), Some((KBD.to_string(), 0)));
+        state.on_tick(&own_tick("layer-unfocus seat0"));
+        assert_eq!(last_switch(&mock), Some((KBD.to_string(), 1)));
+    }
+}
@@ -0,0 +1,151 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{config_file, options, paths, setup, Arity, OPTIONS};
+
+/// Whether `word` starts the Python sway-xkb-switcher, e.g.
+/// `~/bin/sway-xkb-switcher` or `python3 sway_xkb_switcher.py`.
+fn is_python_switcher(word: &str) -> bool {
+    let name = Path::new(word)
+        .file_name()
+        .map(|name| name.to_string_lossy().replace('_', "-"))
+        .unwrap_or_default();
+    name.starts_with("sway-xkb-switcher") && name != env!("CARGO_PKG_NAME")
+}
+
+/// Splits a sway config line into words, minding quotes, up to the end of
+/// the first command or a redirection.
+fn split_words(line: &str) -> Vec<String> {
+    let (mut words, mut word, mut quote) = (vec![], None::<String>, None);
+    for c in line.chars() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), c) => word.get_or_insert_with(String::new).push(c),
+            (None, '\'' | '"') => {
+                quote = Some(c);
+                word.get_or_insert_with(String::new);
+            }
+            (None, '>' | '<') => {
+                // The fd of e.g. 2>&1.
+                word.take_if(|word| word.chars().all(|c| c.is_ascii_digit()));
+                break;
+            }
+            (None, ';' | '&' | '|' | '#') => break,
+            (None, c) if c.is_whitespace() => words.extend(word.take()),
+            (None, c) => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    words.extend(word);
+    words
+}
+
+/// The options the sway config starts the Python daemon with, from the
+/// first `exec` line naming it in sway/config or sway/config.d.
+fn find_exec() -> Option<Vec<String>> {
+    let sway = paths::config_home().join("sway");
+    let mut files: Vec<PathBuf> = fs::read_dir(sway.join("config.d"))
+        .map(|entries| entries.flatten().map(|entry| entry.path()).collect())
+        .unwrap_or_default();
+    files.sort();
+    files.insert(0, sway.join("config"));
+    files.iter().find_map(|file| {
+        fs::read_to_string(file).ok()?.lines().find_map(|line| {
+            let words = split_words(line);
+            if !words.first()?.starts_with("exec") {
+                return None;
+            }
+            let start = words.iter().position(|word| is_python_switcher(word))?;
+            info!("Found the Python switcher in {}", file.display());
+            Some(words[start + 1..].to_vec())
+        })
+    })
+}
+
+/// The config file settings for the Python daemon's options. The Rust
+/// daemon kept its option names, spelled with dashes here; options without
+/// an equivalent are left out with a warning.
+pub fn translate(args: &[String]) -> Result<String, String> {
+    let mut known = vec![];
+    for arg in args {
+        if let Some(long) = arg.strip_prefix("--") {
+            let (name, value) = match long.split_once('=') {
+                Some((name, value)) => (name.replace('_', "-"), Some(value)),
+                None => (long.replace('_', "-"), None),
+            };
+            if !OPTIONS.iter().any(|spec| spec.long == name) {
+                eprintln!("--{name} has no equivalent, leaving it out");
+                continue;
+            }
+            known.push(match value {
+                Some(value) => format!("--{name}={value}"),
+                None => format!("--{name}"),
+            });
+        } else if let Some(short) = arg.strip_prefix('-').and_then(|arg| arg.get(..1)) {
+            if !OPTIONS.iter().any(|spec| spec.short == short) {
+                eprintln!("-{short} has no equivalent, leaving it out");
+                continue;
+            }
+            known.push(arg.clone());
+        } else {
+            known.push(arg.clone());
+        }
+    }
+    let matches = options()
+        .parse(&known)
+        .map_err(|fail| format!("cannot read the Python switcher's options: {fail}"))?;
+    for free in &matches.free {
+        eprintln!("Leaving out the argument {free:?}");
+    }
+    let mut config = String::new();
+    for spec in OPTIONS {
+        if config_file::CLI_ONLY.contains(&spec.long) || !matches.opt_present(spec.long) {
+            continue;
+        }
+        let value = match (spec.arity, matches.opt_str(spec.long)) {
+            (Arity::Multi, _) => {
+                let values: Vec<String> = matches
+                    .opt_strs(spec.long)
+                    .iter()
+                    .map(|value| setup::quote(value))
+                    .collect();
+                format!("[{}]", values.join(", "))
+            }
+            // The file turns a switch on but cannot count it.
+            (Arity::Flag | Arity::FlagMulti, _) | (Arity::FlagValue, None) => "true".into(),
+            (_, Some(value)) => setup::quote(&value),
+            (Arity::Value, None) => continue,
+        };
+        config.push_str(&format!("{} = {value}\n", spec.long));
+    }
+    Ok(config)
+}
+
+/// Runs `migrate [-- <options>]`: writes the config file for the options
+/// the Python daemon is started with, given or found in the sway config.
+/// Only its options are carried over, not the layouts it remembers.
+pub fn run(config_file: PathBuf, args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let args = match args {
+        [dashes, args @ ..] if dashes == "--" => args.to_vec(),
+        [] => find_exec().ok_or(
+            "no exec of the Python sway-xkb-switcher in the sway config, \
+             give its options after migrate --",
+        )?,
+        args => args.to_vec(),
+    };
+    let mut config = format!(
+        "# Migrated by {} from: {}\n",
+        env!("CARGO_PKG_NAME"),
+        args.join(" ")
+    );
+    config.push_str(&translate(&args)?);
+    if setup::offer(&config_file, &config)? {
+        println!(
+            "Written. Replace the Python switcher's exec in the sway config with `exec {}` \
+             and reload sway.",
+            env!("CARGO_PKG_NAME")
+        );
+    }
+    Ok(())
+}
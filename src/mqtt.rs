@@ -0,0 +1,138 @@
+use std::{
+    io::{self, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    sync::mpsc::{self, Sender},
+    thread,
+    time::Duration,
+};
+
+/// How long connecting to the broker and each write may take.
+const TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Minimal MQTT 3.1.1 publisher: clean session, no keep-alive, retained QoS 0
+/// messages. That is all it takes to tell a broker which layout is active.
+/// The broker is talked to on a thread of its own, so an unreachable one
+/// never holds up layout switching.
+#[derive(Debug)]
+pub struct Publisher {
+    payloads: Sender<String>,
+    last: Option<String>,
+}
+
+/// The connection owned by the publishing thread.
+#[derive(Debug)]
+struct Broker {
+    addr: String,
+    topic: String,
+    stream: Option<TcpStream>,
+}
+
+fn push_str(buf: &mut Vec<u8>, s: &str) {
+    buf.extend_from_slice(&(s.len() as u16).to_be_bytes());
+    buf.extend_from_slice(s.as_bytes());
+}
+
+fn packet(header: u8, body: &[u8]) -> Vec<u8> {
+    let mut packet = vec![header];
+    let mut len = body.len();
+    loop {
+        let mut byte = (len % 128) as u8;
+        len /= 128;
+        if len > 0 {
+            byte |= 0x80;
+        }
+        packet.push(byte);
+        if len == 0 {
+            break;
+        }
+    }
+    packet.extend_from_slice(body);
+    packet
+}
+
+impl Publisher {
+    pub fn new(addr: String, topic: String) -> Publisher {
+        let (payloads, receiver) = mpsc::channel::<String>();
+        thread::spawn(move || {
+            let mut broker = Broker {
+                addr,
+                topic,
+                stream: None,
+            };
+            while let Ok(payload) = receiver.recv() {
+                // Only the latest state matters after a slow publish.
+                let payload = receiver.try_iter().last().unwrap_or(payload);
+                broker.publish(&payload);
+            }
+        });
+        Publisher {
+            payloads,
+            last: None,
+        }
+    }
+
+    /// Publishes the layout and focused app, skipping repeats of the last message.
+    pub fn publish(&mut self, layout: &str, app_id: Option<&str>) {
+        let payload = serde_json::json!({ "layout": layout, "app_id": app_id }).to_string();
+        if self.last.as_ref() == Some(&payload) {
+            return;
+        }
+        let _ = self.payloads.send(payload.clone());
+        self.last = Some(payload);
+    }
+}
+
+impl Broker {
+    fn connect(&self) -> io::Result<TcpStream> {
+        let addr = self
+            .addr
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::other(format!("{} has no address", self.addr)))?;
+        let mut stream = TcpStream::connect_timeout(&addr, TIMEOUT)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+        let mut body = Vec::new();
+        push_str(&mut body, "MQTT");
+        body.push(4); // protocol level 3.1.1
+        body.push(0x02); // clean session
+        body.extend_from_slice(&0u16.to_be_bytes()); // no keep-alive
+        push_str(
+            &mut body,
+            &format!("{}-{}", env!("CARGO_PKG_NAME"), std::process::id()),
+        );
+        stream.write_all(&packet(0x10, &body))?;
+
+        let mut connack = [0u8; 4];
+        stream.read_exact(&mut connack)?;
+        if connack[0] != 0x20 || connack[3] != 0 {
+            return Err(io::Error::other(format!(
+                "broker refused connection, return code {}",
+                connack[3]
+            )));
+        }
+        info!("Connected to MQTT broker {}", self.addr);
+        Ok(stream)
+    }
+
+    fn send(&mut self, payload: &str) -> io::Result<()> {
+        let mut stream = match self.stream.take() {
+            Some(stream) => stream,
+            None => self.connect()?,
+        };
+        let mut body = Vec::new();
+        push_str(&mut body, &self.topic);
+        body.extend_from_slice(payload.as_bytes());
+        // A failed write drops the stream, so the next publish reconnects.
+        stream.write_all(&packet(0x31, &body))?;
+        self.stream = Some(stream);
+        Ok(())
+    }
+
+    fn publish(&mut self, payload: &str) {
+        // One retry covers a broker that dropped our idle connection.
+        if let Err(err) = self.send(payload).or_else(|_| self.send(payload)) {
+            warn!("Failed to publish to MQTT broker {}: {err}", self.addr);
+        }
+    }
+}
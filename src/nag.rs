@@ -0,0 +1,32 @@
+use std::{path::PathBuf, process::Command, sync::OnceLock};
+
+use crate::compositor;
+
+struct Nag {
+    log_file: Option<PathBuf>,
+}
+
+static NAG: OnceLock<Nag> = OnceLock::new();
+
+/// Makes [`fatal`] pop up a swaynag bar. `log_file` adds a "View log" button.
+pub fn enable(log_file: Option<PathBuf>) {
+    let _ = NAG.set(Nag { log_file });
+}
+
+pub fn fatal(message: &str) {
+    let Some(nag) = NAG.get() else {
+        return;
+    };
+    let mut cmd = Command::new("swaynag");
+    cmd.args(["-t", "error", "-m"])
+        .arg(format!("{}: {message}", env!("CARGO_PKG_NAME")));
+    if let Some(log_file) = &nag.log_file {
+        cmd.arg("-b")
+            .arg("View log")
+            .arg(format!("less +G '{}'", log_file.display()));
+    }
+    compositor::pass_socket(&mut cmd);
+    if let Err(err) = cmd.spawn() {
+        error!("Failed to run swaynag: {err}");
+    }
+}
@@ -0,0 +1,124 @@
+use std::io::{self, Read};
+
+use serde_json::{json, Value};
+
+use crate::client;
+
+/// Name the extension connects to, and of the host manifest.
+pub const NAME: &str = "sway_xkb_switcher";
+/// ID of the extension in extension/, as Firefox knows it.
+const FIREFOX_EXTENSION: &str = "xkb-switcher@sway-xkb-switcher-rust";
+
+pub const BROWSERS: &[&str] = &["firefox", "chromium"];
+
+/// Whether a browser started us: Firefox passes the host manifest's path
+/// and the extension ID, Chromium the extension's origin.
+pub fn launched_by_browser(args: &[String]) -> bool {
+    args.first()
+        .is_some_and(|arg| arg.ends_with(".json") || arg.starts_with("chrome-extension://"))
+}
+
+/// The site of a tab: the host without `www.`, or for URLs without one
+/// the scheme, e.g. `file`, or the page, e.g. `about:newtab`.
+fn site(url: &str) -> String {
+    let url = url.split(['?', '#']).next().unwrap_or_default();
+    let Some((scheme, rest)) = url.split_once("://") else {
+        return url.to_string();
+    };
+    let authority = rest.split('/').next().unwrap_or_default();
+    let host = authority.rsplit('@').next().unwrap_or_default();
+    let host = match host.strip_prefix('[') {
+        Some(ipv6) => ipv6.split(']').next().unwrap_or_default(),
+        None => host.split(':').next().unwrap_or_default(),
+    };
+    let host = host.to_lowercase();
+    match host.strip_prefix("www.") {
+        _ if host.is_empty() => scheme.to_string(),
+        Some(host) => host.to_string(),
+        None => host,
+    }
+}
+
+/// One message of the native messaging protocol: its length in native byte
+/// order, then as much JSON. `None` once the browser closed the pipe.
+fn read_message(reader: &mut impl Read) -> io::Result<Option<Value>> {
+    let mut len = [0; 4];
+    match reader.read_exact(&mut len) {
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        result => result?,
+    }
+    let mut message = vec![0; u32::from_ne_bytes(len) as usize];
+    reader.read_exact(&mut message)?;
+    serde_json::from_slice(&message)
+        .map(Some)
+        .map_err(io::Error::other)
+}
+
+/// The daemon's view of a message from the extension: which browser window
+/// shows which site, or that the window closed.
+fn report(message: &Value) -> Option<Value> {
+    let window = message["window"].as_i64()?;
+    if message["closed"].as_bool().unwrap_or_default() {
+        return Some(json!({ "window": window, "closed": true }));
+    }
+    Some(json!({
+        "window": window,
+        "site": site(message["url"].as_str()?),
+        "title": message["title"].as_str().unwrap_or_default(),
+    }))
+}
+
+fn forward(browser: i32, report: &Value) {
+    if let Err(err) = client::request(&format!("tab {browser} {report}")) {
+        warn!("Cannot report the active tab: {err}");
+    }
+}
+
+/// Passes what the extension reports about the active tabs on to the
+/// daemon until the browser goes away. The browser is our parent, which
+/// is also the process its sway windows belong to.
+pub fn run() -> io::Result<()> {
+    let browser = unsafe { libc::getppid() };
+    let mut stdin = io::stdin().lock();
+    while let Some(message) = read_message(&mut stdin)? {
+        match report(&message) {
+            Some(report) => forward(browser, &report),
+            None => debug!("Ignoring message {message}"),
+        }
+    }
+    forward(browser, &Value::Null);
+    Ok(())
+}
+
+/// The host manifest to install for `browser`, e.g. as
+/// ~/.mozilla/native-messaging-hosts/sway_xkb_switcher.json. Chromium
+/// only lets extensions it was told the ID of connect.
+pub fn manifest(browser: &str, extension: Option<&str>) -> Result<Value, String> {
+    let path = std::env::current_exe()
+        .map_err(|err| format!("cannot tell where this executable is: {err}"))?;
+    let mut manifest = json!({
+        "name": NAME,
+        "description": "Tells sway-xkb-switcher-rust the site of the active tab",
+        "path": path,
+        "type": "stdio",
+    });
+    match (browser, extension) {
+        ("firefox", _) => {
+            let extension = extension.unwrap_or(FIREFOX_EXTENSION);
+            manifest["allowed_extensions"] = json!([extension]);
+        }
+        ("chromium", Some(extension)) => {
+            manifest["allowed_origins"] = json!([format!("chrome-extension://{extension}/")]);
+        }
+        ("chromium", None) => {
+            return Err("chromium needs the extension ID shown on chrome://extensions".into())
+        }
+        (other, _) => {
+            return Err(format!(
+                "no manifest for {other:?}, expected {}",
+                BROWSERS.join(" or ")
+            ))
+        }
+    }
+    Ok(manifest)
+}
@@ -0,0 +1,119 @@
+use std::{process::Command, thread};
+
+/// When --quiet-hours holds popups back: from `start` until `end`, in
+/// minutes after local midnight, across it when `end` comes first.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct QuietHours {
+    start: u32,
+    end: u32,
+}
+
+impl QuietHours {
+    /// Parses `HH:MM-HH:MM`, e.g. `22:00-07:30`.
+    pub fn parse(hours: &str) -> Option<QuietHours> {
+        let minute = |time: &str| {
+            let (h, m) = time.trim().split_once(':')?;
+            let (h, m): (u32, u32) = (h.parse().ok()?, m.parse().ok()?);
+            (h < 24 && m < 60).then_some(h * 60 + m)
+        };
+        let (start, end) = hours.split_once('-')?;
+        Some(QuietHours {
+            start: minute(start)?,
+            end: minute(end)?,
+        })
+    }
+
+    pub fn contains(self, minute: u32) -> bool {
+        match self.start <= self.end {
+            true => self.start <= minute && minute < self.end,
+            false => minute >= self.start || minute < self.end,
+        }
+    }
+
+    /// Whether the local time is within them.
+    pub fn now(self) -> bool {
+        let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+        let time = unsafe { libc::time(std::ptr::null_mut()) };
+        if unsafe { libc::localtime_r(&time, &mut tm) }.is_null() {
+            return false;
+        }
+        self.contains(tm.tm_hour as u32 * 60 + tm.tm_min as u32)
+    }
+}
+
+/// Whether mako or dunst is set to not disturb. Neither running counts as
+/// no.
+fn do_not_disturb() -> bool {
+    let output = |program: &str, args: &[&str]| {
+        Command::new(program)
+            .args(args)
+            .output()
+            .ok()
+            .filter(|output| output.status.success())
+            .map(|output| String::from_utf8_lossy(&output.stdout).into_owned())
+            .unwrap_or_default()
+    };
+    output("makoctl", &["mode"])
+        .lines()
+        .any(|mode| mode.trim() == "do-not-disturb")
+        || output("dunstctl", &["is-paused"]).trim() == "true"
+}
+
+/// How --notify shows a layout the daemon switched to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Notifier {
+    /// A freedesktop notification through notify-send.
+    NotifySend,
+    /// The swayosd popup.
+    SwayOsd,
+}
+
+impl Notifier {
+    pub fn parse(name: &str) -> Option<Notifier> {
+        match name {
+            "notify-send" => Some(Notifier::NotifySend),
+            "swayosd" => Some(Notifier::SwayOsd),
+            _ => None,
+        }
+    }
+
+    /// Shows `layout` and the window that caused the switch, unless the
+    /// notification daemon is set to not disturb. That is asked and the
+    /// helper waited for on a thread of its own, so neither blocks the
+    /// event loop and the helper does not stay around as a zombie.
+    pub fn show(self, layout: &str, trigger: &str) {
+        let mut command = match self {
+            Notifier::NotifySend => {
+                let mut command = Command::new("notify-send");
+                command
+                    .args(["-a", env!("CARGO_PKG_NAME"), "-i", "input-keyboard"])
+                    .args(["-t", "1500", "-u", "low"])
+                    // Replaces the previous popup instead of stacking up.
+                    .args(["-h", "string:x-canonical-private-synchronous:layout"])
+                    .arg(layout)
+                    .arg(format!("for {trigger}"));
+                command
+            }
+            Notifier::SwayOsd => {
+                let mut command = Command::new("swayosd-client");
+                command
+                    .args(["--custom-icon", "input-keyboard"])
+                    .arg("--custom-message")
+                    .arg(layout);
+                command
+            }
+        };
+        thread::spawn(move || {
+            if do_not_disturb() {
+                debug!("Not showing the layout, do not disturb is on");
+                return;
+            }
+            match command.spawn() {
+                Ok(mut child) => {
+                    let _ = child.wait();
+                }
+                Err(err) => warn!("Cannot show the layout with {self:?}: {err}"),
+            }
+        });
+    }
+}
@@ -0,0 +1,162 @@
+use std::{
+    env,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+const APP_NAME: &str = "sway-xkb-switcher";
+
+#[derive(Default)]
+struct Overrides {
+    state_dir: Option<PathBuf>,
+    namespace: Option<String>,
+    instance: Option<String>,
+}
+
+static OVERRIDES: OnceLock<Overrides> = OnceLock::new();
+
+/// `--state-dir` moves every file the daemon owns (state, pidfile, sockets,
+/// logs) under one directory; `--namespace` and `--instance-name` go around
+/// the session name so side-by-side setups and instances get separate
+/// files. Must run before any other function in this module.
+pub fn configure(state_dir: Option<PathBuf>, namespace: Option<String>, instance: Option<String>) {
+    let _ = OVERRIDES.set(Overrides {
+        state_dir,
+        namespace,
+        instance,
+    });
+}
+
+/// Name of the files of `session`, e.g. `exp-wayland-1-work` in namespace
+/// `exp` for the instance named `work`.
+pub fn session_name(session: &str, namespace: Option<&str>, instance: Option<&str>) -> String {
+    let parts: Vec<&str> = [namespace, Some(session), instance]
+        .into_iter()
+        .flatten()
+        .collect();
+    parts.join("-")
+}
+
+/// Name of this instance, `default` unless --instance-name gave one.
+pub fn instance() -> &'static str {
+    overrides().instance.as_deref().unwrap_or("default")
+}
+
+fn overrides() -> &'static Overrides {
+    OVERRIDES.get_or_init(Overrides::default)
+}
+
+fn xdg_dir(var: &str, fallback: &str) -> PathBuf {
+    match env::var_os(var) {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(env::var_os("HOME").unwrap_or_else(|| "/tmp".into())).join(fallback),
+    }
+}
+
+/// Names the sway session the daemon runs in, so instances on different TTYs
+/// or in a nested sway keep separate files. WAYLAND_DISPLAY is preferred over
+/// SWAYSOCK because it stays the same across sway restarts, while the socket
+/// name contains sway's pid.
+pub fn session() -> &'static str {
+    static SESSION: OnceLock<String> = OnceLock::new();
+    SESSION.get_or_init(|| {
+        let display = env::var("WAYLAND_DISPLAY").ok().and_then(|display| {
+            Path::new(&display)
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+        });
+        let socket = || {
+            env::var("SWAYSOCK").ok().and_then(|sock| {
+                Path::new(&sock)
+                    .file_stem()
+                    .map(|name| name.to_string_lossy().into_owned())
+            })
+        };
+        let session = display
+            .or_else(socket)
+            .filter(|name| !name.is_empty())
+            .unwrap_or_else(|| "default".to_string());
+        let overrides = overrides();
+        session_name(
+            &session,
+            overrides.namespace.as_deref(),
+            overrides.instance.as_deref(),
+        )
+    })
+}
+
+pub fn config_home() -> PathBuf {
+    xdg_dir("XDG_CONFIG_HOME", ".config")
+}
+
+pub fn config_file() -> PathBuf {
+    config_home().join(APP_NAME).join("config.toml")
+}
+
+pub fn state_dir() -> PathBuf {
+    match &overrides().state_dir {
+        Some(dir) => dir.clone(),
+        None => xdg_dir("XDG_STATE_HOME", ".local/state").join(APP_NAME),
+    }
+}
+
+/// Remembered layouts of this session. Concurrent sessions would overwrite
+/// each other's on every save, so like the runtime files it is per session.
+pub fn state_file() -> PathBuf {
+    state_dir().join(state_file_name(session()))
+}
+
+/// File name of the state of `session`.
+pub fn state_file_name(session: &str) -> String {
+    format!("state-{session}.json")
+}
+
+/// Where earlier versions kept the state of all sessions, read once by a
+/// session that has none of its own yet.
+pub fn shared_state_file() -> PathBuf {
+    let overrides = overrides();
+    let name = [
+        overrides.namespace.as_deref(),
+        overrides.instance.as_deref(),
+    ];
+    match name.into_iter().flatten().collect::<Vec<_>>()[..] {
+        [] => state_dir().join("state.json"),
+        ref parts => state_dir().join(format!("state-{}.json", parts.join("-"))),
+    }
+}
+
+pub fn session_state_dir() -> PathBuf {
+    state_dir().join(session())
+}
+
+pub fn xdg_runtime_dir() -> PathBuf {
+    match env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => env::temp_dir(),
+    }
+}
+
+/// Where runtime files such as the pidfile go.
+pub fn runtime_dir() -> PathBuf {
+    match &overrides().state_dir {
+        Some(dir) => dir.clone(),
+        None => xdg_runtime_dir(),
+    }
+}
+
+pub fn pid_file() -> PathBuf {
+    runtime_dir().join(format!("{APP_NAME}-{}.pid", session()))
+}
+
+/// Held by the running instance of the session.
+pub fn lock_file() -> PathBuf {
+    runtime_dir().join(format!("{APP_NAME}-{}.lock", session()))
+}
+
+pub fn control_socket() -> PathBuf {
+    runtime_dir().join(format!("{APP_NAME}-{}.sock", session()))
+}
+
+pub fn log_file() -> PathBuf {
+    session_state_dir().join(format!("{APP_NAME}.log"))
+}
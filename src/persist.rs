@@ -0,0 +1,180 @@
+use std::{fs, io, path::Path};
+
+use serde_json::{json, Map, Value};
+
+use crate::{fxhash::FxHashMap, StoredLayout};
+
+/// Bumped whenever the layout of the file changes incompatibly, with a step
+/// added to MIGRATIONS.
+const VERSION: u64 = 3;
+
+/// Steps bringing the file from the version at their index, plus one, to
+/// the next, each knowing only the version before it. Files without a
+/// version are version 1.
+const MIGRATIONS: &[fn(&mut Map<String, Value>)] = &[
+    // Version 1 stored bare layout indices. Without a name recorded, the
+    // index is restored as is.
+    |layouts| {
+        for stored in layouts.values_mut() {
+            *stored = json!({ "name": "", "index": stored.take() });
+        }
+    },
+    // Version 2 did not tell layouts chosen by the user from defaults; as
+    // the user's, all of it is kept.
+    |layouts| {
+        for stored in layouts.values_mut() {
+            stored["user"] = json!(true);
+        }
+    },
+];
+
+/// Brings a file of `version` up to VERSION, one step at a time.
+fn migrate(value: &mut Value, version: u64) {
+    for (step, migration) in MIGRATIONS
+        .iter()
+        .enumerate()
+        .skip(version.max(1) as usize - 1)
+    {
+        debug!("Migrating the state file to version {}", step + 2);
+        let windows = value.get_mut("windows").and_then(Value::as_array_mut);
+        for window in windows.into_iter().flatten() {
+            if let Some(layouts) = window.get_mut("layouts").and_then(Value::as_object_mut) {
+                migration(layouts);
+            }
+        }
+        let workspaces = value.get_mut("workspaces").and_then(Value::as_object_mut);
+        for layouts in workspaces.into_iter().flatten().map(|(_, layouts)| layouts) {
+            if let Some(layouts) = layouts.as_object_mut() {
+                migration(layouts);
+            }
+        }
+    }
+}
+
+/// Remembered layout per input, as kept in `LayoutState::state`.
+pub type Layouts = FxHashMap<String, StoredLayout>;
+
+/// Remembered layouts in a form that survives a restart: container ids are
+/// not stable, so windows are identified by app_id (or class) and title.
+#[derive(Debug, Default, Clone)]
+pub struct Saved {
+    pub windows: Vec<(String, String, Layouts)>,
+    pub workspaces: FxHashMap<String, Layouts>,
+}
+
+impl Saved {
+    /// Takes the layout saved for the window, preferring an exact title match
+    /// over the most recently saved window of the same app.
+    pub fn take(&mut self, app: &str, title: &str) -> Option<Layouts> {
+        let index = self
+            .windows
+            .iter()
+            .position(|(a, t, _)| a == app && t == title)
+            .or_else(|| self.windows.iter().rposition(|(a, _, _)| a == app))?;
+        Some(self.windows.remove(index).2)
+    }
+}
+
+fn layouts_to_json(layouts: &Layouts) -> Value {
+    Value::Object(
+        layouts
+            .iter()
+            .map(|(input, stored)| {
+                let stored =
+                    json!({ "name": stored.name, "index": stored.index, "user": stored.user });
+                (input.clone(), stored)
+            })
+            .collect(),
+    )
+}
+
+fn layouts_from_json(value: &Value) -> Layouts {
+    value
+        .as_object()
+        .into_iter()
+        .flatten()
+        .filter_map(|(input, stored)| {
+            let name = stored["name"].as_str().unwrap_or_default().to_string();
+            let user = stored["user"].as_bool().unwrap_or_default();
+            let index = i32::try_from(stored["index"].as_i64()?).ok()?;
+            Some((input.clone(), StoredLayout { name, index, user }))
+        })
+        .collect()
+}
+
+/// Returns `None` when nothing was saved yet. A file written by a newer
+/// version is an error, so it is neither misread nor overwritten. One
+/// written by an older version is migrated, keeping a copy of it next to
+/// it, e.g. state.json.v2.bak, for going back to that version.
+pub fn load(path: &Path) -> io::Result<Option<Saved>> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    let mut value: Value = serde_json::from_str(&text)?;
+    let version = value["version"].as_u64().unwrap_or(1);
+    if version > VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("state file version {version} is newer than {VERSION}"),
+        ));
+    }
+    if version < VERSION {
+        let backup = path.with_extension(format!("json.v{version}.bak"));
+        if !backup.exists() {
+            fs::write(&backup, &text)?;
+            info!(
+                "Kept the version {version} state file as {}",
+                backup.display()
+            );
+        }
+        migrate(&mut value, version);
+    }
+    let windows = value["windows"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|window| {
+            Some((
+                window["app"].as_str()?.to_string(),
+                window["title"].as_str().unwrap_or_default().to_string(),
+                layouts_from_json(&window["layouts"]),
+            ))
+        })
+        .collect();
+    let workspaces = value["workspaces"]
+        .as_object()
+        .into_iter()
+        .flatten()
+        .map(|(name, layouts)| (name.clone(), layouts_from_json(layouts)))
+        .collect();
+    Ok(Some(Saved {
+        windows,
+        workspaces,
+    }))
+}
+
+/// Writes through a temporary file so a crash never leaves half a file.
+pub fn store(path: &Path, saved: &Saved) -> io::Result<()> {
+    let windows: Vec<Value> = saved
+        .windows
+        .iter()
+        .map(|(app, title, layouts)| {
+            json!({ "app": app, "title": title, "layouts": layouts_to_json(layouts) })
+        })
+        .collect();
+    let workspaces: Map<String, Value> = saved
+        .workspaces
+        .iter()
+        .map(|(name, layouts)| (name.clone(), layouts_to_json(layouts)))
+        .collect();
+    let value = json!({ "version": VERSION, "windows": windows, "workspaces": workspaces });
+
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let tmp = path.with_extension("json.tmp");
+    fs::write(&tmp, serde_json::to_vec_pretty(&value)?)?;
+    fs::rename(tmp, path)
+}
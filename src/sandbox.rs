@@ -0,0 +1,253 @@
+use std::{ffi::CString, fs, io, os::unix::ffi::OsStrExt, path::Path};
+
+const LANDLOCK_CREATE_RULESET_VERSION: u32 = 1 << 0;
+const LANDLOCK_RULE_PATH_BENEATH: u32 = 1;
+
+const ACCESS_FS_EXECUTE: u64 = 1 << 0;
+const ACCESS_FS_READ_FILE: u64 = 1 << 2;
+const ACCESS_FS_READ_DIR: u64 = 1 << 3;
+const ACCESS_FS_REMOVE_FILE: u64 = 1 << 5;
+const ACCESS_FS_MAKE_SOCK: u64 = 1 << 9;
+/// Every filesystem access right of Landlock ABI 1.
+const ACCESS_FS_ALL: u64 = (1 << 13) - 1;
+const ACCESS_FS_READ: u64 = ACCESS_FS_READ_FILE | ACCESS_FS_READ_DIR;
+const ACCESS_FS_EXEC: u64 = ACCESS_FS_READ | ACCESS_FS_EXECUTE;
+/// Binding a socket and removing a stale one, nothing else.
+const ACCESS_FS_SOCKET: u64 = ACCESS_FS_READ | ACCESS_FS_REMOVE_FILE | ACCESS_FS_MAKE_SOCK;
+
+#[repr(C)]
+struct RulesetAttr {
+    handled_access_fs: u64,
+}
+
+#[repr(C, packed)]
+struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: i32,
+}
+
+fn check(ret: libc::c_long) -> io::Result<libc::c_long> {
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
+
+fn add_rule(ruleset: i32, path: &Path, access: u64) -> io::Result<()> {
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    let fd = unsafe { libc::open(c_path.as_ptr(), libc::O_PATH | libc::O_CLOEXEC) };
+    if fd < 0 {
+        // Paths that do not exist (yet) are simply not reachable.
+        return Ok(());
+    }
+    let attr = PathBeneathAttr {
+        allowed_access: access,
+        parent_fd: fd,
+    };
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_add_rule,
+            ruleset,
+            LANDLOCK_RULE_PATH_BENEATH,
+            &attr as *const PathBeneathAttr,
+            0,
+        )
+    };
+    unsafe { libc::close(fd) };
+    check(ret).map(|_| ())
+}
+
+/// Restricts the calling thread and the threads it spawns afterwards, but
+/// not those already running: Landlock has no way to sync them.
+pub fn landlock(
+    read: &[&Path],
+    write: &[&Path],
+    exec: &[&Path],
+    sockets: &[&Path],
+) -> io::Result<()> {
+    let abi = check(unsafe {
+        libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            std::ptr::null::<RulesetAttr>(),
+            0,
+            LANDLOCK_CREATE_RULESET_VERSION,
+        )
+    })?;
+    debug!("Landlock ABI version {abi}");
+
+    let attr = RulesetAttr {
+        handled_access_fs: ACCESS_FS_ALL,
+    };
+    let ruleset = check(unsafe {
+        libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            &attr as *const RulesetAttr,
+            std::mem::size_of::<RulesetAttr>(),
+            0,
+        )
+    })? as i32;
+
+    let mut rules = read
+        .iter()
+        .map(|path| (path, ACCESS_FS_READ))
+        .chain(write.iter().map(|path| (path, ACCESS_FS_ALL)))
+        .chain(exec.iter().map(|path| (path, ACCESS_FS_EXEC)))
+        .chain(sockets.iter().map(|path| (path, ACCESS_FS_SOCKET)));
+    let result = rules
+        .try_for_each(|(path, access)| add_rule(ruleset, path, access))
+        .and_then(|_| {
+            check(unsafe { libc::syscall(libc::SYS_landlock_restrict_self, ruleset, 0) })
+        });
+    unsafe { libc::close(ruleset) };
+    result.map(|_| ())
+}
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xc000_003e;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+/// Syscalls the daemon never needs; they fail with EPERM instead of killing
+/// the process so an overlooked libc code path degrades gracefully.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+const DENIED_SYSCALLS: &[libc::c_long] = &[
+    libc::SYS_ptrace,
+    libc::SYS_process_vm_readv,
+    libc::SYS_process_vm_writev,
+    libc::SYS_mount,
+    libc::SYS_umount2,
+    libc::SYS_pivot_root,
+    libc::SYS_chroot,
+    libc::SYS_unshare,
+    libc::SYS_setns,
+    libc::SYS_kexec_load,
+    libc::SYS_init_module,
+    libc::SYS_finit_module,
+    libc::SYS_delete_module,
+    libc::SYS_bpf,
+    libc::SYS_perf_event_open,
+    libc::SYS_userfaultfd,
+    libc::SYS_keyctl,
+    libc::SYS_add_key,
+    libc::SYS_request_key,
+    libc::SYS_reboot,
+    libc::SYS_swapon,
+    libc::SYS_swapoff,
+    libc::SYS_acct,
+];
+
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+fn seccomp() -> io::Result<()> {
+    const LD_W_ABS: u16 = 0x20;
+    const JEQ_K: u16 = 0x15;
+    const RET_K: u16 = 0x06;
+    // Offsets into struct seccomp_data.
+    const NR: u32 = 0;
+    const ARCH: u32 = 4;
+
+    let stmt = |code, k| libc::sock_filter {
+        code,
+        jt: 0,
+        jf: 0,
+        k,
+    };
+    let deny = libc::SECCOMP_RET_ERRNO | libc::EPERM as u32;
+
+    let mut filter = vec![
+        stmt(LD_W_ABS, ARCH),
+        libc::sock_filter {
+            code: JEQ_K,
+            jt: 1,
+            jf: 0,
+            k: AUDIT_ARCH,
+        },
+        stmt(RET_K, deny),
+        stmt(LD_W_ABS, NR),
+    ];
+    for &nr in DENIED_SYSCALLS {
+        filter.push(libc::sock_filter {
+            code: JEQ_K,
+            jt: 0,
+            jf: 1,
+            k: nr as u32,
+        });
+        filter.push(stmt(RET_K, deny));
+    }
+    filter.push(stmt(RET_K, libc::SECCOMP_RET_ALLOW));
+
+    let prog = libc::sock_fprog {
+        len: filter.len() as u16,
+        filter: filter.as_mut_ptr(),
+    };
+    // TSYNC puts every thread of the process under the filter, or returns
+    // the id of one that cannot take it and installs it nowhere.
+    let ret = check(unsafe {
+        libc::syscall(
+            libc::SYS_seccomp,
+            libc::SECCOMP_SET_MODE_FILTER,
+            libc::SECCOMP_FILTER_FLAG_TSYNC,
+            &prog as *const libc::sock_fprog,
+        )
+    })?;
+    match ret {
+        0 => Ok(()),
+        thread => Err(io::Error::other(format!(
+            "thread {thread} cannot take the filter"
+        ))),
+    }
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+fn seccomp() -> io::Result<()> {
+    Err(io::Error::from(io::ErrorKind::Unsupported))
+}
+
+/// Threads of this process, from /proc.
+fn threads() -> io::Result<usize> {
+    Ok(fs::read_dir("/proc/self/task")?.count())
+}
+
+/// Restricts the process, which must not have spawned any thread yet, for
+/// Landlock to cover the ones it spawns later. Filesystem access is limited
+/// to the `read` and `write` trees, binding sockets in `sockets`, plus the
+/// system dirs needed to spawn helpers such as swaynag. Landlock missing
+/// from older kernels is logged and the daemon keeps running without it;
+/// a seccomp filter that cannot cover every thread is an error.
+pub fn apply(read: &[&Path], write: &[&Path], sockets: &[&Path]) -> io::Result<()> {
+    match threads()? {
+        1 => {}
+        threads => {
+            return Err(io::Error::other(format!(
+                "{threads} threads are running, all but one would escape the sandbox"
+            )))
+        }
+    }
+    if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+        warn!(
+            "Cannot set no_new_privs, not sandboxing: {}",
+            io::Error::last_os_error()
+        );
+        return Ok(());
+    }
+    let exec: Vec<&Path> = ["/usr", "/bin", "/lib", "/lib64", "/etc", "/nix/store"]
+        .iter()
+        .map(Path::new)
+        .collect();
+    match landlock(read, write, &exec, sockets) {
+        Ok(()) => info!("Landlock filesystem sandbox applied"),
+        Err(err) => warn!("Landlock sandbox not applied: {err}"),
+    }
+    match seccomp() {
+        Ok(()) => info!("seccomp filter applied"),
+        Err(err) if err.kind() == io::ErrorKind::Unsupported => {
+            warn!("seccomp filter not applied: {err}")
+        }
+        Err(err) => {
+            return Err(io::Error::other(format!(
+                "seccomp filter not applied: {err}"
+            )))
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,155 @@
+use std::{
+    collections::BTreeSet,
+    fs,
+    io::{self, BufRead, Write},
+    path::{Path, PathBuf},
+};
+
+use swayipc::{Connection, Node, NodeLayout};
+
+use crate::paths;
+
+fn ask(question: &str) -> io::Result<String> {
+    print!("{question} ");
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer)?;
+    Ok(answer.trim().to_string())
+}
+
+fn collect_tabbed(node: &Node, in_tabs: bool, apps: &mut BTreeSet<String>) {
+    if in_tabs {
+        if let Some(app_id) = &node.app_id {
+            apps.insert(app_id.clone());
+        }
+    }
+    let tabs = matches!(node.layout, NodeLayout::Tabbed | NodeLayout::Stacked);
+    for child in node.nodes.iter().chain(node.floating_nodes.iter()) {
+        collect_tabbed(child, tabs, apps);
+    }
+}
+
+fn collect_apps(node: &Node, apps: &mut BTreeSet<String>) {
+    if let Some(app_id) = &node.app_id {
+        apps.insert(app_id.clone());
+    }
+    for child in node.nodes.iter().chain(node.floating_nodes.iter()) {
+        collect_apps(child, apps);
+    }
+}
+
+/// The sway config snippet that starts the daemon.
+fn snippet_path() -> PathBuf {
+    paths::config_home().join("sway/config.d/sway-xkb-switcher")
+}
+
+/// A TOML basic string.
+pub fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Writes `contents` to `path` once confirmed, creating its directory.
+pub fn offer(path: &Path, contents: &str) -> Result<bool, Box<dyn std::error::Error>> {
+    println!("\n{contents}");
+    let verb = if path.exists() { "Replace" } else { "Write" };
+    let answer = ask(&format!("{verb} {} with this? [Y/n]", path.display()))?;
+    if answer.eq_ignore_ascii_case("n") {
+        return Ok(false);
+    }
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(path, contents)?;
+    Ok(true)
+}
+
+/// Asks a few questions about the desired behaviour, writes the answers to
+/// the config file and a sway config snippet that starts the daemon.
+pub fn run(config_file: PathBuf) -> Result<(), Box<dyn std::error::Error>> {
+    let mut conn = Connection::new()?;
+
+    let mut layouts = BTreeSet::new();
+    for input in conn.get_inputs()? {
+        if input.input_type == "keyboard" {
+            layouts.extend(input.xkb_layout_names);
+        }
+    }
+    let layouts: Vec<String> = layouts.into_iter().collect();
+    println!("Layouts configured in sway:");
+    for (idx, layout) in layouts.iter().enumerate() {
+        println!("  {}) {layout}", idx + 1);
+    }
+    let default_lang = loop {
+        let answer = ask("Default layout for new windows (number, empty for none):")?;
+        if answer.is_empty() {
+            break None;
+        }
+        match answer.parse::<usize>() {
+            Ok(n) if (1..=layouts.len()).contains(&n) => break Some(layouts[n - 1].clone()),
+            _ => println!("Please enter a number between 1 and {}.", layouts.len()),
+        }
+    };
+
+    let per_workspace = loop {
+        let answer = ask("Remember the layout per window or per workspace? [W/s]")?;
+        match answer.to_lowercase().as_str() {
+            "" | "w" | "window" => break false,
+            "s" | "workspace" => break true,
+            _ => println!("Please answer w (window) or s (workspace)."),
+        }
+    };
+
+    let tree = conn.get_tree()?;
+    let mut tabbed = BTreeSet::new();
+    collect_tabbed(&tree, false, &mut tabbed);
+    let mut open = BTreeSet::new();
+    collect_apps(&tree, &mut open);
+    println!("Open applications: {}", Vec::from_iter(open).join(", "));
+    println!(
+        "Tabbed applications get a separate layout per tab (window title). \
+         Detected in tabbed/stacked containers: {}",
+        Vec::from_iter(tabbed.iter().cloned()).join(", ")
+    );
+    // Workspaces share one layout whatever the tab.
+    let answer = match per_workspace {
+        true => String::new(),
+        false => ask("Tabbed app_ids, comma separated (empty to accept detected):")?,
+    };
+    let tabbed_apps: Vec<String> = if per_workspace {
+        vec![]
+    } else if answer.is_empty() {
+        tabbed.into_iter().collect()
+    } else {
+        answer
+            .split(',')
+            .map(|app| app.trim().to_string())
+            .filter(|app| !app.is_empty())
+            .collect()
+    };
+
+    let mut config = format!("# Written by {} setup.\n", env!("CARGO_PKG_NAME"));
+    if per_workspace {
+        config.push_str("mode = \"workspace\"\n");
+    }
+    if let Some(lang) = default_lang {
+        config.push_str(&format!("default-lang = {}\n", quote(&lang)));
+    }
+    if !tabbed_apps.is_empty() {
+        let apps: Vec<String> = tabbed_apps.iter().map(|app| quote(app)).collect();
+        config.push_str(&format!("tabbed-apps = [{}]\n", apps.join(", ")));
+    }
+    offer(&config_file, &config)?;
+
+    let snippet = snippet_path();
+    let mut exec = format!("exec {}", env!("CARGO_PKG_NAME"));
+    if config_file != paths::config_file() {
+        exec.push_str(&format!(" --config '{}'", config_file.display()));
+    }
+    if offer(&snippet, &format!("{exec}\n"))? {
+        println!(
+            "Written. Make sure your sway config contains `include {}/*` and reload sway.",
+            snippet.parent().unwrap().display()
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,48 @@
+use std::{io, thread};
+
+use crate::compositor;
+
+fn block(signals: &[libc::c_int]) -> io::Result<libc::sigset_t> {
+    let mut set: libc::sigset_t = unsafe { std::mem::zeroed() };
+    unsafe {
+        libc::sigemptyset(&mut set);
+        for &signal in signals {
+            libc::sigaddset(&mut set, signal);
+        }
+    }
+    let ret = unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut()) };
+    if ret != 0 {
+        return Err(io::Error::from_raw_os_error(ret));
+    }
+    Ok(set)
+}
+
+/// Delivers each signal as a sway tick with its payload, so the event loop
+/// sees it as an ordinary event instead of being interrupted mid-read.
+///
+/// All of them are blocked at once and waited for on one helper thread.
+/// This must run before any other thread is spawned, so that every thread
+/// inherits the blocked mask: a thread that does not could take a SIGTERM
+/// and die of its default action. Without sway to deliver the tick,
+/// SIGTERM and SIGINT exit right away instead of being ignored.
+pub fn install(forwards: Vec<(libc::c_int, String)>) -> io::Result<()> {
+    let signals: Vec<libc::c_int> = forwards.iter().map(|(signal, _)| *signal).collect();
+    let set = block(&signals)?;
+    thread::spawn(move || loop {
+        let mut received = 0;
+        if unsafe { libc::sigwait(&set, &mut received) } != 0 {
+            continue;
+        }
+        let Some((_, payload)) = forwards.iter().find(|(signal, _)| *signal == received) else {
+            continue;
+        };
+        info!("Got signal {received}, sending tick {payload}");
+        if let Err(err) = compositor::connect().and_then(|mut conn| conn.send_tick(payload)) {
+            error!("Cannot forward signal {received} to the event loop: {err}");
+            if matches!(received, libc::SIGTERM | libc::SIGINT) {
+                std::process::exit(128 + received);
+            }
+        }
+    });
+    Ok(())
+}
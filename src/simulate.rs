@@ -0,0 +1,132 @@
+use getopts::Options;
+use swayipc::Connection;
+
+use crate::{
+    workspace_key, Compat, Config, KeyBy, LayoutState, Mode, Window, ALL_KEYBOARDS, MARK_KEY,
+    WORKSPACE_KEY,
+};
+
+/// Runs the key derivation and layout selection against a hypothetical,
+/// newly focused window and prints what the daemon would do.
+pub fn run(config: Config, args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let mut opts = Options::new();
+    opts.optopt("", "app_id", "Wayland app_id of the window.", "<app_id>");
+    opts.optopt("", "class", "Xwayland window class.", "<class>");
+    opts.optopt("", "title", "Window title.", "<title>");
+    opts.optopt("", "workspace", "Workspace the window is on.", "<name>");
+    opts.optmulti("", "mark", "Sway mark of the window.", "<mark>");
+    opts.optflag("", "floating", "The window is a floating dialog.");
+    let matches = opts.parse(args)?;
+
+    let window = Window {
+        id: 0,
+        app_id: matches.opt_str("app_id"),
+        class: matches.opt_str("class"),
+        title: matches.opt_str("title"),
+        pid: None,
+        marks: matches.opt_strs("mark"),
+        floating: matches.opt_present("floating"),
+    };
+    let workspace = matches.opt_str("workspace");
+    let mut state = LayoutState::new(Box::new(Connection::new()?), config);
+
+    println!(
+        "window: app_id={:?} class={:?} title={:?} workspace={:?}",
+        window.app_id, window.class, window.title, workspace
+    );
+
+    if state.is_ignored(&window) {
+        println!(
+            "matched: --ignore-apps or --ignore-floating, focus is not tracked and nothing \
+             is switched"
+        );
+        return Ok(());
+    }
+    let secure = state.is_secure_prompt(&window);
+    // Without --workspace the window opens on the focused one, as the
+    // daemon would see it.
+    let key = match (state.config.mode, &workspace) {
+        (Mode::Workspace, Some(name)) => workspace_key(name),
+        _ => state.focus_key(&window),
+    };
+    let tabbed = window
+        .app_id
+        .as_deref()
+        .is_some_and(|id| state.is_tabbed(id));
+    let kind = if key.starts_with(WORKSPACE_KEY) {
+        "one entry per workspace"
+    } else if key.starts_with(MARK_KEY) {
+        "--group-marks, one entry shared by the windows with this mark"
+    } else {
+        match state.key_by(&window) {
+            KeyBy::App => "--key-by app, one entry shared by the app's windows",
+            KeyBy::AppTitle => "--key-by app+title, one entry per app and title",
+            KeyBy::Window if tabbed => "tabbed app, one entry per title",
+            KeyBy::Window => "one entry per window",
+        }
+    };
+    println!("key: {key:?} ({kind})");
+
+    if let Some(path) = state.app_rule(&state.config.xkb_files, &window) {
+        println!("matched: --xkb-file, loads keymap {path}, never remembered");
+        return Ok(());
+    }
+    if let Some((host, lang)) = state.ssh_layout(&window).filter(|_| !secure) {
+        println!("matched: ssh session to {host} (--ssh-layout), never remembered");
+        let targets = state.layout_targets(&lang);
+        return print_targets(&mut state, targets);
+    }
+    let forced = state.app_rule(&state.config.forced_layouts, &window);
+    let app_default = state.app_rule(&state.config.app_defaults, &window);
+    if let Some(lang) = forced.filter(|_| !secure) {
+        println!("matched: --force-layout {lang}");
+        let targets = state.layout_targets(&lang);
+        return print_targets(&mut state, targets);
+    }
+    if let Some(lang) = app_default.filter(|_| !secure) {
+        println!("matched: no memory, --app-default {lang}");
+        let targets = state.layout_targets(&lang);
+        return print_targets(&mut state, targets);
+    }
+    if secure {
+        println!("matched: password prompt (--secure-apps), never remembered");
+    } else if state.config.continuity {
+        println!("matched: no memory, --continuity keeps the current layout");
+        return Ok(());
+    } else {
+        println!("matched: no memory, default layout");
+    }
+
+    let targets = state.default_targets();
+    if targets.is_empty() {
+        if secure {
+            println!("layout: index 0 on all keyboards");
+        } else {
+            println!("layout: unchanged (no default layout configured or matched)");
+        }
+        return Ok(());
+    }
+    print_targets(&mut state, targets)
+}
+
+fn print_targets(
+    state: &mut LayoutState,
+    targets: Vec<(String, i32)>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let inputs = state.comm_conn.get_inputs()?;
+    for (input_id, lo_idx) in targets {
+        if input_id == ALL_KEYBOARDS && state.config.compat == Some(Compat::Swaykbdd) {
+            println!("layout: index {lo_idx} on all keyboards");
+            continue;
+        }
+        let name = inputs
+            .iter()
+            .find(|input| input.identifier == input_id)
+            .and_then(|input| input.xkb_layout_names.get(lo_idx as usize));
+        println!(
+            "layout: {input_id} -> {lo_idx} ({})",
+            name.map_or("?", |n| n)
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,306 @@
+use std::{
+    cell::RefCell,
+    ffi::OsString,
+    fmt::Write as _,
+    fs::{self, File},
+    io::{self, Write as _},
+    path::PathBuf,
+    sync::{Mutex, OnceLock},
+    time::{Duration, Instant, SystemTime},
+};
+
+use log::{LevelFilter, Record};
+use pretty_env_logger::env_logger::{
+    fmt::{Formatter, WriteStyle},
+    Builder, Target,
+};
+use serde_json::{json, Map, Value};
+
+struct Frame {
+    name: &'static str,
+    fields: Vec<(&'static str, String)>,
+    start: Instant,
+    children: Duration,
+}
+
+struct Profile {
+    folded: Option<Mutex<File>>,
+}
+
+thread_local! {
+    static STACK: RefCell<Vec<Frame>> = const { RefCell::new(Vec::new()) };
+}
+
+static PROFILE: OnceLock<Profile> = OnceLock::new();
+
+/// Turns on span timings. Every closed span is logged with its duration and,
+/// when `folded` is given, its self time in microseconds is appended to that
+/// file in the folded-stack format understood by `flamegraph.pl`/`inferno`.
+pub fn enable_profiling(folded: Option<File>) {
+    let _ = PROFILE.set(Profile {
+        folded: folded.map(Mutex::new),
+    });
+}
+
+/// Guard for a logging span. Every log record emitted while the guard is alive
+/// is prefixed with the span name and its fields, e.g.
+/// `window{change=Focus key=42}:input{id=1:1:AT}: ...`.
+pub struct Span {
+    depth: usize,
+}
+
+impl Span {
+    pub fn enter(name: &'static str, fields: Vec<(&'static str, String)>) -> Span {
+        STACK.with(|stack| {
+            let mut stack = stack.borrow_mut();
+            stack.push(Frame {
+                name,
+                fields,
+                start: Instant::now(),
+                children: Duration::ZERO,
+            });
+            Span { depth: stack.len() }
+        })
+    }
+
+    pub fn record(&self, key: &'static str, value: impl ToString) {
+        STACK.with(|stack| {
+            if let Some(frame) = stack.borrow_mut().get_mut(self.depth - 1) {
+                match frame.fields.iter_mut().find(|(k, _)| *k == key) {
+                    Some((_, v)) => *v = value.to_string(),
+                    None => frame.fields.push((key, value.to_string())),
+                }
+            }
+        })
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        if let Some(profile) = PROFILE.get() {
+            self.finish(profile);
+        }
+        STACK.with(|stack| stack.borrow_mut().truncate(self.depth - 1));
+    }
+}
+
+impl Span {
+    fn finish(&self, profile: &Profile) {
+        let (elapsed, self_time, path) = STACK.with(|stack| {
+            let mut stack = stack.borrow_mut();
+            let elapsed = stack[self.depth - 1].start.elapsed();
+            let self_time = elapsed.saturating_sub(stack[self.depth - 1].children);
+            if self.depth > 1 {
+                stack[self.depth - 2].children += elapsed;
+            }
+            let path: Vec<&str> = stack[..self.depth].iter().map(|f| f.name).collect();
+            (elapsed, self_time, path.join(";"))
+        });
+        info!(target: "profile", "{path} took {elapsed:?}");
+        if let Some(folded) = &profile.folded {
+            if let Ok(mut file) = folded.lock() {
+                let _ = writeln!(file, "{path} {}", self_time.as_micros());
+            }
+        }
+    }
+}
+
+#[macro_export]
+macro_rules! span {
+    ($name:literal $(, $key:ident = $value:expr)* $(,)?) => {
+        $crate::span::Span::enter($name, vec![$((stringify!($key), $value.to_string())),*])
+    };
+}
+
+fn current() -> String {
+    STACK.with(|stack| {
+        let mut out = String::new();
+        for frame in stack.borrow().iter() {
+            out.push_str(frame.name);
+            if !frame.fields.is_empty() {
+                out.push('{');
+                for (i, (key, value)) in frame.fields.iter().enumerate() {
+                    if i > 0 {
+                        out.push(' ');
+                    }
+                    let _ = write!(out, "{key}={value}");
+                }
+                out.push('}');
+            }
+            out.push(':');
+        }
+        out
+    })
+}
+
+/// RUST_LOG_SPAN limits logging to records inside spans matching it.
+fn filtered_out(spans: &str) -> bool {
+    static FILTER: OnceLock<Option<String>> = OnceLock::new();
+    FILTER
+        .get_or_init(|| std::env::var("RUST_LOG_SPAN").ok())
+        .as_ref()
+        .is_some_and(|filter| !spans.contains(filter.as_str()))
+}
+
+fn format(f: &mut Formatter, record: &Record) -> std::io::Result<()> {
+    let level = f.default_styled_level(record.level());
+    let mut style = f.style();
+    let target = style.set_bold(true).value(record.target());
+    let spans = current();
+    if filtered_out(&spans) {
+        return Ok(());
+    }
+    if spans.is_empty() {
+        writeln!(f, " {level:<5} {target} > {}", record.args())
+    } else {
+        writeln!(f, " {level:<5} {target} > {spans} {}", record.args())
+    }
+}
+
+/// One object per line for log aggregators. Span fields are flattened as
+/// `span.field`, e.g. `window.app_id` or `input.layout`, with numbers kept
+/// numeric.
+fn format_json(f: &mut Formatter, record: &Record) -> std::io::Result<()> {
+    if filtered_out(&current()) {
+        return Ok(());
+    }
+    let mut fields = Map::new();
+    let mut spans = vec![];
+    STACK.with(|stack| {
+        for frame in stack.borrow().iter() {
+            spans.push(Value::from(frame.name));
+            for (key, value) in &frame.fields {
+                let value = match value.parse::<i64>() {
+                    Ok(n) => Value::from(n),
+                    Err(_) => Value::from(value.as_str()),
+                };
+                fields.insert(format!("{}.{key}", frame.name), value);
+            }
+        }
+    });
+    let timestamp = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64();
+    let line = json!({
+        "timestamp": timestamp,
+        "level": record.level().as_str(),
+        "target": record.target(),
+        "message": record.args().to_string(),
+        "spans": spans,
+        "fields": fields,
+    });
+    writeln!(f, "{line}")
+}
+
+/// Size at which the log file is moved to `<file>.1`, replacing the one
+/// rotated before.
+const LOG_ROTATE_SIZE: u64 = 10 << 20;
+
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf) -> io::Result<RotatingFile> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let file = File::options().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile { path, file, size })
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let mut old = OsString::from(&self.path);
+        old.push(".1");
+        fs::rename(&self.path, old)?;
+        *self = RotatingFile::open(self.path.clone())?;
+        Ok(())
+    }
+}
+
+impl io::Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.size + buf.len() as u64 > LOG_ROTATE_SIZE {
+            // Better an oversized log than none.
+            let _ = self.rotate();
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+static LOG_FILE: OnceLock<PathBuf> = OnceLock::new();
+
+/// The --log-file in use, which the sandbox has to keep writable.
+pub fn log_file() -> Option<&'static PathBuf> {
+    LOG_FILE.get()
+}
+
+#[derive(Debug, Default)]
+pub struct LogOptions {
+    /// No colors, e.g. when stderr goes to a file.
+    pub plain: bool,
+    /// Overrides RUST_LOG, including its module directives, and the
+    /// targets shown by default such as `dump`.
+    pub level: Option<LevelFilter>,
+    /// Raises the default level, keeping RUST_LOG's module directives.
+    pub verbosity: Option<LevelFilter>,
+    pub file: Option<PathBuf>,
+    /// One JSON object per record instead of text.
+    pub json: bool,
+}
+
+pub fn init_logger(options: LogOptions) {
+    let mut builder = Builder::new();
+    if options.plain || options.file.is_some() {
+        builder.write_style(WriteStyle::Never);
+    }
+    if options.json {
+        builder.format(format_json);
+    } else {
+        builder.format(format);
+    }
+    match options.level {
+        // -q means nothing at all, --log-level exactly that level.
+        Some(level) => {
+            builder.filter_level(level);
+        }
+        None => {
+            builder.filter_level(log::LevelFilter::Error);
+            builder.filter_module("profile", log::LevelFilter::Info);
+            // Asked for with SIGUSR1, so shown without RUST_LOG.
+            builder.filter_module("dump", log::LevelFilter::Info);
+            // What --dry-run is for.
+            builder.filter_module("dry-run", log::LevelFilter::Info);
+            if let Ok(filters) = std::env::var("RUST_LOG") {
+                builder.parse_filters(&filters);
+            }
+            if let Some(level) = options.verbosity {
+                builder.filter_level(level);
+            }
+        }
+    }
+    let mut failed = None;
+    if let Some(path) = options.file {
+        match RotatingFile::open(path.clone()) {
+            Ok(file) => {
+                builder.target(Target::Pipe(Box::new(file)));
+                let _ = LOG_FILE.set(path);
+            }
+            Err(err) => failed = Some(format!("{}: {err}", path.display())),
+        }
+    }
+    builder.init();
+    if let Some(err) = failed {
+        error!("Cannot open the log file {err}, logging to stderr");
+    }
+}
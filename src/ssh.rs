@@ -0,0 +1,60 @@
+use std::fs;
+
+/// ssh options that take an argument, so the host is not mistaken for it.
+const OPTS_WITH_ARG: &str = "BbcDEeFIiJLlmOopQRSWw";
+
+/// Host of the ssh session running in a terminal. The foreground ssh
+/// process below the window's pid is preferred; the title (`ssh user@host`)
+/// is the fallback for terminals whose pid is not the shell's parent.
+pub fn host(title: Option<&str>, pid: Option<i32>) -> Option<String> {
+    pid.and_then(host_from_processes)
+        .or_else(|| title.and_then(|title| host_from_args(title.split_whitespace())))
+}
+
+fn host_from_args<'a>(mut args: impl Iterator<Item = &'a str>) -> Option<String> {
+    args.find(|arg| *arg == "ssh" || arg.ends_with("/ssh"))?;
+    while let Some(arg) = args.next() {
+        if let Some(opts) = arg.strip_prefix('-') {
+            // `-p 22` takes the next word, `-p22` does not.
+            if opts.len() == 1 && OPTS_WITH_ARG.contains(opts) {
+                args.next();
+            }
+            continue;
+        }
+        let host = arg.rsplit_once('@').map_or(arg, |(_, host)| host);
+        return Some(host.trim_end_matches(':').to_string());
+    }
+    None
+}
+
+fn children(pid: i32) -> Vec<i32> {
+    let Ok(tasks) = fs::read_dir(format!("/proc/{pid}/task")) else {
+        return vec![];
+    };
+    tasks
+        .flatten()
+        .filter_map(|task| fs::read_to_string(task.path().join("children")).ok())
+        .flat_map(|children| {
+            children
+                .split_whitespace()
+                .filter_map(|pid| pid.parse().ok())
+                .collect::<Vec<i32>>()
+        })
+        .collect()
+}
+
+fn host_from_processes(pid: i32) -> Option<String> {
+    let mut pending = children(pid);
+    // Bounded so a fork bomb in a terminal cannot stall the daemon.
+    for _ in 0..256 {
+        let pid = pending.pop()?;
+        if let Ok(cmdline) = fs::read(format!("/proc/{pid}/cmdline")) {
+            let cmdline = String::from_utf8_lossy(&cmdline);
+            if let Some(host) = host_from_args(cmdline.split('\0')) {
+                return Some(host);
+            }
+        }
+        pending.extend(children(pid));
+    }
+    None
+}
@@ -0,0 +1,268 @@
+use std::{
+    collections::VecDeque,
+    fmt::Write as _,
+    io::{self, BufRead, BufReader, Write},
+    net::TcpListener,
+    sync::{Arc, Mutex, MutexGuard},
+    thread,
+    time::{Duration, Instant},
+};
+
+use serde_json::{json, Value};
+use swayipc::{Event, EventType, Fallible, Input, Seat, Version, Workspace};
+
+use crate::{
+    compositor::{Compositor, Events},
+    fxhash::FxHashMap,
+};
+
+#[derive(Debug, Default)]
+struct Counters {
+    events: u64,
+    switches: u64,
+    ipc_errors: u64,
+    /// Switches per app_id (or class), `-` for workspaces.
+    app_switches: FxHashMap<String, u64>,
+    /// Sizes of the daemon's maps, e.g. `remembered`, as of the last event.
+    sizes: Vec<(&'static str, usize)>,
+    /// The last RECENT switches as app, layout and when, oldest first.
+    recent: VecDeque<(String, String, Instant)>,
+}
+
+/// How many switches `status --json` lists.
+const RECENT: usize = 20;
+
+/// Counters for `status --json` and --metrics, shared with the thread that
+/// serves the latter.
+#[derive(Debug, Clone)]
+pub struct Stats {
+    counters: Arc<Mutex<Counters>>,
+    started: Instant,
+}
+
+impl Default for Stats {
+    fn default() -> Stats {
+        Stats {
+            counters: Arc::default(),
+            started: Instant::now(),
+        }
+    }
+}
+
+impl Stats {
+    fn counters(&self) -> MutexGuard<'_, Counters> {
+        self.counters.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    pub fn event(&self) {
+        self.counters().events += 1;
+    }
+
+    pub fn switch(&self, app: &str, layout: &str) {
+        let mut counters = self.counters();
+        counters.switches += 1;
+        *counters.app_switches.entry(app.to_string()).or_default() += 1;
+        if counters.recent.len() == RECENT {
+            counters.recent.pop_front();
+        }
+        counters
+            .recent
+            .push_back((app.to_string(), layout.to_string(), Instant::now()));
+    }
+
+    pub fn ipc_error(&self) {
+        self.counters().ipc_errors += 1;
+    }
+
+    pub fn set_sizes(&self, sizes: Vec<(&'static str, usize)>) {
+        self.counters().sizes = sizes;
+    }
+
+    pub fn to_json(&self) -> Value {
+        let counters = self.counters();
+        let sizes: serde_json::Map<String, Value> = counters
+            .sizes
+            .iter()
+            .map(|(name, size)| (name.to_string(), json!(size)))
+            .collect();
+        let recent: Vec<Value> = counters
+            .recent
+            .iter()
+            .map(|(app, layout, at)| {
+                json!({ "app": app, "layout": layout, "seconds_ago": at.elapsed().as_secs() })
+            })
+            .collect();
+        json!({
+            "uptime_seconds": self.started.elapsed().as_secs(),
+            "events": counters.events,
+            "switches": counters.switches,
+            "ipc_errors": counters.ipc_errors,
+            "app_switches": counters.app_switches,
+            "sizes": sizes,
+            "recent": recent,
+        })
+    }
+
+    /// The counters in the Prometheus text exposition format.
+    pub fn to_prometheus(&self) -> String {
+        let counters = self.counters();
+        let mut text = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, samples: Vec<(String, f64)>| {
+            let _ = writeln!(text, "# HELP xkb_switcher_{name} {help}");
+            let _ = writeln!(text, "# TYPE xkb_switcher_{name} {kind}");
+            for (labels, value) in samples {
+                let _ = writeln!(text, "xkb_switcher_{name}{labels} {value}");
+            }
+        };
+        let single = |value: f64| vec![(String::new(), value)];
+        metric(
+            "uptime_seconds",
+            "gauge",
+            "Seconds since the daemon started.",
+            single(self.started.elapsed().as_secs_f64()),
+        );
+        metric(
+            "events_total",
+            "counter",
+            "Sway events handled.",
+            single(counters.events as f64),
+        );
+        metric(
+            "switches_total",
+            "counter",
+            "Keyboards switched to another layout.",
+            single(counters.switches as f64),
+        );
+        metric(
+            "ipc_errors_total",
+            "counter",
+            "Failed sway IPC requests and unreadable events.",
+            single(counters.ipc_errors as f64),
+        );
+        metric(
+            "app_switches_total",
+            "counter",
+            "Keyboards switched to another layout, per app.",
+            counters
+                .app_switches
+                .iter()
+                .map(|(app, count)| (format!("{{app=\"{}\"}}", escape(app)), *count as f64))
+                .collect(),
+        );
+        metric(
+            "entries",
+            "gauge",
+            "Entries in the daemon's maps.",
+            counters
+                .sizes
+                .iter()
+                .map(|(map, size)| (format!("{{map=\"{map}\"}}"), *size as f64))
+                .collect(),
+        );
+        text
+    }
+
+    /// Answers every HTTP request on `addr` with the metrics, e.g. for
+    /// `--metrics 127.0.0.1:9101`.
+    pub fn serve(&self, addr: &str) -> io::Result<()> {
+        let listener = TcpListener::bind(addr)?;
+        info!(
+            "Serving metrics on http://{}/metrics",
+            listener.local_addr()?
+        );
+        let stats = self.clone();
+        thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                // Requests are answered one at a time, so a client that
+                // sends nothing must not hold up the next one.
+                let _ = stream.set_read_timeout(Some(Duration::from_secs(2)));
+                let _ = stream.set_write_timeout(Some(Duration::from_secs(2)));
+                // Only the request line matters; the rest is not waited for.
+                let mut request = String::new();
+                let _ = BufReader::new(&stream).read_line(&mut request);
+                let body = stats.to_prometheus();
+                let response = format!(
+                    "HTTP/1.0 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\n\
+                     Content-Length: {}\r\n\r\n{body}",
+                    body.len()
+                );
+                if let Err(err) = stream.write_all(response.as_bytes()) {
+                    debug!("Cannot answer a metrics request: {err}");
+                }
+            }
+        });
+        Ok(())
+    }
+}
+
+fn escape(label: &str) -> String {
+    label
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Counts the failed requests of the connection it wraps.
+#[derive(Debug)]
+pub struct Counted {
+    inner: Box<dyn Compositor>,
+    stats: Stats,
+}
+
+impl Counted {
+    pub fn new(inner: Box<dyn Compositor>, stats: Stats) -> Counted {
+        Counted { inner, stats }
+    }
+
+    fn count<T>(&self, result: Fallible<T>) -> Fallible<T> {
+        if result.is_err() {
+            self.stats.ipc_error();
+        }
+        result
+    }
+}
+
+impl Compositor for Counted {
+    fn get_inputs(&mut self) -> Fallible<Vec<Input>> {
+        let result = self.inner.get_inputs();
+        self.count(result)
+    }
+
+    fn get_seats(&mut self) -> Fallible<Vec<Seat>> {
+        let result = self.inner.get_seats();
+        self.count(result)
+    }
+
+    fn get_workspaces(&mut self) -> Fallible<Vec<Workspace>> {
+        let result = self.inner.get_workspaces();
+        self.count(result)
+    }
+
+    fn get_version(&mut self) -> Fallible<Version> {
+        let result = self.inner.get_version();
+        self.count(result)
+    }
+
+    fn run_command(&mut self, command: String) -> Fallible<Vec<Fallible<()>>> {
+        let result = self.inner.run_command(command);
+        if result
+            .as_ref()
+            .is_ok_and(|outcomes| outcomes.iter().any(Result::is_err))
+        {
+            self.stats.ipc_error();
+        }
+        self.count(result)
+    }
+
+    fn subscribe(&mut self, events: &[EventType]) -> Fallible<Events> {
+        let result = self.inner.subscribe(events);
+        let stats = self.stats.clone();
+        self.count(result).map(|events| -> Events {
+            Box::new(events.inspect(move |event: &Fallible<Event>| {
+                if event.is_err() {
+                    stats.ipc_error();
+                }
+            }))
+        })
+    }
+}
@@ -0,0 +1,103 @@
+use std::{
+    env,
+    os::{
+        linux::net::SocketAddrExt,
+        unix::{
+            ffi::OsStrExt,
+            net::{SocketAddr, UnixDatagram},
+        },
+    },
+    sync::OnceLock,
+    thread,
+    time::Duration,
+};
+
+use swayipc::Connection;
+
+use crate::compositor;
+
+struct Notifier {
+    socket: UnixDatagram,
+    addr: SocketAddr,
+}
+
+/// Opened on first use, which must be before the sandbox is applied.
+fn notifier() -> Option<&'static Notifier> {
+    static NOTIFIER: OnceLock<Option<Notifier>> = OnceLock::new();
+    NOTIFIER
+        .get_or_init(|| {
+            let path = env::var_os("NOTIFY_SOCKET")?;
+            let addr = match path.as_bytes().strip_prefix(b"@") {
+                Some(name) => SocketAddr::from_abstract_name(name),
+                None => SocketAddr::from_pathname(&path),
+            };
+            let notifier = addr.and_then(|addr| {
+                Ok(Notifier {
+                    socket: UnixDatagram::unbound()?,
+                    addr,
+                })
+            });
+            match notifier {
+                Ok(notifier) => Some(notifier),
+                Err(err) => {
+                    warn!("Cannot notify systemd at {path:?}: {err}");
+                    None
+                }
+            }
+        })
+        .as_ref()
+}
+
+/// Whether the daemon runs as a `Type=notify` systemd service.
+pub fn enabled() -> bool {
+    notifier().is_some()
+}
+
+/// Sends a state like `READY=1` to systemd, if it asked for them.
+pub fn notify(state: &str) {
+    let Some(notifier) = notifier() else {
+        return;
+    };
+    if let Err(err) = notifier
+        .socket
+        .send_to_addr(state.as_bytes(), &notifier.addr)
+    {
+        debug!("Cannot send {state:?} to systemd: {err}");
+    }
+}
+
+/// With `WatchdogSec=`, sends `payload` as a sway tick at half the timeout.
+/// The event loop answers the tick with `WATCHDOG=1`, so a loop stuck
+/// anywhere gets the service restarted.
+pub fn start_watchdog(payload: String) {
+    let Some(usec) = env::var("WATCHDOG_USEC")
+        .ok()
+        .and_then(|usec| usec.parse::<u64>().ok())
+    else {
+        return;
+    };
+    // After --daemonize the pings would come from the wrong process.
+    if env::var("WATCHDOG_PID").is_ok_and(|pid| pid != std::process::id().to_string()) {
+        return;
+    }
+    let interval = Duration::from_micros(usec / 2);
+    info!("Pinging the systemd watchdog every {interval:?}");
+    thread::spawn(move || {
+        let mut sway: Option<Connection> = None;
+        loop {
+            thread::sleep(interval);
+            let sent = match &mut sway {
+                Some(conn) => conn.send_tick(&payload),
+                None => compositor::connect().and_then(|mut conn| {
+                    let sent = conn.send_tick(&payload);
+                    sway = Some(conn);
+                    sent
+                }),
+            };
+            if let Err(err) = sent {
+                debug!("Cannot send the watchdog tick: {err}");
+                sway = None;
+            }
+        }
+    });
+}
@@ -0,0 +1,411 @@
+use std::fmt;
+
+/// The part of TOML the config file needs: strings, integers, booleans,
+/// arrays, inline tables, `[table]` and `[[array of tables]]` headers.
+/// Dates, floats and dotted keys are rejected rather than misread.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    String(String),
+    Integer(i64),
+    Boolean(bool),
+    Array(Vec<Value>),
+    Table(Table),
+}
+
+/// Keys in file order, so errors and merged settings follow the file.
+pub type Table = Vec<(String, Value)>;
+
+impl Value {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::String(_) => "string",
+            Value::Integer(_) => "integer",
+            Value::Boolean(_) => "boolean",
+            Value::Array(_) => "array",
+            Value::Table(_) => "table",
+        }
+    }
+}
+
+/// Writes the value back as TOML, for error messages.
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::String(s) => write!(f, "{s:?}"),
+            Value::Integer(n) => write!(f, "{n}"),
+            Value::Boolean(b) => write!(f, "{b}"),
+            Value::Array(items) => {
+                let items: Vec<String> = items.iter().map(ToString::to_string).collect();
+                write!(f, "[{}]", items.join(", "))
+            }
+            Value::Table(table) => {
+                let entries: Vec<String> =
+                    table.iter().map(|(k, v)| format!("{k} = {v}")).collect();
+                write!(f, "{{ {} }}", entries.join(", "))
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ParseError {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "line {}, column {}: {}",
+            self.line, self.column, self.message
+        )
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+struct Parser<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+    line: usize,
+    column: usize,
+}
+
+type Result<T> = std::result::Result<T, ParseError>;
+
+pub fn parse(input: &str) -> Result<Table> {
+    let mut parser = Parser {
+        chars: input.chars().peekable(),
+        line: 1,
+        column: 1,
+    };
+    parser.document()
+}
+
+/// Line and column of the first line of `input` setting `key`, as
+/// `key = ...`, `[key]` or `[[key]]`, with `_` and `-` taken as the same.
+pub fn locate(input: &str, key: &str) -> Option<(usize, usize)> {
+    let key = key.replace('_', "-");
+    input.lines().enumerate().find_map(|(index, line)| {
+        let rest = line.trim_start();
+        let column = line.len() - rest.len() + 1;
+        let (column, rest) = match rest.trim_start_matches('[') {
+            header if header.len() < rest.len() => (column + rest.len() - header.len(), header),
+            _ => (column, rest),
+        };
+        let name = match rest.chars().next()? {
+            quote @ ('"' | '\'') => rest[1..].split(quote).next()?,
+            _ => rest.split(|c| !is_bare_key(c)).next()?,
+        };
+        (name.replace('_', "-") == key).then_some((index + 1, column))
+    })
+}
+
+fn is_bare_key(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '-'
+}
+
+impl Parser<'_> {
+    fn error<T>(&self, message: impl Into<String>) -> Result<T> {
+        Err(ParseError {
+            line: self.line,
+            column: self.column,
+            message: message.into(),
+        })
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        self.chars.peek().copied()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.chars.next()?;
+        if c == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+        Some(c)
+    }
+
+    fn expect(&mut self, expected: char) -> Result<()> {
+        match self.peek() {
+            Some(c) if c == expected => {
+                self.bump();
+                Ok(())
+            }
+            Some(c) => self.error(format!("expected '{expected}', found '{c}'")),
+            None => self.error(format!("expected '{expected}', found end of file")),
+        }
+    }
+
+    fn skip_spaces(&mut self) {
+        while matches!(self.peek(), Some(' ' | '\t')) {
+            self.bump();
+        }
+    }
+
+    fn skip_comment(&mut self) {
+        if self.peek() == Some('#') {
+            while !matches!(self.peek(), None | Some('\n')) {
+                self.bump();
+            }
+        }
+    }
+
+    /// Whitespace, newlines and comments, as allowed inside arrays.
+    fn skip_blank(&mut self) {
+        loop {
+            self.skip_spaces();
+            self.skip_comment();
+            match self.peek() {
+                Some('\n' | '\r') => {
+                    self.bump();
+                }
+                _ => return,
+            }
+        }
+    }
+
+    fn end_of_line(&mut self) -> Result<()> {
+        self.skip_spaces();
+        self.skip_comment();
+        if self.peek() == Some('\r') {
+            self.bump();
+        }
+        match self.peek() {
+            None => Ok(()),
+            Some('\n') => {
+                self.bump();
+                Ok(())
+            }
+            Some(c) => self.error(format!("unexpected '{c}' after value")),
+        }
+    }
+
+    fn document(&mut self) -> Result<Table> {
+        let mut root = Table::new();
+        // Path of the current [table] or [[array]] header.
+        let mut current: Option<(String, bool)> = None;
+        loop {
+            self.skip_blank();
+            match self.peek() {
+                None => return Ok(root),
+                Some('[') => {
+                    self.bump();
+                    let array = self.peek() == Some('[');
+                    if array {
+                        self.bump();
+                    }
+                    self.skip_spaces();
+                    let name = self.key()?;
+                    self.skip_spaces();
+                    self.expect(']')?;
+                    if array {
+                        self.expect(']')?;
+                    }
+                    self.end_of_line()?;
+                    self.open_table(&mut root, &name, array)?;
+                    current = Some((name, array));
+                }
+                Some(_) => {
+                    let (line, column) = (self.line, self.column);
+                    let key = self.key()?;
+                    self.skip_spaces();
+                    self.expect('=')?;
+                    self.skip_spaces();
+                    let value = self.value()?;
+                    self.end_of_line()?;
+                    let table = match &current {
+                        None => &mut root,
+                        Some((name, array)) => table_mut(&mut root, name, *array),
+                    };
+                    if table.iter().any(|(k, _)| *k == key) {
+                        return Err(ParseError {
+                            line,
+                            column,
+                            message: format!("duplicate key '{key}'"),
+                        });
+                    }
+                    table.push((key, value));
+                }
+            }
+        }
+    }
+
+    fn open_table(&self, root: &mut Table, name: &str, array: bool) -> Result<()> {
+        match root.iter_mut().find(|(k, _)| k == name) {
+            None if array => {
+                root.push((name.to_string(), Value::Array(vec![Value::Table(vec![])])))
+            }
+            None => root.push((name.to_string(), Value::Table(vec![]))),
+            Some((_, Value::Array(items))) if array => items.push(Value::Table(vec![])),
+            Some(_) => return self.error(format!("table '{name}' defined twice")),
+        }
+        Ok(())
+    }
+
+    fn key(&mut self) -> Result<String> {
+        let key = match self.peek() {
+            Some('"') => self.basic_string()?,
+            Some('\'') => self.literal_string()?,
+            _ => {
+                let mut key = String::new();
+                while let Some(c) = self.peek().filter(|c| is_bare_key(*c)) {
+                    key.push(c);
+                    self.bump();
+                }
+                if key.is_empty() {
+                    return match self.peek() {
+                        Some(c) => self.error(format!("expected a key, found '{c}'")),
+                        None => self.error("expected a key"),
+                    };
+                }
+                key
+            }
+        };
+        if self.peek() == Some('.') {
+            return self.error("dotted keys are not supported");
+        }
+        Ok(key)
+    }
+
+    fn value(&mut self) -> Result<Value> {
+        match self.peek() {
+            Some('"') => self.basic_string().map(Value::String),
+            Some('\'') => self.literal_string().map(Value::String),
+            Some('[') => self.array(),
+            Some('{') => self.inline_table(),
+            Some(c) if c.is_ascii_alphanumeric() || c == '+' || c == '-' => self.scalar(),
+            Some(c) => self.error(format!("expected a value, found '{c}'")),
+            None => self.error("expected a value"),
+        }
+    }
+
+    fn scalar(&mut self) -> Result<Value> {
+        let mut word = String::new();
+        while let Some(c) = self.peek().filter(|c| is_bare_key(*c) || *c == '+') {
+            word.push(c);
+            self.bump();
+        }
+        match word.as_str() {
+            "true" => return Ok(Value::Boolean(true)),
+            "false" => return Ok(Value::Boolean(false)),
+            _ => {}
+        }
+        match word.replace('_', "").parse::<i64>() {
+            Ok(n) => Ok(Value::Integer(n)),
+            Err(_) if matches!(self.peek(), Some('.' | ':')) => {
+                self.error("floats and dates are not supported")
+            }
+            Err(_) => self.error(format!("invalid value '{word}', strings must be quoted")),
+        }
+    }
+
+    fn basic_string(&mut self) -> Result<String> {
+        self.expect('"')?;
+        let mut out = String::new();
+        loop {
+            match self.bump() {
+                None | Some('\n') => return self.error("unterminated string"),
+                Some('"') => return Ok(out),
+                Some('\\') => match self.bump() {
+                    Some('"') => out.push('"'),
+                    Some('\\') => out.push('\\'),
+                    Some('n') => out.push('\n'),
+                    Some('t') => out.push('\t'),
+                    Some('r') => out.push('\r'),
+                    Some('u') => {
+                        let hex: String = (0..4).filter_map(|_| self.bump()).collect();
+                        match u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32) {
+                            Some(c) => out.push(c),
+                            None => return self.error(format!("invalid escape \\u{hex}")),
+                        }
+                    }
+                    Some(c) => return self.error(format!("invalid escape \\{c}")),
+                    None => return self.error("unterminated string"),
+                },
+                Some(c) => out.push(c),
+            }
+        }
+    }
+
+    fn literal_string(&mut self) -> Result<String> {
+        self.expect('\'')?;
+        let mut out = String::new();
+        loop {
+            match self.bump() {
+                None | Some('\n') => return self.error("unterminated string"),
+                Some('\'') => return Ok(out),
+                Some(c) => out.push(c),
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Value> {
+        self.expect('[')?;
+        let mut items = vec![];
+        loop {
+            self.skip_blank();
+            if self.peek() == Some(']') {
+                self.bump();
+                return Ok(Value::Array(items));
+            }
+            items.push(self.value()?);
+            self.skip_blank();
+            match self.peek() {
+                Some(',') => {
+                    self.bump();
+                }
+                Some(']') => {}
+                _ => return self.error("expected ',' or ']' in array"),
+            }
+        }
+    }
+
+    fn inline_table(&mut self) -> Result<Value> {
+        self.expect('{')?;
+        let mut table = Table::new();
+        self.skip_spaces();
+        if self.peek() == Some('}') {
+            self.bump();
+            return Ok(Value::Table(table));
+        }
+        loop {
+            self.skip_spaces();
+            let key = self.key()?;
+            self.skip_spaces();
+            self.expect('=')?;
+            self.skip_spaces();
+            let value = self.value()?;
+            if table.iter().any(|(k, _)| *k == key) {
+                return self.error(format!("duplicate key '{key}'"));
+            }
+            table.push((key, value));
+            self.skip_spaces();
+            match self.bump() {
+                Some(',') => {}
+                Some('}') => return Ok(Value::Table(table)),
+                _ => return self.error("expected ',' or '}' in inline table"),
+            }
+        }
+    }
+}
+
+fn table_mut<'a>(root: &'a mut Table, name: &str, array: bool) -> &'a mut Table {
+    let value = &mut root
+        .iter_mut()
+        .find(|(k, _)| k == name)
+        .expect("header was opened before its keys")
+        .1;
+    match value {
+        Value::Table(table) if !array => table,
+        Value::Array(items) => match items.last_mut() {
+            Some(Value::Table(table)) => table,
+            _ => unreachable!("[[{name}]] always ends with a table"),
+        },
+        _ => unreachable!("[{name}] is a table"),
+    }
+}
@@ -0,0 +1,73 @@
+use std::io::{self, Write};
+
+use serde_json::json;
+use swayipc::{Connection, Event, EventType, WindowChange};
+
+use crate::{client, focused_leaf, short_layout_tag, tick_command};
+
+fn focused_app(conn: &mut Connection) -> Option<String> {
+    let tree = conn.get_tree().ok()?;
+    let window = focused_leaf(tree)?;
+    window.app_id.or_else(|| window.window_properties?.class)
+}
+
+/// Prints a line of JSON for Waybar's `custom` module (`"return-type":
+/// "json"`) whenever the active layout or the focused app changes. It only
+/// listens to sway, so it never competes with the daemon's switching;
+/// `class` also carries "paused" or "frozen" while the daemon is, as of the
+/// last focus change or tick command.
+pub fn run() -> Result<(), Box<dyn std::error::Error>> {
+    let mut conn = Connection::new()?;
+    let events =
+        Connection::new()?.subscribe([EventType::Input, EventType::Window, EventType::Tick])?;
+    let mut last = None;
+    let mut stdout = io::stdout();
+    // Print once right away so the module is not empty until the first event.
+    for event in std::iter::once(None).chain(events.map(Some)) {
+        match event.transpose()? {
+            Some(Event::Window(w)) if w.change != WindowChange::Focus => continue,
+            // Asking for the status below sends a control tick itself.
+            Some(Event::Tick(tick))
+                if matches!(
+                    tick_command(&tick.payload),
+                    None | Some("control" | "watchdog" | "debounce")
+                ) =>
+            {
+                continue
+            }
+            _ => {}
+        }
+        let Some(layout) = conn
+            .get_inputs()?
+            .into_iter()
+            .find(|input| input.input_type == "keyboard")
+            .and_then(|input| input.xkb_active_layout_name)
+        else {
+            continue;
+        };
+        let tag = short_layout_tag(&layout);
+        let app = focused_app(&mut conn);
+        let status = client::request("status").ok();
+        let mut class = vec![tag.to_lowercase()];
+        for flag in ["paused", "frozen"] {
+            if status
+                .as_ref()
+                .and_then(|status| status[flag].as_bool())
+                .unwrap_or_default()
+            {
+                class.push(flag.to_string());
+            }
+        }
+        let tooltip = match &app {
+            Some(app) => format!("{layout} ({app})"),
+            None => layout.clone(),
+        };
+        let line = json!({ "text": tag, "alt": layout, "tooltip": tooltip, "class": class });
+        if last.as_ref() != Some(&line) {
+            writeln!(stdout, "{line}")?;
+            stdout.flush()?;
+            last = Some(line);
+        }
+    }
+    Ok(())
+}
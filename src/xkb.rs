@@ -0,0 +1,100 @@
+use std::{env, fs, path::PathBuf, sync::OnceLock};
+
+/// Layout and variant descriptions from xkeyboard-config, the names sway
+/// reports in `xkb_layout_names`.
+#[derive(Debug, Default)]
+struct Registry {
+    /// `(layout, description)`, e.g. `("ru", "Russian")`.
+    layouts: Vec<(String, String)>,
+    /// `(layout, variant, description)`, e.g.
+    /// `("us", "dvorak", "English (Dvorak)")`.
+    variants: Vec<(String, String, String)>,
+}
+
+fn rules_file() -> PathBuf {
+    let root = env::var_os("XKB_CONFIG_ROOT")
+        .filter(|root| !root.is_empty())
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("/usr/share/X11/xkb"));
+    root.join("rules/evdev.lst")
+}
+
+fn parse(text: &str) -> Registry {
+    let mut registry = Registry::default();
+    let mut section = "";
+    for line in text.lines() {
+        if let Some(name) = line.strip_prefix('!') {
+            section = name.trim();
+            continue;
+        }
+        let Some((code, description)) = line.trim().split_once(char::is_whitespace) else {
+            continue;
+        };
+        let description = description.trim();
+        match section {
+            "layout" => registry
+                .layouts
+                .push((code.to_string(), description.to_string())),
+            "variant" => {
+                if let Some((layout, description)) = description.split_once(": ") {
+                    registry.variants.push((
+                        layout.to_string(),
+                        code.to_string(),
+                        description.to_string(),
+                    ));
+                }
+            }
+            _ => {}
+        }
+    }
+    registry
+}
+
+fn registry() -> &'static Registry {
+    static REGISTRY: OnceLock<Registry> = OnceLock::new();
+    REGISTRY.get_or_init(|| {
+        let path = rules_file();
+        match fs::read_to_string(&path) {
+            Ok(text) => parse(&text),
+            Err(err) => {
+                warn!(
+                    "Cannot read xkb layout names from {}: {err}",
+                    path.display()
+                );
+                Registry::default()
+            }
+        }
+    })
+}
+
+/// Reads the registry now, before the sandbox hides /usr/share.
+pub fn preload() {
+    registry();
+}
+
+/// Descriptions a short xkb name like `ru` or `us(dvorak)` stands for, most
+/// specific first: the variant, else the layout followed by its variants.
+pub fn descriptions(wanted: &str) -> Vec<&'static str> {
+    let registry = registry();
+    let wanted = wanted.trim();
+    if let Some((layout, variant)) = wanted.strip_suffix(')').and_then(|w| w.split_once('(')) {
+        let (layout, variant) = (layout.trim(), variant.trim());
+        return registry
+            .variants
+            .iter()
+            .filter(|(l, v, _)| l == layout && v == variant)
+            .map(|(_, _, description)| description.as_str())
+            .collect();
+    }
+    let layouts = registry
+        .layouts
+        .iter()
+        .filter(|(code, _)| code == wanted)
+        .map(|(_, description)| description.as_str());
+    let variants = registry
+        .variants
+        .iter()
+        .filter(|(layout, _, _)| layout == wanted)
+        .map(|(_, _, description)| description.as_str());
+    layouts.chain(variants).collect()
+}